@@ -0,0 +1,99 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+// Copyright 2023-2025 rtldg <rtldg@protonmail.com>
+
+//! A seek in every room at once, with two members per room. `cargo bench --bench broadcast`
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use futures::{SinkExt, StreamExt};
+use simulcast_mpv::message::{PingSettings, WsMessage};
+use std::time::{Duration, Instant};
+use tokio_tungstenite::{tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+type Ws = WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>;
+
+async fn join(addr: std::net::SocketAddr, room: &str) -> Ws {
+	let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/")).await.unwrap();
+	ws.send(Message::text(
+		serde_json::to_string(&WsMessage::Join(room.to_string())).unwrap(),
+	))
+	.await
+	.unwrap();
+	ws
+}
+
+/// Skips everything (like `Party` & `Ping`) until the next `AbsoluteSeek`.
+async fn next_seek(ws: &mut Ws) {
+	while let Some(msg) = ws.next().await {
+		let msg = msg.unwrap();
+		if let Ok(WsMessage::AbsoluteSeek(_)) = serde_json::from_str(msg.to_text().unwrap()) {
+			return;
+		}
+	}
+	panic!("relay hung up");
+}
+
+fn seek_every_room(c: &mut Criterion) {
+	let rt = tokio::runtime::Runtime::new().unwrap();
+	let addr = rt.block_on(async {
+		let relay = simulcast_mpv::RelayServer::bind(
+			"127.0.0.1:0".parse().unwrap(),
+			http::Uri::from_static("https://example.com/simulcast-mpv"),
+			// Nobody answers pings here.
+			PingSettings {
+				interval: Duration::from_secs(600),
+				timeout: Duration::from_secs(6000),
+			},
+		)
+		.await
+		.unwrap()
+		.rate_limit(0);
+		let addr = relay.local_addr().unwrap();
+		tokio::spawn(relay.serve(std::future::pending()));
+		addr
+	});
+
+	let mut group = c.benchmark_group("seek_every_room");
+	for rooms in [1, 16, 128] {
+		let mut members = rt.block_on(async {
+			let mut members = vec![];
+			for i in 0..rooms {
+				let room = format!("room {i}");
+				members.push((join(addr, &room).await, join(addr, &room).await));
+			}
+			members
+		});
+		let seek = Message::text(serde_json::to_string(&WsMessage::AbsoluteSeek(1.0)).unwrap());
+		let _ = group.bench_function(BenchmarkId::from_parameter(rooms), |b| {
+			b.iter_custom(|iters| {
+				rt.block_on(async {
+					let start = Instant::now();
+					for _ in 0..iters {
+						// A task per room so the rooms really are at the same time.
+						let rooms = members.drain(..).map(|(mut a, mut b)| {
+							let seek = seek.clone();
+							tokio::spawn(async move {
+								a.send(seek).await.unwrap();
+								// The relay sends seeks back to the sender too.
+								next_seek(&mut a).await;
+								next_seek(&mut b).await;
+								(a, b)
+							})
+						});
+						members = futures::future::try_join_all(rooms).await.unwrap();
+					}
+					start.elapsed()
+				})
+			});
+		});
+		rt.block_on(async {
+			for (mut a, mut b) in members {
+				let _ = a.close(None).await;
+				let _ = b.close(None).await;
+			}
+		});
+	}
+	group.finish();
+}
+
+criterion_group!(benches, seek_every_room);
+criterion_main!(benches);
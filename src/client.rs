@@ -11,6 +11,7 @@ use anyhow::Context;
 use log::debug;
 use log::error;
 use log::info;
+use log::warn;
 use serde_json::json;
 #[cfg(windows)]
 use std::os::windows::process::CommandExt;
@@ -22,40 +23,803 @@ use tokio_tungstenite::tungstenite::protocol::CloseFrame;
 
 use futures::SinkExt;
 use futures::StreamExt;
+use futures::TryStreamExt;
+use tokio_tungstenite::tungstenite;
 
 use crate::mpvipc::Mpv;
+use crate::mpvipc::MpvIpc;
+use crate::mpvipc::PropertyObservers;
 use anyhow::anyhow;
 use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::mpsc::UnboundedSender;
 
+use crate::message::clean_title;
+use crate::message::ParseError;
+use crate::message::PingSettings;
 use crate::message::WsMessage;
+use crate::osd::OsdStrings;
 
+#[derive(Default)]
 struct SharedState {
 	party_count: u32,
 	paused: bool,
 	time: f64,
 	room_code: String,
 	room_hash: String,
+	// What `room_hash` is a hash of. Only the room's members know it.
+	room_preimage: String,
+	nickname: String,
+	// Random token for `WsMessage::Session`. The same for every reconnect.
+	session: String,
+	// `--acks`
+	acks: bool,
+	// `--solo-resume`
+	solo_resume: bool,
+	// `--osd-strings`
+	osd: OsdStrings,
+	// `--share-title`
+	share_title: bool,
+	roster: Vec<String>,
+	// "nickname (version)" of whoever's been in the room longest.
+	leader: String,
+	// The relay's short name for the room ("brave-otter-42"). Empty for password rooms.
+	room_name: String,
+	// Our file ended (or someone else's did and we jumped to the end).
+	at_end: bool,
+	// Pause/seek/resume that are waiting for things to settle before being sent to the room.
+	pending: PendingPlayback,
+	// We told the room to wait for our `paused-for-cache`.
+	buffering: bool,
+	// "connecting", "connected", "reconnecting", or "disconnected". For the Lua script's badge.
+	connection_status: &'static str,
+	// `--sync-profile`
+	sync_profile: SyncProfile,
+	// `--observer`: we follow the room but never move it.
+	observer: bool,
+	// `--join-countdown`: seconds of warning before pausing for someone joining. 0 pauses right away.
+	join_countdown: u32,
+	// `--join-pause`
+	join_pause: JoinPause,
 }
 
-fn get_room_hash(code: &str, relay_room: &str) -> String {
-	let code = code
-		.chars()
-		.map(|c| match c {
-			'_' | '-' | '+' | '.' => ' ',
-			_ => c,
+impl SharedState {
+	/// Nobody to send our pauses/seeks to. Observers aren't in `party_count` but they're in the roster & still want them.
+	fn solo(&self) -> bool {
+		self.party_count < 2 && self.roster.len() < 2
+	}
+}
+
+/// `--drift-threshold` & `--drift-interval`. Drift correction is off without a threshold.
+#[derive(Clone, Copy, Debug)]
+pub struct DriftSettings {
+	/// Seconds away from the leader before we're nudged back.
+	pub threshold: f64,
+	/// How often we send our own position (only the leader's is used).
+	pub interval: Duration,
+}
+
+/// `--sync-mode`
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum SyncMode {
+	/// Seek everyone to the same time.
+	Time,
+	/// Seek everyone to the same spot in the same chapter. Falls back to `time` for files without chapters.
+	Chapters,
+}
+
+/// `--join-pause`: what someone joining does to us.
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum JoinPause {
+	/// Pause & pull them to our position.
+	#[default]
+	Always,
+	/// Keep going. They jump to wherever the room is.
+	Never,
+	/// Like `always` while we're playing & like `never` while we're paused.
+	OnlyIfPlaying,
+}
+
+/// `--room-code-rules`: how filenames & room codes are cleaned up before hashing. Everyone in a party needs the same rules.
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum RoomCodeRules {
+	/// `_` `-` `+` `.` become spaces. What older clients do.
+	#[default]
+	Classic,
+	/// Also NFKC-normalize (so NFC/NFD & full-width characters match), trim, & collapse runs of spaces.
+	Unicode,
+	/// `unicode` & lowercased.
+	Caseless,
+}
+
+/// `--sync-profile`: one knob for how hard we try to keep everyone on the same frame.
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum SyncProfile {
+	/// Smaller seeks count, changes go out sooner, & drift correction is on (0.5s, checked every 2s).
+	Tight,
+	/// The defaults.
+	#[default]
+	Normal,
+	/// Only bigger seeks count & changes wait longer to settle so mashing keys interrupts everyone less.
+	Relaxed,
+}
+
+impl SyncProfile {
+	/// How far mpv can land from where we expect before it counts as our user seeking.
+	fn seek_threshold(self) -> f64 {
+		match self {
+			SyncProfile::Tight => 0.02,
+			SyncProfile::Normal => 0.03,
+			SyncProfile::Relaxed => 0.25,
+		}
+	}
+
+	/// How long pause/seek/resume have to settle before they're sent. Mashing space would make everyone stutter otherwise.
+	fn debounce(self) -> Duration {
+		match self {
+			SyncProfile::Tight => Duration::from_millis(75),
+			SyncProfile::Normal => Duration::from_millis(150),
+			SyncProfile::Relaxed => Duration::from_millis(400),
+		}
+	}
+
+	/// Drift correction for when `--drift-threshold` isn't set.
+	pub fn drift(self) -> Option<DriftSettings> {
+		match self {
+			SyncProfile::Tight => Some(DriftSettings {
+				threshold: 0.5,
+				interval: Duration::from_secs(2),
+			}),
+			SyncProfile::Normal | SyncProfile::Relaxed => None,
+		}
+	}
+}
+
+/// Where the relay is looked up when `--relay-url` isn't set.
+/// github.io url used because it's cdn-backed and probably won't bother github too much if we fetch it all the time
+pub const DEFAULT_DISCOVERY_URL: &str = "https://rtldg.github.io/simulcast-mpv/servers.txt";
+
+/// `--acks`: how long the relay has to answer a `Resume`/seek before we say syncing failed.
+const ACK_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// The first mpv release with `user-data/*` properties.
+const MIN_MPV_VERSION: &str = "0.36.0";
+
+/// How long mpv quitting waits for the websocket to close. The relay would otherwise keep us around until the ping timeout.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Default)]
+struct PendingPlayback {
+	// Bumped for every change so only the latest timer sends anything.
+	generation: u64,
+	// `AbsoluteSeek` or `ChapterSeek`.
+	seek: Option<WsMessage>,
+	// Sent after `seek` (if there is one).
+	resume: bool,
+}
+
+/// `user-data/simulcast/*` properties that the client keeps updated. Used by `simulcast-mpv status`.
+pub const STATUS_PROPERTIES: &[&str] = &[
+	"user-data/simulcast/party_count",
+	"user-data/simulcast/room_hash",
+	"user-data/simulcast/custom_room_code",
+	"user-data/simulcast/roster",
+	"user-data/simulcast/leader",
+	"user-data/simulcast/room_name",
+	"user-data/simulcast/heartbeat",
+	"user-data/simulcast/connection_status",
+	"user-data/simulcast/nickname",
+];
+
+/// Mirrors the interesting parts of `SharedState` into `user-data/simulcast/*` so they can be inspected from outside.
+fn publish_state(mpv: &mut dyn MpvIpc, state: &Mutex<SharedState>) {
+	let (party_count, room_hash, room_code, roster, leader, room_name, connection_status) = {
+		let state = state.lock().unwrap();
+		(
+			state.party_count,
+			state.room_hash.clone(),
+			state.room_code.clone(),
+			state.roster.clone(),
+			state.leader.clone(),
+			state.room_name.clone(),
+			state.connection_status,
+		)
+	};
+	let _ = mpv.set_property("user-data/simulcast/party_count", &json!(party_count));
+	let _ = mpv.set_property("user-data/simulcast/room_hash", &json!(room_hash));
+	let _ = mpv.set_property("user-data/simulcast/custom_room_code", &json!(room_code));
+	let _ = mpv.set_property("user-data/simulcast/roster", &json!(roster));
+	let _ = mpv.set_property("user-data/simulcast/leader", &json!(leader));
+	let _ = mpv.set_property("user-data/simulcast/room_name", &json!(room_name));
+	let _ = mpv.set_property("user-data/simulcast/connection_status", &json!(connection_status));
+}
+
+// The pause/seek/resume state machine. These only decide what to do so they can be tested without mpv or a relay.
+//
+// Local changes (our user pausing/seeking) never take effect by themselves when there's a party.
+// mpv is kept paused & the room is asked instead. The relay echoes seeks & resumes back to everyone,
+// including us, & that's when `state` actually changes (`remote_seek()`/`remote_resume()`).
+
+/// What to do after a local pause/unpause or seek. Applied in this order.
+#[derive(Debug, Default, PartialEq)]
+struct Reaction {
+	/// Put mpv back to paused.
+	pause_mpv: bool,
+	/// Ask the room to seek here.
+	seek: Option<f64>,
+	/// Ask the room to resume.
+	resume: bool,
+}
+
+/// Our user paused or unpaused. `skip_first_unpause` is for mpv starting unpaused while we're joining a party.
+fn local_pause(state: &mut SharedState, skip_first_unpause: &mut bool, paused: bool, time: f64) -> Reaction {
+	if paused == state.paused {
+		return Reaction::default();
+	}
+
+	if !paused && std::mem::take(skip_first_unpause) && state.party_count > 1 && !state.observer {
+		return Reaction {
+			pause_mpv: true,
+			..Default::default()
+		};
+	}
+
+	state.time = time;
+
+	if state.solo() || state.observer {
+		state.paused = paused;
+		return Reaction::default();
+	}
+
+	state.paused = true;
+	if paused {
+		Reaction {
+			seek: Some(time),
+			..Default::default()
+		}
+	} else {
+		// if we are here then we probably unpaused with the onscreen-display
+		Reaction {
+			pause_mpv: true,
+			resume: true,
+			..Default::default()
+		}
+	}
+}
+
+/// mpv finished seeking. Anything that isn't where the room put us was our user.
+fn local_seek(state: &mut SharedState, paused: bool, time: f64) -> Reaction {
+	if state.at_end {
+		// Jumping to the end because of `EndReached`. Don't echo it back to the room.
+		state.time = time;
+		return Reaction::default();
+	}
+
+	if (time - state.time).abs() <= state.sync_profile.seek_threshold() {
+		return Reaction::default();
+	}
+
+	// seems like we seeked...
+	state.time = time;
+	if state.solo() || state.observer {
+		return Reaction::default();
+	}
+
+	state.paused = true;
+	// The relay echoes this back to everyone (us included) so the whole room lands on the same time.
+	Reaction {
+		pause_mpv: !paused,
+		seek: Some(time),
+		resume: false,
+	}
+}
+
+/// The relay sent a new party count. Returns (pause mpv, send our position to the room).
+fn party_changed(state: &mut SharedState, count: u32) -> (bool, bool) {
+	// a new user has joined the party
+	let should_seek = state.party_count > 0 && count > state.party_count;
+
+	let pause_for_join = match state.join_pause {
+		JoinPause::Always => true,
+		JoinPause::Never => false,
+		JoinPause::OnlyIfPlaying => !state.paused,
+	};
+	if should_seek && !pause_for_join {
+		// They ask for the room's state when they join so there's nothing for us to do.
+		state.party_count = count;
+		return (false, false);
+	}
+
+	if state.party_count < 2 && count == 1 {
+		// user is solo-watching and probably just opened mpv...
+	} else {
+		// party count has changed (or we just got a random Party msg?) so pause that bih
+		state.paused = true;
+	}
+
+	state.party_count = count;
+	(state.paused, should_seek)
+}
+
+/// Names in `new` that weren't in `old`. Names can repeat ("anonymous (2.1.0)") so it's by count.
+fn new_members(old: &[String], new: &[String]) -> Vec<String> {
+	let mut old = old.to_vec();
+	new.iter()
+		.filter(|name| match old.iter().position(|o| o == *name) {
+			Some(i) => {
+				let _ = old.swap_remove(i);
+				false
+			}
+			None => true,
+		})
+		.cloned()
+		.collect()
+}
+
+/// The room seeked (implies pause).
+fn remote_seek(state: &mut SharedState, time: f64) {
+	state.paused = true;
+	state.time = time;
+}
+
+/// The room resumed.
+fn remote_resume(state: &mut SharedState) {
+	state.paused = false;
+}
+
+/// Tells the room our file ended. Only once per file & only if we're not solo-watching.
+fn end_reached(state: &Mutex<SharedState>, sender: &UnboundedSender<WsMessage>) {
+	let mut state = state.lock().unwrap();
+	if state.solo() || state.at_end || state.observer {
+		return;
+	}
+	state.at_end = true;
+	let _ = sender.send(WsMessage::EndReached);
+}
+
+/// `_` `-` `+` `.` all become spaces so "Movie.2024.mkv", "Movie_2024_mkv" & "Movie 2024 mkv" end up in the same room.
+/// With `RoomCodeRules::Classic` nothing else is touched (not even case) since that'd split rooms with older clients.
+/// The other rules NFKC-normalize first (macOS's NFD filenames & full-width characters), then swap the separators,
+/// then trim & collapse any whitespace into single spaces (so "Movie - 2024" is "Movie 2024"), then maybe lowercase.
+fn normalize_room_code(code: &str, rules: RoomCodeRules) -> String {
+	use unicode_normalization::UnicodeNormalization;
+	let separators = |c| match c {
+		'_' | '-' | '+' | '.' => ' ',
+		_ => c,
+	};
+	let code: String = match rules {
+		RoomCodeRules::Classic => return code.chars().map(separators).collect(),
+		RoomCodeRules::Unicode | RoomCodeRules::Caseless => code.nfkc().map(separators).collect(),
+	};
+	let code = code.split_whitespace().collect::<Vec<_>>().join(" ");
+	match rules {
+		RoomCodeRules::Caseless => code.to_lowercase(),
+		_ => code,
+	}
+}
+
+fn room_preimage(code: &str, relay_room: &str, rules: RoomCodeRules) -> String {
+	normalize_room_code(code, rules) + relay_room
+}
+
+fn get_room_hash(code: &str, relay_room: &str, rules: RoomCodeRules) -> String {
+	blake3::hash(room_preimage(code, relay_room, rules).as_bytes())
+		.to_hex()
+		.to_string()
+}
+
+/// `MigrateRoom`'s new custom room code. A relay that only has the old room's hash & `nonce` can't work it out.
+/// Lowercase hex so every `RoomCodeRules` leaves it alone.
+fn migrated_room_code(old_preimage: &str, nonce: &str) -> String {
+	let mut hasher = blake3::Hasher::new_derive_key("simulcast-mpv migrate room");
+	let _ = hasher.update(old_preimage.as_bytes()).update(nonce.as_bytes());
+	format!("party {}", &hasher.finalize().to_hex()[..16])
+}
+
+/// `simulcast-mpv room-hash`: prints what room a file or room code ends up in so two people can compare.
+pub fn print_room_hash(code: &str, relay_room: &str, rules: RoomCodeRules) {
+	println!("normalized room code = '{}'", normalize_room_code(code, rules));
+	println!("relay room = '{relay_room}'");
+	println!("room hash = {}", get_room_hash(code, relay_room, rules));
+}
+
+/// How much of the newest log `simulcast-mpv diagnostics` includes.
+const DIAGNOSTICS_LOG_LINES: usize = 300;
+
+/// `simulcast-mpv diagnostics`: versions, the relay, the client's `user-data/simulcast/*`, & the end of the newest log
+/// in one file for bug reports. Custom room codes, room passwords, & invites are blanked out.
+pub fn diagnostics(
+	client_sock: Option<&str>,
+	relay_url: Option<http::Uri>,
+	discovery_url: &str,
+	room_password: Option<&str>,
+	invite: Option<&str>,
+	log_dir: &std::path::Path,
+	output: &std::path::Path,
+) -> anyhow::Result<()> {
+	use std::fmt::Write;
+	let mut report = String::new();
+	let mut secrets: Vec<String> = [room_password, invite]
+		.into_iter()
+		.flatten()
+		.map(str::to_string)
+		.collect();
+
+	writeln!(
+		report,
+		"simulcast-mpv {} on {} {}",
+		env!("CARGO_PKG_VERSION"),
+		std::env::consts::OS,
+		std::env::consts::ARCH
+	)?;
+	match Runtime::new()
+		.map_err(anyhow::Error::from)
+		.and_then(|rt| resolve_relay_url(relay_url, discovery_url, &rt))
+	{
+		Ok(relay_url) => writeln!(report, "relay: {relay_url}")?,
+		Err(e) => writeln!(report, "relay: couldn't resolve ({e})")?,
+	}
+
+	match client_sock.map(|sock| (sock, Mpv::connect(sock))) {
+		Some((sock, Ok(mut mpv))) => {
+			mpv.events(false);
+			writeln!(report, "mpv socket: {sock}")?;
+			for property in ["mpv-version", "ffmpeg-version", "platform"] {
+				writeln!(report, "{property}: {}", mpv.get_property(property).unwrap_or_default())?;
+			}
+			writeln!(report, "\n[user-data/simulcast/*]")?;
+			for property in STATUS_PROPERTIES {
+				let value = mpv.get_property(property).unwrap_or_default();
+				if *property == "user-data/simulcast/custom_room_code" {
+					if let Some(code) = value.as_str().filter(|code| !code.is_empty()) {
+						secrets.push(code.to_string());
+						writeln!(report, "{property} = <redacted>")?;
+						continue;
+					}
+				}
+				writeln!(report, "{property} = {value}")?;
+			}
+			let _ = mpv.detach();
+		}
+		Some((sock, Err(e))) => writeln!(report, "mpv socket: couldn't connect to '{sock}' ({e})")?,
+		None => writeln!(report, "mpv socket: not found")?,
+	}
+
+	writeln!(report, "\n[log]")?;
+	match newest_log(log_dir) {
+		Some(path) => {
+			let log = std::fs::read_to_string(&path).with_context(|| format!("couldn't read {}", path.display()))?;
+			let lines: Vec<&str> = log.lines().collect();
+			let tail = &lines[lines.len().saturating_sub(DIAGNOSTICS_LOG_LINES)..];
+			writeln!(report, "last {} lines of {}", tail.len(), path.display())?;
+			for line in tail {
+				writeln!(report, "{line}")?;
+			}
+		}
+		None => writeln!(report, "no simulcast-mpv log in {}", log_dir.display())?,
+	}
+
+	std::fs::write(output, redact(&report, &secrets))
+		.with_context(|| format!("couldn't write {}", output.display()))?;
+	println!("wrote {}. Look it over before sharing it.", output.display());
+	Ok(())
+}
+
+/// The most recently modified `simulcast-mpv*.log` in `log_dir`.
+fn newest_log(log_dir: &std::path::Path) -> Option<std::path::PathBuf> {
+	std::fs::read_dir(log_dir)
+		.ok()?
+		.filter_map(Result::ok)
+		.filter(|entry| {
+			let name = entry.file_name().to_string_lossy().into_owned();
+			name.starts_with("simulcast-mpv") && name.ends_with(".log")
+		})
+		.max_by_key(|entry| entry.metadata().and_then(|m| m.modified()).ok())
+		.map(|entry| entry.path())
+}
+
+/// Blanks out every secret in `text`, plus the room codes older clients logged in `MigrateRoom`s.
+/// Secrets shorter than 3 characters would match all over the place so lines with them are blanked out entirely.
+fn redact(text: &str, secrets: &[String]) -> String {
+	let migrated = text
+		.match_indices("MigrateRoom { room_code: \"")
+		.filter_map(|(start, prefix)| {
+			let rest = &text[start + prefix.len()..];
+			Some(rest[..rest.find("\" }")?].to_string())
+		});
+	let secrets: Vec<String> = secrets
+		.iter()
+		.cloned()
+		.chain(migrated)
+		.filter(|secret| !secret.is_empty())
+		.collect();
+	let (short, long): (Vec<&String>, Vec<&String>) = secrets.iter().partition(|secret| secret.chars().count() < 3);
+	text.split('\n')
+		.map(|line| {
+			if short.iter().any(|secret| line.contains(secret.as_str())) {
+				return "<redacted line>".to_string();
+			}
+			long.iter().fold(line.to_string(), |line, secret| {
+				line.replace(secret.as_str(), "<redacted>")
+			})
 		})
-		.collect::<String>()
-		+ relay_room;
-	blake3::hash(code.as_bytes()).to_hex().to_string()
+		.collect::<Vec<_>>()
+		.join("\n")
+}
+
+pub const MAX_RELAY_ROOM_LEN: usize = 64;
+
+/// clap `value_parser` for `--relay-room`. Trims it & rejects empty, huge, or control-character-filled values.
+pub fn parse_relay_room(relay_room: &str) -> Result<String, String> {
+	let relay_room = relay_room.trim();
+	if relay_room.is_empty() {
+		return Err("relay room can't be empty".to_string());
+	}
+	if relay_room.chars().count() > MAX_RELAY_ROOM_LEN {
+		return Err(format!(
+			"relay room can't be longer than {MAX_RELAY_ROOM_LEN} characters"
+		));
+	}
+	if relay_room.chars().any(char::is_control) {
+		return Err("relay room can't contain control characters".to_string());
+	}
+	Ok(relay_room.to_string())
+}
+
+/// Uses `Join2` with an auth tag when there's a room password so the server can keep strangers out.
+fn join_message(room_hash: String, room_password: Option<&str>) -> WsMessage {
+	match room_password {
+		// Short names ("brave-otter-42") are looked up by the relay & never belong to password rooms.
+		Some(password) if !password.is_empty() && !room_hash.contains('-') => {
+			let key = blake3::derive_key("simulcast-mpv room password", password.as_bytes());
+			let auth_tag = blake3::keyed_hash(&key, room_hash.as_bytes()).to_hex().to_string();
+			WsMessage::Join2 { room_hash, auth_tag }
+		}
+		_ => WsMessage::Join(room_hash),
+	}
+}
+
+/// A `servers.txt`: relay urls, one per line. The first one is used. Blank lines & `#` comments are skipped.
+fn parse_server_list(list: &str) -> anyhow::Result<http::Uri> {
+	let line = list
+		.lines()
+		.map(str::trim)
+		.find(|line| !line.is_empty() && !line.starts_with('#'))
+		.ok_or_else(|| anyhow!("no relay urls in the list"))?;
+	Ok(line.parse()?)
+}
+
+/// Start times of mpv's chapters. Empty if there aren't any.
+fn chapter_starts(mpv: &mut dyn MpvIpc) -> Vec<f64> {
+	let Ok(serde_json::Value::Array(chapters)) = mpv.get_property("chapter-list") else {
+		return vec![];
+	};
+	chapters.iter().filter_map(|c| c["time"].as_f64()).collect()
+}
+
+/// Which chapter `time` is in & how far into it. `None` before the first chapter (or without chapters).
+fn chapter_position(chapter_starts: &[f64], time: f64) -> Option<(i64, f64)> {
+	let chapter = chapter_starts.iter().rposition(|start| *start <= time)?;
+	Some((chapter as i64, time - chapter_starts[chapter]))
+}
+
+/// `--invite`: one shareable code instead of a custom room code + `--relay-room` + `--room-password`.
+/// Returns the (relay_room, room_password) to use. The invite code itself is the room code.
+fn invite_settings(invite: &str) -> (String, String) {
+	let relay_room = blake3::derive_key("simulcast-mpv invite relay room", invite.as_bytes());
+	let room_password = blake3::derive_key("simulcast-mpv invite room password", invite.as_bytes());
+	(
+		blake3::Hash::from(relay_room).to_hex()[..32].to_string(),
+		blake3::Hash::from(room_password).to_hex().to_string(),
+	)
+}
+
+/// mpv logs our connections as "ipc_N" so log which one is which to make mpv's log less confusing.
+fn log_client_name(mpv: &mut dyn MpvIpc, label: &str) {
+	match mpv.client_name() {
+		Ok(name) => info!("mpv connection '{label}' is '{name}'"),
+		Err(e) => debug!("failed to get client_name for mpv connection '{label}': {e:?}"),
+	}
+}
+
+/// The websocket or the long-poll fallback.
+trait RelayConnection:
+	futures::Sink<tungstenite::Message, Error = anyhow::Error>
+	+ futures::Stream<Item = anyhow::Result<tungstenite::Message>>
+	+ Send
+	+ Unpin
+{
+}
+
+impl<T> RelayConnection for T where
+	T: futures::Sink<tungstenite::Message, Error = anyhow::Error>
+		+ futures::Stream<Item = anyhow::Result<tungstenite::Message>>
+		+ Send
+		+ Unpin
+{
+}
+
+/// For networks that block websockets. The same `WsMessage` JSON goes through the relay's `/longpoll/*` HTTP endpoints instead.
+/// The requests happen in tasks on the other ends of these channels.
+struct LongPoll {
+	outgoing: futures::channel::mpsc::UnboundedSender<tungstenite::Message>,
+	incoming: futures::channel::mpsc::UnboundedReceiver<anyhow::Result<tungstenite::Message>>,
+}
+
+impl LongPoll {
+	async fn connect(relay_url: &str, connector: Option<tokio_tungstenite::Connector>) -> anyhow::Result<LongPoll> {
+		// ws://relay/path -> http://relay/path/longpoll/*
+		let base = match relay_url.split_once("://") {
+			Some(("wss", rest)) => format!("https://{rest}"),
+			Some((_, rest)) => format!("http://{rest}"),
+			None => anyhow::bail!("relay url '{relay_url}' is missing a scheme"),
+		};
+		let base = base.trim_end_matches('/').to_string();
+
+		let mut builder = reqwest::Client::builder().timeout(Duration::from_secs(60));
+		if let Some(tokio_tungstenite::Connector::Rustls(config)) = connector {
+			// For --ca-cert.
+			builder = builder.use_preconfigured_tls((*config).clone());
+		}
+		let http = builder.build()?;
+
+		let session = http
+			.post(format!("{base}/longpoll/connect"))
+			.send()
+			.await?
+			.error_for_status()?
+			.text()
+			.await?;
+		info!("connected to relay with long-polling");
+
+		let (outgoing, mut outgoing_r) = futures::channel::mpsc::unbounded::<tungstenite::Message>();
+		let (incoming_s, incoming) = futures::channel::mpsc::unbounded();
+
+		let (send_http, send_base, send_session, send_errors) =
+			(http.clone(), base.clone(), session.clone(), incoming_s.clone());
+		tokio::spawn(async move {
+			while let Some(msg) = outgoing_r.next().await {
+				let request = match msg {
+					tungstenite::Message::Text(text) => send_http
+						.post(format!("{send_base}/longpoll/send?session={send_session}"))
+						.body(text.as_str().to_string()),
+					tungstenite::Message::Close(_) => {
+						let _ = send_http
+							.post(format!("{send_base}/longpoll/close?session={send_session}"))
+							.send()
+							.await;
+						return;
+					}
+					_ => continue,
+				};
+				if let Err(e) = request.send().await.and_then(|r| r.error_for_status()) {
+					let _ = send_errors.unbounded_send(Err(e.into()));
+					return;
+				}
+			}
+		});
+
+		tokio::spawn(async move {
+			while !incoming_s.is_closed() {
+				let body = match http.get(format!("{base}/longpoll/recv?session={session}")).send().await {
+					Ok(resp) => match resp.error_for_status() {
+						Ok(resp) => resp.text().await,
+						Err(e) => Err(e),
+					},
+					Err(e) => Err(e),
+				};
+				match body {
+					Ok(body) => {
+						for line in body.lines() {
+							let _ = incoming_s.unbounded_send(Ok(tungstenite::Message::text(line)));
+						}
+					}
+					Err(e) => {
+						let _ = incoming_s.unbounded_send(Err(e.into()));
+						return;
+					}
+				}
+			}
+		});
+
+		Ok(LongPoll { outgoing, incoming })
+	}
+}
+
+impl futures::Stream for LongPoll {
+	type Item = anyhow::Result<tungstenite::Message>;
+
+	fn poll_next(
+		mut self: std::pin::Pin<&mut Self>,
+		cx: &mut std::task::Context<'_>,
+	) -> std::task::Poll<Option<Self::Item>> {
+		std::pin::Pin::new(&mut self.incoming).poll_next(cx)
+	}
+}
+
+impl futures::Sink<tungstenite::Message> for LongPoll {
+	type Error = anyhow::Error;
+
+	fn poll_ready(
+		mut self: std::pin::Pin<&mut Self>,
+		cx: &mut std::task::Context<'_>,
+	) -> std::task::Poll<Result<(), Self::Error>> {
+		std::pin::Pin::new(&mut self.outgoing)
+			.poll_ready(cx)
+			.map_err(Into::into)
+	}
+
+	fn start_send(mut self: std::pin::Pin<&mut Self>, item: tungstenite::Message) -> Result<(), Self::Error> {
+		std::pin::Pin::new(&mut self.outgoing)
+			.start_send(item)
+			.map_err(Into::into)
+	}
+
+	fn poll_flush(
+		mut self: std::pin::Pin<&mut Self>,
+		cx: &mut std::task::Context<'_>,
+	) -> std::task::Poll<Result<(), Self::Error>> {
+		std::pin::Pin::new(&mut self.outgoing)
+			.poll_flush(cx)
+			.map_err(Into::into)
+	}
+
+	fn poll_close(
+		mut self: std::pin::Pin<&mut Self>,
+		cx: &mut std::task::Context<'_>,
+	) -> std::task::Poll<Result<(), Self::Error>> {
+		std::pin::Pin::new(&mut self.outgoing)
+			.poll_close(cx)
+			.map_err(Into::into)
+	}
 }
 
+/// A websocket, or long-polling if the websocket can't get through.
+async fn connect_relay(
+	relay_url: &str,
+	connector: Option<tokio_tungstenite::Connector>,
+) -> anyhow::Result<Box<dyn RelayConnection>> {
+	match tokio_tungstenite::connect_async_tls_with_config(relay_url, None, false, connector.clone()).await {
+		Ok((ws, _)) => {
+			info!("connected to websocket");
+			Ok(Box::new(ws.sink_err_into().err_into()))
+		}
+		// Something between us & the relay (probably a proxy) doesn't let websocket upgrades through.
+		Err(e @ (tungstenite::Error::Http(_) | tungstenite::Error::Protocol(_))) => {
+			warn!("websocket handshake failed ({e}). falling back to long-polling");
+			Ok(Box::new(
+				LongPoll::connect(relay_url, connector)
+					.await
+					.context("Failed to setup long-poll connection")?,
+			))
+		}
+		Err(e) => Err(anyhow::Error::new(e).context("Failed to setup websocket connection")),
+	}
+}
+
+/// The party count changed (now or after `--join-countdown`). `state.paused` should already be set.
+fn pause_for_party(mpv: &mut dyn MpvIpc, osd: &OsdStrings, count: u32) {
+	// these can hit too early and cause `Err(MpvError: property unavailable)`?
+	let _ = mpv.set_property("pause", &json!(true));
+	let _ = mpv.set_property("speed", &json!(1.0)); // useful for me (since I have my default mpv speed at 1.5x)
+
+	let _ = mpv.show_text(&osd.party_count(count), Some(2000), None);
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn ws_thread(
 	relay_url: String,
-	mpv: &mut Mpv,
+	connector: Option<tokio_tungstenite::Connector>,
+	ping: PingSettings,
+	drift: Option<DriftSettings>,
+	reconnect: bool,
+	relay_room: &str,
+	room_password: Option<&str>,
+	mpv: &mut dyn MpvIpc,
 	receiver: &mut UnboundedReceiver<WsMessage>,
 	state: Arc<Mutex<SharedState>>,
 ) -> anyhow::Result<()> {
 	info!("ws_thread!");
+	let osd = state.lock().unwrap().osd.clone();
 
 	loop {
 		// nom nom nom. eat messages.
@@ -66,85 +830,263 @@ async fn ws_thread(
 		}
 	}
 
-	let (mut ws, _) = tokio_tungstenite::connect_async(relay_url)
-		.await
-		.context("Failed to setup websocket connection")?;
+	let mut ws = connect_relay(&relay_url, connector).await?;
 
-	info!("connected to websocket");
+	state.lock().unwrap().connection_status = "connected";
+	publish_state(mpv, &state);
 
 	ws.send(WsMessage::Info(String::new()).to_websocket_msg()).await?;
+	// Older servers will ignore this.
+	let (nickname, session) = {
+		let state = state.lock().unwrap();
+		(state.nickname.clone(), state.session.clone())
+	};
+	ws.send(
+		WsMessage::Info2 {
+			version: env!("CARGO_PKG_VERSION").to_string(),
+			nickname,
+		}
+		.to_websocket_msg(),
+	)
+	.await?;
+	ws.send(WsMessage::Session(session).to_websocket_msg()).await?;
+	let (acks, share_title, observer) = {
+		let state = state.lock().unwrap();
+		(state.acks, state.share_title, state.observer)
+	};
+	if acks {
+		ws.send(WsMessage::EnableAcks.to_websocket_msg()).await?;
+	}
+	if observer {
+		ws.send(WsMessage::Observer.to_websocket_msg()).await?;
+	}
+	if share_title {
+		if let Some(title) = mpv.get_property("media-title").ok().as_ref().and_then(|t| t.as_str()) {
+			// Too long & the relay would drop us (& then again on every reconnect).
+			ws.send(WsMessage::Title(clean_title(title)).send_helper()).await?;
+		}
+	}
+	// `--acks`: the last `Resume`/seek we sent & when.
+	let mut unacked: Option<(&'static str, std::time::Instant)> = None;
+	// From `PauseReason`. Shown with the seek or `Buffering(true)` that follows.
+	let mut pause_reason: Option<String> = None;
+	// We sent a `RequestState` for `alt+l` (rather than for reconnecting).
+	let mut catching_up = false;
+	// The relay might've held our slot. Then the first `Party` is the same count as before & nothing changed.
+	let mut first_party_after_reconnect = reconnect;
+	// `--join-countdown`: (seconds left, send our position when it hits 0).
+	let mut join_countdown: Option<(u32, bool)> = None;
+	let mut countdown_interval = tokio::time::interval(Duration::from_secs(1));
 
 	{
 		let room_hash = {
 			let state = state.lock().unwrap();
 			state.room_hash.clone()
 		};
-		ws.send(WsMessage::Join(room_hash).send_helper()).await?;
+		let in_room = !room_hash.is_empty();
+		ws.send(join_message(room_hash, room_password).send_helper()).await?;
+		// Whatever the room agreed on while we were gone is more important than where our mpv is at.
+		if reconnect && in_room {
+			ws.send(WsMessage::RequestState.send_helper()).await?;
+		}
 	}
 
 	// Using an `Instant` instead of `intervals_since_last_ping` because it's less prone to breaking in case the interval duration is ever changed for some reason.
 	let mut last_ping_time = std::time::Instant::now();
 
-	let mut interval = tokio::time::interval(Duration::from_secs(1));
+	let mut interval = tokio::time::interval(ping.interval);
+	let mut drift_interval = tokio::time::interval(drift.map_or(Duration::from_secs(3600), |d| d.interval));
 	loop {
 		tokio::select! {
 			_ = interval.tick() => {
-				if last_ping_time.elapsed() > Duration::from_secs(10) {
-					anyhow::bail!("server hasn't pinged for 10s and we probably lost connection."); // anyhow::bail!() will return btw...
+				if last_ping_time.elapsed() > ping.timeout {
+					anyhow::bail!("server hasn't pinged for {:?} and we probably lost connection.", ping.timeout); // anyhow::bail!() will return btw...
+				}
+				// Not `Option::take_if()` since that needs Rust 1.80 & the Windows 7 build uses 1.75.
+				if unacked.is_some_and(|(_, sent)| sent.elapsed() > ACK_TIMEOUT) {
+					let (kind, _) = unacked.take().unwrap();
+					warn!("relay didn't answer our {kind}");
+					let _ = mpv.show_text("SIMULCAST\nsync failed (the relay didn't answer)", Some(3000), None);
+				}
+			}
+			_ = countdown_interval.tick(), if join_countdown.is_some() => {
+				let (left, should_seek) = join_countdown.as_mut().unwrap();
+				*left -= 1;
+				if *left > 0 {
+					let _ = mpv.show_text(&osd.join_countdown(*left), Some(1100), None);
+					continue;
+				}
+				let should_seek = *should_seek;
+				join_countdown = None;
+				let count = {
+					let mut state = state.lock().unwrap();
+					state.paused = true;
+					state.party_count
+				};
+				pause_for_party(mpv, &osd, count);
+				if should_seek {
+					// Wherever we got to during the countdown.
+					let Some(time) = mpv.get_property("playback-time/full").ok().and_then(|t| t.as_f64()) else {
+						continue;
+					};
+					debug!("join countdown finished so sending Seek");
+					ws.send(WsMessage::AbsoluteSeek(time).to_websocket_msg()).await?;
+				}
+			}
+			_ = drift_interval.tick(), if drift.is_some() => {
+				{
+					let state = state.lock().unwrap();
+					if state.solo() || state.paused || state.observer {
+						continue;
+					}
 				}
+				let Some(time) = mpv.get_property("playback-time/full").ok().and_then(|t| t.as_f64()) else {
+					continue;
+				};
+				// The relay only passes this on if we're the leader.
+				ws.send(WsMessage::Position(time).send_helper()).await?;
 			}
 			msg = receiver.recv() => {
 				let Some(msg) = msg else {
 					// Sender has closed and the program is about to exit....
-					let _ = ws.close(
-						Some(CloseFrame {
+					let _ = ws.send(
+						tungstenite::Message::Close(Some(CloseFrame {
 							code: tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode::Normal,
 							reason: "".into(),
-						})
-					).await; // could be canceled if the Runtime is dropped fast
+						}))
+					).await;
+					// Wait for the relay's side of the close handshake so it's actually flushed.
+					while let Some(Ok(_)) = ws.next().await {}
 					return Ok(());
 				};
+				if acks {
+					if let Some(kind) = msg.ack_kind() {
+						unacked = Some((kind, std::time::Instant::now()));
+					}
+				}
+				if msg == WsMessage::RequestState {
+					catching_up = true;
+				}
+				if observer && msg.drives_playback() {
+					// The relay would drop it anyway.
+					debug!("observing so not sending {msg:?}");
+					continue;
+				}
 				ws.send(msg.send_helper()).await?;
 			}
 			msg = ws.next() => {
-				let msg = msg.unwrap()?.into_text()?;
-				let Ok(msg) = serde_json::from_str(&msg) else {
-					debug!("unknown message = '{msg}'");
-					continue;
+				let msg = match msg {
+					Some(Ok(tungstenite::Message::Text(text))) => text,
+					// Returning an error so we reconnect. The relay might've just restarted.
+					Some(Ok(tungstenite::Message::Close(frame))) => match frame {
+						Some(frame) => anyhow::bail!("relay closed the connection ({}: {})", frame.code, frame.reason),
+						None => anyhow::bail!("relay closed the connection"),
+					},
+					// tungstenite answers pings by itself. Nothing we know of is sent as binary.
+					Some(Ok(other)) => {
+						debug!("ignoring non-text websocket frame ({} bytes)", other.len());
+						continue;
+					},
+					Some(Err(e)) => return Err(e),
+					None => anyhow::bail!("relay connection ended"),
+				};
+				let msg = match WsMessage::parse(&msg) {
+					Ok(msg) => msg,
+					Err(ParseError::UnknownVariant(variant)) => {
+						debug!("ignoring '{variant}' message (relay is probably newer than us)");
+						continue;
+					},
+					Err(ParseError::Invalid(e)) => {
+						warn!("invalid message from relay ({e}) = '{msg}'");
+						continue;
+					},
 				};
 				match msg {
-					WsMessage::Ping(_) | WsMessage::Pong(_) => (),
+					WsMessage::Ping(_) | WsMessage::Pong(_) | WsMessage::Position(_) => (),
 					_ => debug!("recv msg = {msg:?}")
 				}
 				match msg {
 					WsMessage::Info(s) => {
 						info!("server info: {s}");
 					},
-					WsMessage::Join(_) => { /* we shouldn't be receiving this */ },
+					WsMessage::ServerInfo { version, repo, uptime_secs, connected, rooms } => {
+						info!(
+							"relay is version {version} (repo {repo}), up for {}h{}m with {connected} clients in {rooms} rooms",
+							uptime_secs / 3600,
+							uptime_secs % 3600 / 60
+						);
+					},
+					WsMessage::Info2 { .. } => { /* we shouldn't be receiving this */ },
+					WsMessage::Incompatible { min_version } => {
+						error!("relay requires simulcast-mpv version {min_version} or newer");
+						let _ = mpv.show_text(
+							&format!("SIMULCAST\nplease update simulcast-mpv\n(the relay requires version {min_version} or newer)"),
+							Some(10000),
+							None,
+						);
+						// No point in reconnecting. We'd just get rejected again.
+						return Ok(());
+					},
+					WsMessage::RateLimited => {
+						// The relay closes the connection next & we reconnect like usual.
+						error!("relay disconnected us for sending too many messages");
+						let _ = mpv.show_text("SIMULCAST\nthe relay disconnected us for sending too many messages", Some(5000), None);
+					},
+					WsMessage::Ack(kind) => {
+						if unacked.is_some_and(|(unacked, _)| unacked == kind) {
+							unacked = None;
+						}
+					},
+					WsMessage::Nack { kind, reason } => {
+						unacked = None;
+						warn!("relay didn't take our {kind}: {reason}");
+						let _ = mpv.show_text(&format!("SIMULCAST\nsync failed ({reason})"), Some(3000), None);
+					},
+					WsMessage::Join(_) | WsMessage::Join2 { .. } | WsMessage::ForceResync(_) | WsMessage::Session(_) | WsMessage::EnableAcks | WsMessage::Observer | WsMessage::Nick(_) | WsMessage::Title(_) | WsMessage::InRoom { .. } => { /* we shouldn't be receiving this */ },
 					WsMessage::Party(count) => {
-						let (should_pause, should_seek) = {
+						let (should_pause, should_seek, went_solo, joined, countdown) = {
 							let mut state = state.lock().unwrap();
 
-							// a new user has joined the party
-							let should_seek = state.party_count > 0 && count > state.party_count;
-
-							if state.party_count < 2 && count == 1 {
-								// user is solo-watching and probably just opened mpv...
-							} else {
-								// party count has changed (or we just got a random Party msg?) so pause that bih
-								state.paused = true;
+							if std::mem::take(&mut first_party_after_reconnect) && count == state.party_count {
+								continue;
 							}
 
-							state.party_count = count;
-							(state.paused, should_seek)
+							let went_solo = state.solo_resume && state.party_count > 1 && count == 1;
+							// We're the one joining. Jump to wherever the room already is in case nobody pulls us there
+							// (`--join-pause never`, observers, etc).
+							let joined = state.party_count == 0 && count > 1;
+							let was_paused = state.paused;
+							let (should_pause, should_seek) = party_changed(&mut state, count);
+							if went_solo {
+								state.paused = false;
+							}
+							// Someone joined while we're watching. Keep playing for a few seconds before pausing for them.
+							let countdown = (should_seek && !was_paused && state.join_countdown > 0).then_some(state.join_countdown);
+							if countdown.is_some() {
+								state.paused = false;
+							}
+							(
+								should_pause && !went_solo && countdown.is_none(),
+								should_seek && !state.observer,
+								went_solo,
+								joined,
+								countdown,
+							)
 						};
-
-						if should_pause {
-							// these can hit too early and cause `Err(MpvError: property unavailable)`?
-							let _ = mpv.set_property("pause", &json!(true));
-							let _ = mpv.set_property("speed", &json!(1.0)); // useful for me (since I have my default mpv speed at 1.5x)
-
-							let _ = mpv.show_text(&format!("party count: {count}"), Some(2000), None);
+						publish_state(mpv, &state);
+
+						// Whoever joined/left since made it stale.
+						join_countdown = None;
+						if went_solo {
+							let _ = mpv.set_property("pause", &json!(false));
+							let _ = mpv.show_text(&osd.watching_solo, Some(3000), None);
+						} else if should_pause {
+							pause_for_party(mpv, &osd, count);
+						} else if let Some(secs) = countdown {
+							join_countdown = Some((secs, should_seek));
+							countdown_interval.reset();
+							let _ = mpv.show_text(&osd.join_countdown(secs), Some(1100), None);
+							continue;
 						}
 
 						// TODO:
@@ -153,44 +1095,240 @@ async fn ws_thread(
 						// server-side hax to ignore all but the first seek. At least right now...
 						// But that's probably the way to go.
 						if should_seek {
-							let Ok(time) = mpv.get_property("playback-time/full") else {
+							let Some(time) = mpv.get_property("playback-time/full").ok().and_then(|t| t.as_f64()) else {
 								continue;
 							};
-							let time = time.as_f64().unwrap();
 							debug!("party_count increased so sending Seek");
 							ws.send(WsMessage::AbsoluteSeek(time).to_websocket_msg()).await?;
 						}
+						if joined {
+							ws.send(WsMessage::RequestState.to_websocket_msg()).await?;
+						}
 					},
-					WsMessage::Resume => {
-						{
+					WsMessage::Roster(roster) => {
+						let joined = {
 							let mut state = state.lock().unwrap();
-							state.paused = false;
+							let joined = if state.roster.is_empty() {
+								// We're the one joining.
+								vec![]
+							} else {
+								new_members(&state.roster, &roster)
+							};
+							state.roster = roster;
+							joined
+						};
+						publish_state(mpv, &state);
+						if !joined.is_empty() {
+							let _ = mpv.show_text(&osd.member_joined(&joined.join(", ")), Some(3000), None);
 						}
-						mpv.set_property("pause", &json!(false))?;
 					},
-					WsMessage::AbsoluteSeek(time) => {
+					WsMessage::NickChanged { old, new } => {
 						{
 							let mut state = state.lock().unwrap();
-							state.paused = true;
-							state.time = time;
+							// Before the `Roster` that follows so they don't look like someone new joining.
+							for name in state.roster.iter_mut() {
+								if *name == old || *name == format!("{old} (observing)") {
+									*name = name.replacen(old.as_str(), &new, 1);
+									break;
+								}
+							}
+							if state.leader == old {
+								state.leader = new.clone();
+							}
 						}
-						mpv.set_property("pause", &json!(true))?;
-						// "osd-auto" is a prefix to make it show the onscreen-display seek bar just like seek binds do
-						let _ = mpv.raw_command(&json!(["osd-auto", "seek", time.to_string(), "absolute+exact"]))?;
+						publish_state(mpv, &state);
+						let _ = mpv.show_text(&osd.renamed(&old, &new), Some(3000), None);
 					},
-					WsMessage::Ping(s) => {
-						last_ping_time = std::time::Instant::now();
-						ws.send(WsMessage::Pong(s).to_websocket_msg()).await?;
+					WsMessage::MemberLeft(name) => {
+						// `Party` already paused us. This just says why.
+						let _ = mpv.show_text(&osd.member_left(&name), Some(3000), None);
 					},
-					WsMessage::Pong(_) => { /* we shouldn't be reciving this */},
-				}
-			}
-		}
-	}
-}
-
-fn spawn_input_reader(client_sock: String) -> anyhow::Result<()> {
-	let exe = std::env::current_exe()?;
+					WsMessage::MigrateRoom { nonce, room_hash, .. } => {
+						let room_code = {
+							let mut state = state.lock().unwrap();
+							state.room_code = migrated_room_code(&state.room_preimage, &nonce);
+							// Normalizing doesn't touch these so it's the same with any `--room-code-rules`.
+							state.room_preimage = state.room_code.clone() + relay_room;
+							state.room_hash = room_hash.clone();
+							state.room_code.clone()
+						};
+						publish_state(mpv, &state);
+						ws.send(join_message(room_hash, room_password).send_helper()).await?;
+						let _ = mpv.show_text(&osd.party_moved(&room_code), Some(3000), None);
+					},
+					WsMessage::LeaderChanged(leader) => {
+						let took_over = {
+							let mut state = state.lock().unwrap();
+							// The roster has already dropped whoever left. When joining a room it's still the old room's.
+							let took_over = !state.leader.is_empty() && !state.roster.contains(&state.leader);
+							state.leader = leader.clone();
+							took_over
+						};
+						publish_state(mpv, &state);
+						if took_over {
+							let _ = mpv.show_text(&osd.leader_changed(&leader), Some(3000), None);
+						}
+					},
+					WsMessage::RoomName(name) => {
+						let changed = {
+							let mut state = state.lock().unwrap();
+							std::mem::replace(&mut state.room_name, name.clone()) != name
+						};
+						publish_state(mpv, &state);
+						if changed && !name.is_empty() {
+							let _ = mpv.show_text(&osd.room_name(&name), Some(3000), None);
+						}
+					},
+					WsMessage::Resume => {
+						remote_resume(&mut state.lock().unwrap());
+						mpv.set_property("pause", &json!(false))?;
+					},
+					WsMessage::AbsoluteSeek(_) | WsMessage::ChapterSeek { .. } => {
+						// Someone else already pulled the room together.
+						join_countdown = None;
+						let reason = pause_reason.take();
+						let time = match msg {
+							WsMessage::ChapterSeek { chapter, offset, time } => usize::try_from(chapter)
+								.ok()
+								.and_then(|chapter| chapter_starts(mpv).get(chapter).map(|start| start + offset))
+								.unwrap_or(time),
+							WsMessage::AbsoluteSeek(time) => time,
+							_ => unreachable!(),
+						};
+						// mpv would clamp to the end & we'd echo that back to the room, dragging everyone there.
+						if let Some(duration) = mpv.get_property("duration").ok().and_then(|d| d.as_f64()) {
+							if time > duration {
+								warn!(
+									"peer seeked to {time:.3}s but our file is {duration:.3}s long ({:.3}s short)",
+									time - duration
+								);
+								state.lock().unwrap().paused = true;
+								mpv.set_property("pause", &json!(true))?;
+								let _ = mpv.show_text(
+									"SIMULCAST\npeer is ahead of your file's length — different version?",
+									Some(5000),
+									None,
+								);
+								continue;
+							}
+						}
+						remote_seek(&mut state.lock().unwrap(), time);
+						mpv.set_property("pause", &json!(true))?;
+						// "osd-auto" is a prefix to make it show the onscreen-display seek bar just like seek binds do
+						if let Err(e) = mpv.command(&json!(["osd-auto", "seek", time.to_string(), "absolute+exact"])) {
+							if e.downcast_ref::<crate::mpvipc::MpvError>().is_none() {
+								return Err(e);
+							}
+							// Like while a file's still loading.
+							warn!("couldn't seek to {time:.3}s for the room: {e}");
+						}
+						if let Some(reason) = reason {
+							let _ = mpv.show_text(&reason, Some(3000), None);
+						}
+					},
+					WsMessage::EndReached => {
+						{
+							let mut state = state.lock().unwrap();
+							if state.party_count < 2 || state.at_end {
+								continue;
+							}
+							state.at_end = true;
+							state.paused = true;
+						}
+						mpv.set_property("pause", &json!(true))?;
+						let _ = mpv.raw_command(&json!(["osd-auto", "seek", "100", "absolute-percent+exact"]))?;
+						let _ = mpv.show_text(&osd.end_reached, Some(2000), None);
+					},
+					WsMessage::Buffering(true) => {
+						let reason = pause_reason.take();
+						{
+							let mut state = state.lock().unwrap();
+							if state.party_count < 2 {
+								continue;
+							}
+							state.paused = true;
+						}
+						mpv.set_property("pause", &json!(true))?;
+						let _ = mpv.show_text(reason.as_deref().unwrap_or(&osd.waiting_for_buffering), Some(3000), None);
+					},
+					WsMessage::PauseReason { reason, name } => {
+						pause_reason = match reason.as_str() {
+							"seek" => Some(osd.paused_by(&name)),
+							"buffering" => Some(osd.paused_for_buffering(&name)),
+							_ => None,
+						};
+					},
+					WsMessage::Buffering(false) => {
+						// A `Resume` follows this. If we're still buffering then the room got tired of waiting for us.
+						if std::mem::take(&mut state.lock().unwrap().buffering) {
+							let _ = mpv.show_text(&osd.stopped_waiting, Some(3000), None);
+						}
+					},
+					WsMessage::Position(leader_time) => {
+						let Some(drift) = drift else {
+							continue;
+						};
+						if state.lock().unwrap().paused {
+							continue;
+						}
+						let Some(time) = mpv.get_property("playback-time/full").ok().and_then(|t| t.as_f64()) else {
+							continue;
+						};
+						if (time - leader_time).abs() < drift.threshold {
+							continue;
+						}
+						debug!("drifted {:.3}s from the leader. nudging to {leader_time}", time - leader_time);
+						{
+							let mut state = state.lock().unwrap();
+							if state.paused {
+								continue;
+							}
+							// So the event loop doesn't think we seeked ourselves.
+							state.time = leader_time;
+						}
+						let _ = mpv.raw_command(&json!(["no-osd", "seek", leader_time.to_string(), "absolute+exact"]))?;
+					},
+					WsMessage::RequestState => {
+						let Some(time) = mpv.get_property("playback-time/full").ok().and_then(|t| t.as_f64()) else {
+							continue;
+						};
+						let paused = state.lock().unwrap().paused;
+						ws.send(WsMessage::State { time, paused }.send_helper()).await?;
+					},
+					WsMessage::State { time, paused } => {
+						let mpv_paused = mpv.get_property("pause").ok().and_then(|p| p.as_bool()).unwrap_or(false);
+						{
+							let mut state = state.lock().unwrap();
+							state.paused = paused || mpv_paused;
+							state.time = time;
+						}
+						// Pause/unpause only if mpv isn't already there. The event loop has to see a `state.paused`
+						// that matches every pause change or it thinks our user did it (pausing & unpausing back to back can't).
+						if paused && !mpv_paused {
+							mpv.set_property("pause", &json!(true))?;
+						}
+						let _ = mpv.raw_command(&json!(["osd-auto", "seek", time.to_string(), "absolute+exact"]))?;
+						if !paused && mpv_paused {
+							state.lock().unwrap().paused = false;
+							mpv.set_property("pause", &json!(false))?;
+						}
+						if std::mem::take(&mut catching_up) {
+							let _ = mpv.show_text(&osd.caught_up, Some(2000), None);
+						}
+					},
+					WsMessage::Ping(s) => {
+						last_ping_time = std::time::Instant::now();
+						ws.send(WsMessage::Pong(s).to_websocket_msg()).await?;
+					},
+					WsMessage::Pong(_) => { /* we shouldn't be reciving this */},
+				}
+			}
+		}
+	}
+}
+
+fn spawn_input_reader(client_sock: String) -> anyhow::Result<()> {
+	let exe = std::env::current_exe()?;
 	#[cfg(windows)]
 	{
 		const CREATE_NEW_CONSOLE: u32 = 0x00000010;
@@ -209,31 +1347,138 @@ fn spawn_input_reader(client_sock: String) -> anyhow::Result<()> {
 	Ok(())
 }
 
-pub fn client(
-	verbosity: log::LevelFilter,
-	relay_url: Option<http::Uri>,
-	relay_room: String,
-	client_sock: String,
-) -> anyhow::Result<()> {
-	let rt = tokio::runtime::Builder::new_multi_thread()
-		.enable_all()
-		.worker_threads(2)
-		.build()?;
-	let res = client_inner(verbosity, relay_url, relay_room, client_sock, &rt);
-	// mainly wait for our websocket connection to close...
-	rt.shutdown_timeout(Duration::from_secs_f64(0.5));
-	res
+/// The system's roots plus the PEM certificates in `ca_cert`. For private relays with self-signed certs.
+fn tls_connector(ca_cert: &std::path::Path) -> anyhow::Result<tokio_tungstenite::Connector> {
+	let pem = std::fs::read(ca_cert).with_context(|| format!("failed to read --ca-cert '{}'", ca_cert.display()))?;
+
+	let mut roots = rustls::RootCertStore::empty();
+	let native = rustls_native_certs::load_native_certs();
+	for err in native.errors {
+		debug!("failed to load a native root certificate: {err}");
+	}
+	let _ = roots.add_parsable_certificates(native.certs);
+
+	let mut added = 0;
+	for cert in rustls_pemfile::certs(&mut pem.as_slice()) {
+		let cert = cert.with_context(|| format!("malformed PEM in --ca-cert '{}'", ca_cert.display()))?;
+		roots
+			.add(cert)
+			.with_context(|| format!("invalid certificate in --ca-cert '{}'", ca_cert.display()))?;
+		added += 1;
+	}
+	if added == 0 {
+		return Err(anyhow!(
+			"no PEM certificates found in --ca-cert '{}'",
+			ca_cert.display()
+		));
+	}
+	info!("added {added} certificate(s) from '{}'", ca_cert.display());
+
+	let config = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+		.with_safe_default_protocol_versions()?
+		.with_root_certificates(roots)
+		.with_no_client_auth();
+	Ok(tokio_tungstenite::Connector::Rustls(Arc::new(config)))
+}
+
+/// Everything the client needs. Fill in what you need & `run()` it to sync an mpv instance.
+#[derive(Clone, Debug)]
+pub struct SimulcastClient {
+	/// `None` reads the relay from `discovery_url`.
+	pub relay_url: Option<http::Uri>,
+	/// A `servers.txt` list. See `parse_server_list()`.
+	pub discovery_url: String,
+	/// Salt for the room hashes. See `parse_relay_room()`.
+	pub relay_room: String,
+	pub room_password: Option<String>,
+	/// Replaces `relay_room` & `room_password`. See `--invite`.
+	pub invite: Option<String>,
+	pub nickname: Option<String>,
+	/// Extra PEM certificate(s) to trust for `wss://` relays.
+	pub ca_cert: Option<std::path::PathBuf>,
+	/// mpv's `input-ipc-server`. `None` looks in the usual places.
+	pub client_sock: Option<String>,
+	pub ping: PingSettings,
+	pub drift: Option<DriftSettings>,
+	pub sync_mode: SyncMode,
+	/// Also fills in `drift` when it's `None`.
+	pub sync_profile: SyncProfile,
+	/// Ask the relay to confirm every pause/seek/resume & show "sync failed" when it doesn't.
+	pub acks: bool,
+	/// Keep playing when everyone else leaves instead of staying paused.
+	pub solo_resume: bool,
+	/// Follow the room without ever pausing/seeking it for everyone.
+	pub observer: bool,
+	/// Seconds of "pausing in 3..." before pausing for someone joining. 0 pauses right away.
+	pub join_countdown: u32,
+	pub join_pause: JoinPause,
+	pub room_code_rules: RoomCodeRules,
+	/// OSD text for room events.
+	pub osd: OsdStrings,
+	/// How long to wait on mpv before giving up on a command. `None` waits forever.
+	pub mpv_timeout: Option<Duration>,
+	/// Tell the relay what's being watched (mpv's `media-title`) for its log & `/now-playing`. Otherwise only the room hash leaves the client.
+	pub share_title: bool,
+	/// Write `user-data/simulcast/heartbeat` for the Lua script.
+	pub heartbeat: bool,
+	/// A testing tool: ignore `relay_url` & sync with a pretend partner on an in-process relay that's this far away.
+	pub solo_test: Option<Duration>,
+}
+
+impl SimulcastClient {
+	/// The same defaults as `simulcast-mpv client`.
+	pub fn new(client_sock: Option<String>) -> Self {
+		SimulcastClient {
+			relay_url: None,
+			discovery_url: DEFAULT_DISCOVERY_URL.to_string(),
+			relay_room: "abcd1234".to_string(),
+			room_password: None,
+			invite: None,
+			nickname: None,
+			ca_cert: None,
+			client_sock,
+			ping: PingSettings {
+				interval: Duration::from_secs(1),
+				timeout: Duration::from_secs(10),
+			},
+			drift: None,
+			sync_mode: SyncMode::Time,
+			sync_profile: SyncProfile::Normal,
+			acks: false,
+			solo_resume: false,
+			observer: false,
+			join_countdown: 3,
+			join_pause: JoinPause::Always,
+			room_code_rules: RoomCodeRules::Classic,
+			osd: OsdStrings::default(),
+			mpv_timeout: Some(crate::mpvipc::DEFAULT_TIMEOUT),
+			share_title: false,
+			heartbeat: true,
+			solo_test: None,
+		}
+	}
+
+	/// Blocks until mpv quits (or the relay says we're too old). Uses its own tokio runtime & doesn't setup any logging.
+	pub fn run(self) -> anyhow::Result<()> {
+		let rt = tokio::runtime::Builder::new_multi_thread()
+			.enable_all()
+			.worker_threads(2)
+			.build()?;
+		let res = client_inner(self, &rt);
+		// client_inner() already waited for the websocket to close. This is for anything else still running.
+		rt.shutdown_timeout(Duration::from_secs_f64(0.5));
+		res
+	}
 }
 
-fn client_inner(
+/// `simulcast-mpv client`: logs to stdout & the temp directory then runs `settings`.
+/// `log_dir: None` only logs to stdout.
+pub fn client(
 	verbosity: log::LevelFilter,
-	relay_url: Option<http::Uri>,
-	relay_room: String,
-	client_sock: String,
-	rt: &Runtime,
+	log_dir: Option<std::path::PathBuf>,
+	settings: SimulcastClient,
 ) -> anyhow::Result<()> {
-	let verbosity = if true { log::LevelFilter::Debug } else { verbosity };
-	flexi_logger::Logger::with(
+	let logger = flexi_logger::Logger::with(
 		flexi_logger::LogSpecification::builder()
 			.default(verbosity)
 			.module("rustls", log::LevelFilter::Warn)
@@ -242,10 +1487,13 @@ fn client_inner(
 			.build(),
 	)
 	.format(flexi_logger::detailed_format)
-	.log_to_stdout()
-	.log_to_file(flexi_logger::FileSpec::default().directory(std::env::temp_dir()))
-	// .log_to_file(flexi_logger::FileSpec::try_from("simulcast.log")?)
-	.start()?;
+	.log_to_stdout();
+	let _logger = match log_dir {
+		Some(log_dir) => logger
+			.log_to_file(flexi_logger::FileSpec::default().directory(log_dir))
+			.start()?,
+		None => logger.start()?,
+	};
 	// simple_logging::log_to_file("out.log", verbosity)?;
 
 	log_panics::init();
@@ -253,15 +1501,19 @@ fn client_inner(
 	// TODO: include git revision...?
 	info!("simulcast-mpv version {}!", env!("CARGO_PKG_VERSION"));
 
+	settings.run()
+}
+
+/// `--relay-url` or the first one from `--discovery-url`.
+fn resolve_relay_url(relay_url: Option<http::Uri>, discovery_url: &str, rt: &Runtime) -> anyhow::Result<http::Uri> {
 	let relay_url = if let Some(relay_url) = relay_url {
 		relay_url
 	} else {
 		// TODO: check list of urls to see if they're alive?
-		info!("querying server from https://rtldg.github.io/simulcast-mpv/servers.txt ...");
-		// github.io url used because it's cdn-backed and probably won't bother github too much if we fetch it all the time
+		info!("querying server from {discovery_url} ...");
 		let resp = rt.block_on(async {
 			reqwest::Client::new()
-				.get("https://rtldg.github.io/simulcast-mpv/servers.txt")
+				.get(discovery_url)
 				.header(
 					"user-agent",
 					format!(
@@ -274,15 +1526,10 @@ fn client_inner(
 				.send()
 				.await
 		})?;
-		rt.block_on(async { resp.text().await })?
-			.lines()
-			.next()
-			.unwrap()
-			.trim()
-			.parse()?
+		let list = rt.block_on(async { resp.error_for_status()?.text().await })?;
+		parse_server_list(&list).with_context(|| format!("bad server list from {discovery_url}"))?
 	};
 
-	// TODO: Throw error messages up on mpv's screen too...
 	if relay_url.host().is_none() {
 		return Err(anyhow!("relay url is missing a host. url: '{relay_url}'"));
 	}
@@ -291,9 +1538,239 @@ fn client_inner(
 			"relay url scheme must be 'ws://' or 'wss://'. url: '{relay_url}'"
 		));
 	}
+	Ok(relay_url)
+}
+
+/// `--solo-test`: a relay inside the client & a proxy in front of it that adds `latency` both ways.
+/// Returns the proxy's url (for us) & the relay's (for `solo_test_partner()`).
+#[cfg(feature = "server")]
+async fn solo_test_relay(latency: Duration, ping: PingSettings) -> anyhow::Result<(http::Uri, String)> {
+	let relay =
+		crate::server::RelayServer::bind("127.0.0.1:0".parse()?, env!("CARGO_PKG_REPOSITORY").parse()?, ping).await?;
+	let relay_addr = relay.local_addr().context("in-process relay has no address")?;
+	tokio::spawn(relay.serve(std::future::pending()));
+
+	let proxy = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+	let proxy_addr = proxy.local_addr()?;
+	tokio::spawn(async move {
+		while let Ok((client, _)) = proxy.accept().await {
+			let Ok(relay) = tokio::net::TcpStream::connect(relay_addr).await else {
+				continue;
+			};
+			let (client_r, client_w) = client.into_split();
+			let (relay_r, relay_w) = relay.into_split();
+			tokio::spawn(delayed_copy(client_r, relay_w, latency / 2));
+			tokio::spawn(delayed_copy(relay_r, client_w, latency / 2));
+		}
+	});
+	info!("solo test: relay on {relay_addr} with {latency:?} of latency through {proxy_addr}");
+	Ok((format!("ws://{proxy_addr}").parse()?, format!("ws://{relay_addr}")))
+}
+
+#[cfg(not(feature = "server"))]
+async fn solo_test_relay(_latency: Duration, _ping: PingSettings) -> anyhow::Result<(http::Uri, String)> {
+	anyhow::bail!("--solo-test needs the relay, which isn't in this build (the `server` feature)")
+}
+
+/// Copies `from` to `to` with everything arriving `delay` late.
+#[cfg(feature = "server")]
+async fn delayed_copy(
+	mut from: tokio::net::tcp::OwnedReadHalf,
+	mut to: tokio::net::tcp::OwnedWriteHalf,
+	delay: Duration,
+) {
+	use tokio::io::{AsyncReadExt, AsyncWriteExt};
+	let (queue, mut queued) = tokio::sync::mpsc::unbounded_channel::<(tokio::time::Instant, Vec<u8>)>();
+	let writer = tokio::spawn(async move {
+		while let Some((at, bytes)) = queued.recv().await {
+			tokio::time::sleep_until(at).await;
+			if to.write_all(&bytes).await.is_err() {
+				return;
+			}
+		}
+		let _ = to.shutdown().await;
+	});
+	let mut buf = vec![0; 4096];
+	while let Ok(n @ 1..) = from.read(&mut buf).await {
+		if queue
+			.send((tokio::time::Instant::now() + delay, buf[..n].to_vec()))
+			.is_err()
+		{
+			break;
+		}
+	}
+	drop(queue);
+	let _ = writer.await;
+}
+
+/// `--solo-test`'s pretend partner. Follows us between rooms & answers like an mpv that's always in sync,
+/// so the relay sends our own pauses/seeks/resumes back to us.
+async fn solo_test_partner(
+	relay_url: String,
+	room_password: Option<String>,
+	state: Arc<Mutex<SharedState>>,
+) -> anyhow::Result<()> {
+	let (mut ws, _) = tokio_tungstenite::connect_async(relay_url.as_str()).await?;
+	ws.send(
+		WsMessage::Info2 {
+			version: env!("CARGO_PKG_VERSION").to_string(),
+			nickname: "solo test".to_string(),
+		}
+		.to_websocket_msg(),
+	)
+	.await?;
+	let mut room_hash = String::new();
+	let mut interval = tokio::time::interval(Duration::from_millis(250));
+	loop {
+		tokio::select! {
+			_ = interval.tick() => {
+				let current = state.lock().unwrap().room_hash.clone();
+				if current != room_hash && !current.is_empty() {
+					room_hash = current;
+					ws.send(join_message(room_hash.clone(), room_password.as_deref()).to_websocket_msg()).await?;
+				}
+			}
+			msg = ws.next() => {
+				let Some(msg) = msg else {
+					return Ok(());
+				};
+				let tungstenite::Message::Text(text) = msg? else {
+					continue;
+				};
+				match WsMessage::parse(&text) {
+					Ok(WsMessage::Ping(s)) => ws.send(WsMessage::Pong(s).to_websocket_msg()).await?,
+					Ok(WsMessage::RequestState) => {
+						let (time, paused) = {
+							let state = state.lock().unwrap();
+							(state.time, state.paused)
+						};
+						ws.send(WsMessage::State { time, paused }.to_websocket_msg()).await?;
+					}
+					_ => (),
+				}
+			}
+		}
+	}
+}
 
+/// `simulcast-mpv check-relay`: connects, says hello like the client does, & prints what the relay answers.
+/// Errors if the relay is unreachable or says we're too old for it.
+pub fn check_relay(
+	relay_url: Option<http::Uri>,
+	discovery_url: &str,
+	ca_cert: Option<&std::path::Path>,
+) -> anyhow::Result<()> {
+	let rt = Runtime::new()?;
+	let relay_url = resolve_relay_url(relay_url, discovery_url, &rt)?;
+	let connector = ca_cert.map(tls_connector).transpose()?;
+	println!("relay: {relay_url}");
+
+	rt.block_on(async {
+		let mut ws = connect_relay(&relay_url.to_string(), connector).await?;
+		let sent_at = std::time::Instant::now();
+		ws.send(WsMessage::Info(String::new()).to_websocket_msg()).await?;
+		ws.send(
+			WsMessage::Info2 {
+				version: env!("CARGO_PKG_VERSION").to_string(),
+				nickname: String::new(),
+			}
+			.to_websocket_msg(),
+		)
+		.await?;
+
+		// `Incompatible` (if it's coming) is right behind `Info` & `ServerInfo`.
+		let mut got_info = false;
+		let mut deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+		loop {
+			let msg = match tokio::time::timeout_at(deadline, ws.next()).await {
+				Ok(Some(msg)) => msg?,
+				Ok(None) => anyhow::bail!("the relay closed the connection"),
+				Err(_) if got_info => break,
+				Err(_) => anyhow::bail!("the relay didn't answer within 10s"),
+			};
+			let Ok(text) = msg.to_text() else {
+				continue;
+			};
+			match WsMessage::parse(text) {
+				Ok(WsMessage::Info(info)) => {
+					println!("{info}");
+					println!("round trip: {}ms", sent_at.elapsed().as_millis());
+					got_info = true;
+					deadline = tokio::time::Instant::now() + Duration::from_millis(500);
+				}
+				Ok(WsMessage::ServerInfo {
+					uptime_secs,
+					connected,
+					rooms,
+					..
+				}) => {
+					println!(
+						"up for {}h{}m with {connected} clients in {rooms} rooms",
+						uptime_secs / 3600,
+						uptime_secs / 60 % 60
+					);
+				}
+				Ok(WsMessage::Incompatible { min_version }) => anyhow::bail!(
+					"the relay needs simulcast-mpv {min_version} or newer (this is {})",
+					env!("CARGO_PKG_VERSION")
+				),
+				_ => (),
+			}
+		}
+		let _ = ws.close().await;
+		println!("ok");
+		Ok(())
+	})
+}
+
+fn client_inner(settings: SimulcastClient, rt: &Runtime) -> anyhow::Result<()> {
+	let SimulcastClient {
+		relay_url,
+		discovery_url,
+		relay_room,
+		room_password,
+		invite,
+		nickname,
+		ca_cert,
+		client_sock,
+		ping,
+		drift,
+		sync_mode,
+		sync_profile,
+		acks,
+		solo_resume,
+		observer,
+		join_countdown,
+		join_pause,
+		room_code_rules,
+		osd,
+		mpv_timeout,
+		share_title,
+		heartbeat,
+		solo_test,
+	} = settings;
+
+	info!("sync profile = {sync_profile:?}");
+	let drift = drift.or(sync_profile.drift());
+
+	// TODO: Throw error messages up on mpv's screen too...
+	let (relay_url, solo_test_url) = match solo_test {
+		Some(latency) => {
+			let (relay_url, partner_url) = rt.block_on(solo_test_relay(latency, ping))?;
+			(relay_url, Some(partner_url))
+		}
+		None => (resolve_relay_url(relay_url, &discovery_url, rt)?, None),
+	};
 	info!("relay_url = '{relay_url}'");
 
+	let connector = ca_cert.as_deref().map(tls_connector).transpose()?;
+
+	let client_sock = match client_sock {
+		Some(client_sock) => client_sock,
+		None => crate::mpvipc::find_socket()?,
+	};
+	info!("client_sock = '{client_sock}'");
+
 	// The previously-used mpvipc crate would potentially eat events, which isn't optimal.
 	// It's still easier to separate sockets for events & querying to help minimize
 	// the chance of bugs until I finish more TODOs in mpvipc.rs
@@ -301,267 +1778,1242 @@ fn client_inner(
 		Mpv::connect(&client_sock).context(format!("failed to connect to mpv socket '{}'", client_sock))?;
 	let mut mpv_query = Mpv::connect(&client_sock)?;
 	mpv_query.events(false);
+	mpv_query.set_timeout(mpv_timeout)?;
 	let mut mpv_ws = Mpv::connect(&client_sock)?;
 	mpv_ws.events(false);
+	mpv_ws.set_timeout(mpv_timeout)?;
+
+	log_client_name(&mut mpv_events, "simulcast-events");
+	log_client_name(&mut mpv_query, "simulcast-query");
+	log_client_name(&mut mpv_ws, "simulcast-ws");
 
 	info!("mpv objects are setup...");
 
+	// Everything goes through `user-data/simulcast/*` (the Lua script, status, etc) & older mpv doesn't have `user-data`.
+	// `set_property()` doesn't check for errors so the `get_property()` is what fails.
+	let _ = mpv_query.set_property("user-data/simulcast/probe", &json!(true));
+	if mpv_query.get_property("user-data/simulcast/probe").is_err() {
+		error!("mpv doesn't support user-data properties. simulcast-mpv needs mpv {MIN_MPV_VERSION} or newer");
+		let _ = mpv_query.show_text(
+			&format!("SIMULCAST\nplease update mpv\n(simulcast-mpv needs mpv {MIN_MPV_VERSION} or newer)"),
+			Some(10000),
+			None,
+		);
+		return Ok(());
+	}
+
 	let heartbeat_sock = client_sock.clone();
-	let _ = std::thread::spawn(move || {
-		let mut mpv_heartbeat = Mpv::connect(&heartbeat_sock).unwrap();
-		mpv_heartbeat.events(false);
-		// with a 32-bit build: it'd take 13.6y to finish this loop 😇
-		for i in 1..usize::MAX {
-			std::thread::sleep(Duration::from_secs_f64(0.1));
-			if let Err(_) = mpv_heartbeat.set_property("user-data/simulcast/heartbeat", &json!(i)) {
-				// mpv most likely exited (or if the property setting is failing: everything is already fucked!)
-				return;
+	let heartbeat_running = Arc::new(std::sync::atomic::AtomicBool::new(true));
+	let heartbeat_running_thread = heartbeat_running.clone();
+	let heartbeat = heartbeat.then(|| {
+		std::thread::spawn(move || {
+			let mut mpv_heartbeat = Mpv::connect(&heartbeat_sock).unwrap();
+			mpv_heartbeat.events(false);
+			log_client_name(&mut mpv_heartbeat, "simulcast-heartbeat");
+			// with a 32-bit build: it'd take 13.6y to finish this loop 😇
+			for i in 1..usize::MAX {
+				std::thread::sleep(Duration::from_secs_f64(0.1));
+				if !heartbeat_running_thread.load(std::sync::atomic::Ordering::Relaxed) {
+					let _ = mpv_heartbeat.detach();
+					return;
+				}
+				if mpv_heartbeat
+					.set_property("user-data/simulcast/heartbeat", &json!(i))
+					.is_err()
+				{
+					// mpv most likely exited (or if the property setting is failing: everything is already fucked!)
+					return;
+				}
 			}
-		}
+		})
 	});
+	if heartbeat.is_none() {
+		// Tells the Lua script to stop waiting for beats. mpv quitting is still caught by the "shutdown" event.
+		info!("heartbeat disabled");
+		mpv_query.set_property("user-data/simulcast/heartbeat", &json!(-1))?;
+	}
 
-	let file = if let Ok(filename) = mpv_query.get_property("filename") {
-		let filename = filename.as_str().unwrap();
-		info!("file = '{filename}'");
-		filename.to_string()
+	// mpv starts us before the file is necessarily loaded. Nothing's listening to `mpv_events` yet so it can wait.
+	let file = mpv_events
+		.get_property_watch("filename", Duration::from_secs(5), &mut |f| f.is_string())
+		.ok()
+		.and_then(|f| f.as_str().map(str::to_string));
+	match &file {
+		Some(filename) => info!("file = '{filename}'"),
+		// mpv's idling. `on_filename()` joins the file's room once one's loaded.
+		None => info!("no file loaded yet. not joining a room until there is one"),
+	}
+
+	let (relay_room, room_password, room_code) = match invite.as_deref().map(str::trim) {
+		Some(invite) if !invite.is_empty() => {
+			if room_password.is_some() {
+				warn!("--invite replaces --room-password");
+			}
+			let (relay_room, room_password) = invite_settings(invite);
+			(relay_room, Some(room_password), invite.to_string())
+		}
+		_ => (relay_room, room_password, String::new()),
+	};
+	let preimage = match room_code.is_empty() {
+		true => file.as_deref().unwrap_or_default(),
+		false => &room_code,
+	};
+	let preimage = room_preimage(preimage, &relay_room, room_code_rules);
+	let room_hash = if room_code.is_empty() {
+		file.map(|file| get_room_hash(&file, &relay_room, room_code_rules))
+			.unwrap_or_default()
 	} else {
-		rand::random::<u64>().to_string()
+		let room_hash = get_room_hash(&room_code, &relay_room, room_code_rules);
+		// So people can check they typed the same invite.
+		info!("invite room hash = {room_hash}");
+		let _ = mpv_query.show_text(
+			&format!("SIMULCAST\ninvite room id = {}", &room_hash[..8]),
+			Some(5000),
+			None,
+		);
+		room_hash
 	};
 
 	let state = Arc::new(Mutex::new(SharedState {
 		party_count: 0,
 		paused: false,
 		time: 0.0,
-		room_code: String::new(),
-		room_hash: get_room_hash(&file, &relay_room),
+		room_code,
+		room_hash,
+		room_preimage: preimage,
+		nickname: nickname.unwrap_or_default(),
+		session: format!("{:032x}", rand::random::<u128>()),
+		acks,
+		solo_resume,
+		osd,
+		share_title,
+		roster: vec![],
+		leader: String::new(),
+		room_name: String::new(),
+		at_end: false,
+		pending: PendingPlayback::default(),
+		buffering: false,
+		connection_status: "connecting",
+		sync_profile,
+		observer,
+		join_countdown,
+		join_pause,
 	}));
 
+	publish_state(&mut mpv_query, &state);
+	// Read & written by the Lua script. Changing it renames us (`Events::on_nickname()`).
+	let nickname = state.lock().unwrap().nickname.clone();
+	mpv_query.set_property("user-data/simulcast/nickname", &json!(nickname))?;
+
+	if let Some(url) = solo_test_url {
+		let (room_password, state) = (room_password.clone(), state.clone());
+		rt.spawn(async move {
+			if let Err(e) = solo_test_partner(url, room_password, state).await {
+				error!("solo test partner: {e:?}");
+			}
+		});
+	}
+
 	let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel::<WsMessage>();
 	let state_ws = state.clone();
-	rt.spawn(async move {
+	let (relay_room_ws, room_password_ws) = (relay_room.clone(), room_password.clone());
+	let ws_task = rt.spawn(async move {
+		let mut reconnect = false;
 		loop {
-			let err = ws_thread(relay_url.to_string(), &mut mpv_ws, &mut receiver, state_ws.clone()).await;
+			let err = ws_thread(
+				relay_url.to_string(),
+				connector.clone(),
+				ping,
+				drift,
+				reconnect,
+				&relay_room_ws,
+				room_password_ws.as_deref(),
+				&mut mpv_ws,
+				&mut receiver,
+				state_ws.clone(),
+			)
+			.await;
 			if let Err(err) = err {
 				error!("{:?}", err);
 			} else {
 				// Sender/receiver closed and ws_thread returned because the program is about to exit.
+				// (Or the relay said we're too old.)
+				state_ws.lock().unwrap().connection_status = "disconnected";
+				publish_state(&mut mpv_ws, &state_ws);
 				return;
 			}
+			// `party_count` & `roster` are kept since the relay holds our slot for a few seconds.
+			state_ws.lock().unwrap().connection_status = "reconnecting";
+			publish_state(&mut mpv_ws, &state_ws);
+			reconnect = true;
+			tokio::time::sleep(Duration::from_secs_f64(std::f64::consts::PI)).await;
+		}
+	});
+
+	let mut events = Events {
+		mpv: Box::new(mpv_query),
+		rt: rt.handle().clone(),
+		state,
+		sender,
+		relay_room,
+		room_code_rules,
+		room_password,
+		client_sock,
+		sync_mode,
+		need_to_skip_first_unpause: true,
+		seeking: false,
+		A_spam_last: std::time::SystemTime::now(),
+		A_spam_count: 0,
+		A_spam_cooldown: std::time::SystemTime::UNIX_EPOCH,
+	};
+
+	let mut observers = PropertyObservers::default();
+	observers.observe(&mut mpv_events, "filename", Events::on_filename)?;
+	observers.observe(&mut mpv_events, "pause", Events::on_pause)?;
+	observers.observe(&mut mpv_events, "user-data/simulcast/fuckmpv", Events::on_fuckmpv)?;
+	observers.observe(&mut mpv_events, "user-data/simulcast/nickname", Events::on_nickname)?;
+	observers.observe(
+		&mut mpv_events,
+		"user-data/simulcast/input_reader",
+		Events::on_input_reader,
+	)?;
+	observers.observe(
+		&mut mpv_events,
+		"user-data/simulcast/force_resync",
+		Events::on_force_resync,
+	)?;
+	// Only becomes true with `keep-open`. "end-file" covers the rest.
+	observers.observe(&mut mpv_events, "eof-reached", Events::on_eof_reached)?;
+	observers.observe(&mut mpv_events, "paused-for-cache", Events::on_paused_for_cache)?;
+
+	let mut res = Ok(());
+	while let Ok(value) = mpv_events.listen_for_event() {
+		match handle_event(&mut events, &mut observers, &value) {
+			Ok(true) => (),
+			Ok(false) => break,
+			// Better to miss one event than to stop syncing.
+			Err(e) if crate::mpvipc::is_timeout(&e) => warn!("{e}. skipped event {value}"),
+			Err(e) => {
+				res = Err(e);
+				break;
+			}
+		}
+	}
+
+	// mpv is probably quitting but detach cleanly in case it isn't.
+	heartbeat_running.store(false, std::sync::atomic::Ordering::Relaxed);
+	if let Some(heartbeat) = heartbeat {
+		let _ = heartbeat.join();
+	}
+	let _ = mpv_events.detach();
+	let _ = events.mpv.detach();
+
+	// Dropping the last sender tells ws_thread to close the websocket. (The debounce timers hold clones for a bit.)
+	drop(events);
+	if rt.block_on(tokio::time::timeout(CLOSE_TIMEOUT, ws_task)).is_err() {
+		warn!("websocket didn't close within {CLOSE_TIMEOUT:?}");
+	}
+
+	res
+}
+
+/// Returns false when mpv is shutting down.
+fn handle_event(
+	events: &mut Events,
+	observers: &mut PropertyObservers<Events>,
+	value: &serde_json::Value,
+) -> anyhow::Result<bool> {
+	//debug!("{}", value);
+	// mpv can send events without these fields (or with weird types) so don't unwrap anything in here.
+	let Some(event) = value["event"].as_str() else {
+		debug!("event without a name: {value}");
+		return Ok(true);
+	};
+	match event {
+		"shutdown" => return Ok(false),
+		"property-change" => observers.dispatch(events, value)?,
+		"seek" => {
+			// playback-time isn't settled until the seek finishes so wait for "playback-restart".
+			events.seeking = true;
+		}
+		"end-file" if value["reason"].as_str() == Some("eof") => end_reached(&events.state, &events.sender),
+		"playback-restart" => events.on_playback_restart()?,
+		_ => (),
+	}
+	Ok(true)
+}
+
+/// Everything the mpv event handlers need.
+#[allow(non_snake_case)]
+struct Events {
+	// The "simulcast-query" connection.
+	mpv: Box<dyn MpvIpc>,
+	// For the `send_when_settled()` timers.
+	rt: tokio::runtime::Handle,
+	state: Arc<Mutex<SharedState>>,
+	sender: UnboundedSender<WsMessage>,
+	relay_room: String,
+	room_code_rules: RoomCodeRules,
+	room_password: Option<String>,
+	client_sock: String,
+	sync_mode: SyncMode,
+	need_to_skip_first_unpause: bool,
+	seeking: bool,
+	A_spam_last: std::time::SystemTime,
+	A_spam_count: u32,
+	A_spam_cooldown: std::time::SystemTime,
+}
+
+impl Events {
+	fn on_pause(&mut self, paused: bool) -> anyhow::Result<()> {
+		let Some(time) = self
+			.mpv
+			.get_property("playback-time/full")
+			.ok()
+			.and_then(|t| t.as_f64())
+		else {
+			debug!("pause called. paused={paused}, no time though");
+			return Ok(());
+		};
+		let reaction = {
+			let mut state = self.state.lock().unwrap();
+			debug!("pause called. state={}, new={}", state.paused, paused);
+			local_pause(&mut state, &mut self.need_to_skip_first_unpause, paused, time)
+		};
+		self.react(reaction)
+	}
+
+	fn react(&mut self, reaction: Reaction) -> anyhow::Result<()> {
+		if reaction.pause_mpv {
+			self.mpv.set_property("pause", &json!(true))?;
+		}
+		if let Some(time) = reaction.seek {
+			self.debounce_seek(time);
+		}
+		if reaction.resume {
+			self.debounce_resume();
+		}
+		Ok(())
+	}
+
+	fn on_eof_reached(&mut self, eof_reached: bool) -> anyhow::Result<()> {
+		if eof_reached {
+			end_reached(&self.state, &self.sender);
+		} else {
+			self.state.lock().unwrap().at_end = false;
+		}
+		Ok(())
+	}
+
+	/// Asks the room to wait while our cache refills. Once it has, everyone's pulled to our position & resumed.
+	fn on_paused_for_cache(&mut self, paused_for_cache: bool) -> anyhow::Result<()> {
+		let mut state = self.state.lock().unwrap();
+		if paused_for_cache {
+			if state.solo() || state.paused || state.buffering || state.observer {
+				return Ok(());
+			}
+			debug!("paused for cache. asking the room to wait");
+			state.buffering = true;
+			let _ = self.sender.send(WsMessage::Buffering(true));
+		} else if std::mem::take(&mut state.buffering) {
+			drop(state);
+			if let Some(time) = self
+				.mpv
+				.get_property("playback-time/full")
+				.ok()
+				.and_then(|t| t.as_f64())
 			{
-				let mut state = state_ws.lock().unwrap();
-				state.party_count = 0;
+				let msg = self.seek_message(time);
+				let _ = self.sender.send(msg);
 			}
-			tokio::time::sleep(Duration::from_secs_f64(3.1415926535897932384626433)).await;
+			let _ = self.sender.send(WsMessage::Buffering(false));
 		}
-	});
+		Ok(())
+	}
 
-	mpv_events.observe_property(1, "filename")?;
-	mpv_events.observe_property(2, "pause")?;
-	//mpv_events.observe_property(3, "playback-time")?;
-	mpv_events.observe_property(4, "user-data/simulcast/fuckmpv")?;
-	mpv_events.observe_property(5, "user-data/simulcast/input_reader")?;
+	/// Moves us to the new file's room. `None` (or "") is mpv idling with no file: we leave the room until one's loaded
+	/// instead of sitting in the old file's room with a party count of 0.
+	/// Custom room codes aren't tied to a file so they're kept either way.
+	fn on_filename(&mut self, filename: Option<String>) -> anyhow::Result<()> {
+		let filename = filename.unwrap_or_default();
+		let room_hash = {
+			let mut state = self.state.lock().unwrap();
+			state.at_end = false;
+			if !state.room_code.is_empty() {
+				// The roomid should:tm: still be valid.
+				return Ok(());
+			}
+			let room_hash = match filename.is_empty() {
+				true => String::new(),
+				false => get_room_hash(&filename, &self.relay_room, self.room_code_rules),
+			};
+			if room_hash == state.room_hash {
+				// Same file again. The relay ignores a `Join` for the room we're already in so nothing changes.
+				return Ok(());
+			}
+			state.party_count = 0;
+			state.roster.clear();
+			state.leader.clear();
+			state.room_name.clear();
+			state.room_preimage = room_preimage(&filename, &self.relay_room, self.room_code_rules);
+			state.room_hash = room_hash.clone();
+			room_hash
+		};
+		publish_state(&mut *self.mpv, &self.state);
+		if room_hash.is_empty() {
+			info!("no file loaded. leaving the room until there is one");
+			let _ = self.sender.send(WsMessage::Join(room_hash));
+		} else {
+			let _ = self.sender.send(join_message(room_hash, self.room_password.as_deref()));
+		}
+		Ok(())
+	}
 
-	// let mut tick = 0;
-	#[allow(non_snake_case)]
-	let (mut A_spam_last, mut A_spam_count, mut A_spam_cooldown) =
-		(std::time::SystemTime::now(), 0, std::time::SystemTime::UNIX_EPOCH);
+	/// Something (the Lua script, `set user-data/simulcast/nickname bob`) renamed us. Also used when reconnecting.
+	fn on_nickname(&mut self, nickname: Option<String>) -> anyhow::Result<()> {
+		let Some(nickname) = nickname else {
+			return Ok(());
+		};
+		// The relay trims it the same way but our copy is what's sent when reconnecting.
+		let cleaned: String = nickname.trim().chars().filter(|c| !c.is_control()).take(32).collect();
+		{
+			let mut state = self.state.lock().unwrap();
+			if cleaned == state.nickname {
+				return Ok(());
+			}
+			state.nickname = cleaned.clone();
+		}
+		info!("nickname = '{cleaned}'");
+		if cleaned != nickname {
+			self.mpv.set_property("user-data/simulcast/nickname", &json!(cleaned))?;
+		}
+		let _ = self.sender.send(WsMessage::Nick(cleaned));
+		Ok(())
+	}
 
-	let mut need_to_skip_first_unpause = true;
+	fn on_fuckmpv(&mut self, data: String) -> anyhow::Result<()> {
+		if data == "." {
+			return Ok(());
+		}
 
-	while let Ok(value) = mpv_events.listen_for_event() {
-		//debug!("{}", value);
-		match value["event"].as_str().unwrap() {
-			"shutdown" => return Ok(()),
-			"property-change" => {
-				match value["name"].as_str().unwrap() {
-					"pause" => {
-						let paused = value["data"].as_bool().unwrap();
-
-						let Ok(time) = mpv_query.get_property("playback-time/full") else {
-							debug!("pause called. paused={paused}, no time though");
-							continue;
-						};
-						let time = time.as_f64().unwrap();
-						let mut state = state.lock().unwrap();
+		debug!("user-data/simulcast/fuckmpv = '{data}'");
+		self.mpv.set_property("user-data/simulcast/fuckmpv", &json!("."))?;
+
+		if data == "queue_resume" {
+			let local_only = {
+				let state = self.state.lock().unwrap();
+				state.solo() || state.observer
+			};
+			if local_only {
+				self.mpv.set_property("pause", &json!(false))?;
+				return Ok(());
+			}
 
-						debug!("pause called. state={}, new={}", state.paused, paused);
+			// let time: f64 = mpv_query.get_property("playback-time/full")?;
+			// sender.send(WsMessage::AbsoluteSeek(time))?;
+			self.debounce_resume();
+		} else if data == "migrate_room" {
+			self.migrate_room();
+		} else if data == "catch_up" {
+			self.catch_up();
+		} else if data == "print_info" {
+			if self.A_spam_last.elapsed()? > Duration::from_secs(2) {
+				self.A_spam_count = 0;
+				self.A_spam_cooldown = std::time::SystemTime::UNIX_EPOCH;
+			}
 
-						if paused == state.paused {
-							continue;
-						}
+			self.A_spam_count += 1;
+			self.A_spam_last = std::time::SystemTime::now();
 
-						if !paused && need_to_skip_first_unpause {
-							need_to_skip_first_unpause = false;
-							if state.party_count > 1 {
-								drop(state);
-								mpv_query.set_property("pause", &json!(true))?;
-								continue;
-							}
-						}
+			if self.A_spam_count > 3 && self.A_spam_cooldown.elapsed()? > Duration::from_secs(2) {
+				self.A_spam_cooldown = std::time::SystemTime::now();
+				let input_reader_sock = self.client_sock.clone();
+				let _ = std::thread::spawn(|| spawn_input_reader(input_reader_sock));
+				// do prompt for custom room code...
+			}
 
-						state.time = time;
+			// holy shit I hate Lua
+			let (party_count, room_code, room_hash, room_name, roster, leader) = {
+				let state = self.state.lock().unwrap();
+				(
+					state.party_count,
+					state.room_code.clone(),
+					state.room_hash.clone(),
+					state.room_name.clone(),
+					state.roster.join("\n  "),
+					state.leader.clone(),
+				)
+			};
 
-						if state.party_count < 2 {
-							state.paused = paused;
-							continue;
-						}
+			let _ = self.mpv.show_text(
+				&format!("SIMULCAST\nparty count = {party_count}\ncustom room code = '{room_code}'\nroom id/hash = {room_hash}\nroom name = {room_name}\nleader = {leader}\nparty:\n  {roster}"),
+				Some(7000),
+				None
+			);
+		}
+		Ok(())
+	}
 
-						debug!("about to do pause stuff. state={}, new={}", state.paused, paused);
+	/// Moves the whole party to a new custom room code so everyone can switch files without splitting up.
+	fn migrate_room(&mut self) {
+		let osd = self.state.lock().unwrap().osd.clone();
+		if self.state.lock().unwrap().observer {
+			let _ = self.mpv.show_text(&osd.observers_cant_move_room, Some(2000), None);
+			return;
+		}
+		let nonce = format!("{:032x}", rand::random::<u128>());
+		let (room_code, room_hash) = {
+			let mut state = self.state.lock().unwrap();
+			state.room_code = migrated_room_code(&state.room_preimage, &nonce);
+			state.room_preimage = room_preimage(&state.room_code, &self.relay_room, self.room_code_rules);
+			state.room_hash = get_room_hash(&state.room_code, &self.relay_room, self.room_code_rules);
+			(state.room_code.clone(), state.room_hash.clone())
+		};
+		let auth_tag = match join_message(room_hash.clone(), self.room_password.as_deref()) {
+			WsMessage::Join2 { auth_tag, .. } => auth_tag,
+			_ => String::new(),
+		};
+		publish_state(&mut *self.mpv, &self.state);
+		let _ = self.mpv.show_text(&osd.moving_everyone(&room_code), Some(3000), None);
+		let _ = self.sender.send(WsMessage::MigrateRoom {
+			nonce,
+			room_hash,
+			auth_tag,
+		});
+	}
 
-						state.paused = true;
-						drop(state);
+	/// `alt+l`. Jumps to wherever the room is without moving anyone else (unlike `alt+r`).
+	/// It's the same `RequestState` that reconnecting sends.
+	fn catch_up(&mut self) {
+		let osd = self.state.lock().unwrap().osd.clone();
+		{
+			let mut state = self.state.lock().unwrap();
+			if state.party_count < 2 {
+				drop(state);
+				let _ = self.mpv.show_text(&osd.nobody_to_catch_up_to, Some(2000), None);
+				return;
+			}
+			// We're about to jump anyway.
+			state.pending.seek = None;
+			state.pending.resume = false;
+		}
+		let _ = self.sender.send(WsMessage::RequestState);
+	}
 
-						if paused {
-							let _ = sender.send(WsMessage::AbsoluteSeek(time));
-						} else {
-							// if we are here then we probably unpaused with the onscreen-display
-							mpv_query.set_property("pause", &json!(true))?;
-							let _ = sender.send(WsMessage::Resume);
-						}
-					}
-					"filename" => {
-						let Some(filename) = value.get("data") else {
-							continue;
-						};
-						let filename = filename.as_str().unwrap();
+	fn on_force_resync(&mut self, data: String) -> anyhow::Result<()> {
+		if data == "." {
+			return Ok(());
+		}
+		self.mpv.set_property("user-data/simulcast/force_resync", &json!("."))?;
+
+		let Some(time) = self
+			.mpv
+			.get_property("playback-time/full")
+			.ok()
+			.and_then(|t| t.as_f64())
+		else {
+			return Ok(());
+		};
+		let osd = self.state.lock().unwrap().osd.clone();
+		{
+			let mut state = self.state.lock().unwrap();
+			if state.solo() {
+				drop(state);
+				let _ = self.mpv.show_text(&osd.nobody_to_resync_with, Some(2000), None);
+				return Ok(());
+			}
+			if state.observer {
+				drop(state);
+				let _ = self.mpv.show_text(&osd.observers_cant_move_room, Some(2000), None);
+				return Ok(());
+			}
+			state.paused = true;
+			// Whatever was pending would undo the resync.
+			state.pending.seek = None;
+			state.pending.resume = false;
+		}
+		self.mpv.set_property("pause", &json!(true))?;
+		let _ = self.sender.send(WsMessage::ForceResync(time));
+		let _ = self.mpv.show_text(&osd.resyncing_everyone, Some(2000), None);
+		Ok(())
+	}
 
-						let room_hash = {
-							let mut state = state.lock().unwrap();
-							if !state.room_code.is_empty() {
-								// The roomid should:tm: still be valid.
-								continue;
-							} else {
-								state.party_count = 0;
-								if !filename.is_empty() {
-									state.room_hash = get_room_hash(filename, &relay_room);
-								}
-							}
-							state.room_hash.clone()
-						};
-						let _ = sender.send(WsMessage::Join(room_hash));
-					}
-					"user-data/simulcast/fuckmpv" => {
-						let Some(data) = value["data"].as_str() else {
-							// tf?
-							continue;
-						};
+	fn on_input_reader(&mut self, data: String) -> anyhow::Result<()> {
+		let room_hash = {
+			let mut state = self.state.lock().unwrap();
+			state.room_code = data;
+			if let Some(name) = state.room_code.strip_prefix('@') {
+				// Someone read out the relay's short name for their room.
+				// The relay knows the name anyway so there's nothing better to move a room on from.
+				state.room_hash = name.trim().to_string();
+				state.room_preimage = state.room_hash.clone();
+			} else {
+				let code = match state.room_code.is_empty() {
+					true => self
+						.mpv
+						.get_property("filename")
+						.ok()
+						.and_then(|v| v.as_str().map(str::to_string))
+						.unwrap_or_default(),
+					false => state.room_code.clone(),
+				};
+				if code.is_empty() {
+					// No file to fall back on. `on_filename()` joins its room once there is one.
+					state.party_count = 0;
+					state.roster.clear();
+					state.leader.clear();
+					state.room_name.clear();
+					state.room_preimage.clear();
+					state.room_hash.clear();
+				} else {
+					state.room_preimage = room_preimage(&code, &self.relay_room, self.room_code_rules);
+					state.room_hash = get_room_hash(&code, &self.relay_room, self.room_code_rules);
+				}
+			}
+			state.room_hash.clone()
+		};
+		publish_state(&mut *self.mpv, &self.state);
+		if room_hash.is_empty() {
+			info!("no file loaded. leaving the room until there is one");
+			let _ = self.sender.send(WsMessage::Join(room_hash));
+		} else {
+			let _ = self.sender.send(join_message(room_hash, self.room_password.as_deref()));
+		}
+		Ok(())
+	}
 
-						if data == "." {
-							continue;
-						}
+	/// Replaces any pending seek. A pending resume is dropped because the seek pauses everyone.
+	/// `AbsoluteSeek` or `ChapterSeek` depending on `--sync-mode`.
+	fn seek_message(&mut self, time: f64) -> WsMessage {
+		if self.sync_mode == SyncMode::Chapters {
+			if let Some((chapter, offset)) = chapter_position(&chapter_starts(&mut *self.mpv), time) {
+				return WsMessage::ChapterSeek { chapter, offset, time };
+			}
+		}
+		WsMessage::AbsoluteSeek(time)
+	}
 
-						debug!("user-data/simulcast/fuckmpv = '{data}'");
-						mpv_query.set_property("user-data/simulcast/fuckmpv", &json!("."))?;
+	fn debounce_seek(&mut self, time: f64) {
+		let msg = self.seek_message(time);
+		{
+			let mut state = self.state.lock().unwrap();
+			state.pending.seek = Some(msg);
+			state.pending.resume = false;
+		}
+		self.send_when_settled();
+	}
 
-						if data == "queue_resume" {
-							if state.lock().unwrap().party_count < 2 {
-								mpv_query.set_property("pause", &json!(false))?;
-								continue;
-							}
+	fn debounce_resume(&mut self) {
+		self.state.lock().unwrap().pending.resume = true;
+		self.send_when_settled();
+	}
 
-							// let time: f64 = mpv_query.get_property("playback-time/full")?;
-							// sender.send(WsMessage::AbsoluteSeek(time))?;
-							let _ = sender.send(WsMessage::Resume);
-						} else if data == "print_info" {
-							if A_spam_last.elapsed()? > Duration::from_secs(2) {
-								A_spam_count = 0;
-								A_spam_cooldown = std::time::SystemTime::UNIX_EPOCH;
-							}
+	/// Sends whatever is pending once nothing else has changed for `--sync-profile`'s debounce.
+	fn send_when_settled(&mut self) {
+		let (generation, debounce) = {
+			let mut state = self.state.lock().unwrap();
+			state.pending.generation += 1;
+			(state.pending.generation, state.sync_profile.debounce())
+		};
+		let state = self.state.clone();
+		let sender = self.sender.clone();
+		self.rt.spawn(async move {
+			tokio::time::sleep(debounce).await;
+			let (seek, resume) = {
+				let mut state = state.lock().unwrap();
+				if state.pending.generation != generation {
+					return;
+				}
+				(state.pending.seek.take(), std::mem::take(&mut state.pending.resume))
+			};
+			if let Some(msg) = seek {
+				let _ = sender.send(WsMessage::PauseReason {
+					reason: "seek".to_string(),
+					name: String::new(),
+				});
+				let _ = sender.send(msg);
+			}
+			if resume {
+				let _ = sender.send(WsMessage::Resume);
+			}
+		});
+	}
 
-							A_spam_count += 1;
-							A_spam_last = std::time::SystemTime::now();
+	fn on_playback_restart(&mut self) -> anyhow::Result<()> {
+		if !std::mem::take(&mut self.seeking) {
+			// File (re)loads and such.
+			return Ok(());
+		}
 
-							if A_spam_count > 3 && A_spam_cooldown.elapsed()? > Duration::from_secs(2) {
-								A_spam_cooldown = std::time::SystemTime::now();
-								let input_reader_sock = client_sock.clone();
-								let _ = std::thread::spawn(|| spawn_input_reader(input_reader_sock));
-								// do prompt for custom room code...
-							}
+		let Some(time) = self.mpv.get_property("playback-time/full")?.as_f64() else {
+			debug!("seek event but playback-time isn't a number");
+			return Ok(());
+		};
+		let Some(paused) = self.mpv.get_property("pause")?.as_bool() else {
+			debug!("seek event but pause isn't a bool");
+			return Ok(());
+		};
+		let reaction = {
+			let mut state = self.state.lock().unwrap();
+			debug!("seek finished. time = {}. expected = {}", time, state.time);
+			local_seek(&mut state, paused, time)
+		};
+		self.react(reaction)
+	}
+}
 
-							// holy shit I hate Lua
-							let (party_count, room_code, room_hash) = {
-								let state = state.lock().unwrap();
-								(state.party_count, state.room_code.clone(), state.room_hash.clone())
-							};
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn diagnostics_are_redacted() {
+		let report = "custom_room_code = \"our secret room\"\nuser-data/simulcast/input_reader = 'our secret room'\nparty_count = 2";
+		let redacted = redact(report, &["our secret room".to_string()]);
+		assert!(!redacted.contains("our secret room"));
+		assert_eq!(redacted.matches("<redacted>").count(), 2);
+		assert!(redacted.contains("party_count = 2"));
+
+		// Short ones take the whole line with them.
+		let redacted = redact("room_password = ab\nparty_count = 2", &["ab".to_string()]);
+		assert_eq!(redacted, "<redacted line>\nparty_count = 2");
+
+		// Older clients logged the room code they migrated to.
+		let log = r#"DEBUG recv msg = MigrateRoom { room_code: "party 0123abcd" }
+INFO joined party 0123abcd"#;
+		let redacted = redact(log, &[]);
+		assert!(!redacted.contains("0123abcd"), "{redacted}");
+	}
 
-							let _ = mpv_query.show_text(
-								&format!("SIMULCAST\nparty count = {party_count}\ncustom room code = '{room_code}'\nroom id/hash = {room_hash}"),
-								Some(7000),
-								None
-							);
-						}
-					}
-					"user-data/simulcast/input_reader" => {
-						let Some(data) = value["data"].as_str() else {
-							// tf?
-							continue;
-						};
-						let data = data.to_string();
+	#[test]
+	fn separators_share_a_room() {
+		let room = get_room_hash("Movie 2024 mkv", "abcd1234", RoomCodeRules::Classic);
+		for code in ["Movie.2024.mkv", "Movie_2024_mkv", "Movie-2024+mkv"] {
+			assert_eq!(get_room_hash(code, "abcd1234", RoomCodeRules::Classic), room, "{code}");
+		}
+		// Case & other characters still matter.
+		assert_ne!(
+			get_room_hash("movie.2024.mkv", "abcd1234", RoomCodeRules::Classic),
+			room
+		);
+		assert_ne!(
+			get_room_hash("Movie,2024,mkv", "abcd1234", RoomCodeRules::Classic),
+			room
+		);
+		assert_ne!(
+			get_room_hash("Movie 2024 mkv", "abcd1235", RoomCodeRules::Classic),
+			room
+		);
+	}
 
-						let room_hash = {
-							let mut state = state.lock().unwrap();
-							state.room_code = data;
-							if !state.room_code.is_empty() {
-								state.room_hash = get_room_hash(&state.room_code, &relay_room);
-							} else {
-								state.room_hash = get_room_hash(
-									&mpv_query
-										.get_property("filename")
-										.map(|v| v.as_str().unwrap_or_default().to_string())
-										.unwrap_or_else(|_| rand::random::<u64>().to_string()),
-									&relay_room,
-								);
-							}
-							state.room_hash.clone()
-						};
-						let _ = sender.send(WsMessage::Join(room_hash));
+	#[test]
+	fn room_code_rules() {
+		use RoomCodeRules::*;
+		#[rustfmt::skip]
+		let cases = [
+			(Classic, "Movie.2024.mkv", "Movie 2024 mkv"),
+			(Classic, " Movie - 2024 ", " Movie   2024 "),
+			(Classic, "Cafe\u{301}", "Cafe\u{301}"),
+			(Unicode, "Movie.2024.mkv", "Movie 2024 mkv"),
+			(Unicode, " Movie - 2024 ", "Movie 2024"),
+			(Unicode, "Movie\t\u{3000}2024\n", "Movie 2024"),
+			// NFD (macOS) & NFC (Windows/Linux) spellings of the same name.
+			(Unicode, "Cafe\u{301}.mkv", "Caf\u{e9} mkv"),
+			(Unicode, "Caf\u{e9}.mkv", "Caf\u{e9} mkv"),
+			// Full-width.
+			(Unicode, "\u{ff2d}\u{ff4f}\u{ff56}\u{ff49}\u{ff45}\u{ff0e}\u{ff12}\u{ff10}\u{ff12}\u{ff14}", "Movie 2024"),
+			(Unicode, "MOVIE.mkv", "MOVIE mkv"),
+			(Caseless, "MOVIE.mkv", "movie mkv"),
+			(Caseless, "\u{d6}STERREICH", "\u{f6}sterreich"),
+			(Caseless, "O\u{308}sterreich", "\u{f6}sterreich"),
+			(Caseless, "  ", ""),
+		];
+		for (rules, code, normalized) in cases {
+			assert_eq!(normalize_room_code(code, rules), normalized, "{rules:?} {code:?}");
+		}
+
+		// Classic is what older clients hash so it can't change.
+		assert_eq!(
+			get_room_hash("Movie.2024.mkv", "abcd1234", Classic),
+			blake3::hash(b"Movie 2024 mkvabcd1234").to_hex().to_string()
+		);
+	}
+
+	#[test]
+	fn migrated_room_codes() {
+		use RoomCodeRules::*;
+		// Members with differently named copies of a file still end up with the same code.
+		let a = room_preimage("Movie.2024.mkv", "abcd1234", Classic);
+		let b = room_preimage("Movie_2024_mkv", "abcd1234", Classic);
+		let code = migrated_room_code(&a, "nonce");
+		assert_eq!(code, migrated_room_code(&b, "nonce"));
+		assert_ne!(code, migrated_room_code(&a, "other nonce"));
+		// The relay knows the old hash but not what it's a hash of.
+		assert_ne!(
+			code,
+			migrated_room_code(&get_room_hash("Movie.2024.mkv", "abcd1234", Classic), "nonce")
+		);
+		for rules in [Classic, Unicode, Caseless] {
+			assert_eq!(normalize_room_code(&code, rules), code);
+		}
+	}
+
+	#[test]
+	fn pause_seek_resume() {
+		// Solo user opens mpv. mpv starts unpaused so there's nothing to skip.
+		let mut a = SharedState::default();
+		let mut a_skip = false;
+		assert_eq!(party_changed(&mut a, 1), (false, false));
+		assert_eq!(local_pause(&mut a, &mut a_skip, false, 0.0), Reaction::default());
+		// Solo pausing & seeking is nobody else's business.
+		assert_eq!(local_pause(&mut a, &mut a_skip, true, 5.0), Reaction::default());
+		assert!(a.paused);
+		assert_eq!(local_pause(&mut a, &mut a_skip, false, 5.0), Reaction::default());
+		assert_eq!(local_seek(&mut a, false, 20.0), Reaction::default());
+		assert!(!a.paused);
+
+		// Second user joins. Both pause & a tells the room where it's at.
+		let mut b = SharedState::default();
+		let mut b_skip = true;
+		assert_eq!(party_changed(&mut b, 2), (true, false));
+		assert_eq!(party_changed(&mut a, 2), (true, true));
+		// mpv's pause properties catch up.
+		assert_eq!(local_pause(&mut a, &mut a_skip, true, 20.0), Reaction::default());
+		assert_eq!(local_pause(&mut b, &mut b_skip, true, 0.0), Reaction::default());
+
+		// a seeks. The room is asked & the relay echoes it to both.
+		assert_eq!(
+			local_seek(&mut a, true, 50.0),
+			Reaction {
+				seek: Some(50.0),
+				..Default::default()
+			}
+		);
+		remote_seek(&mut a, 50.0);
+		remote_seek(&mut b, 50.0);
+		// Those seeks finishing aren't new seeks.
+		assert_eq!(local_seek(&mut a, true, 50.0), Reaction::default());
+		assert_eq!(local_seek(&mut b, true, 50.01), Reaction::default());
+
+		// b unpauses. The first one is eaten because mpv might've started playing before we knew about the party.
+		let paused_again = Reaction {
+			pause_mpv: true,
+			..Default::default()
+		};
+		assert_eq!(local_pause(&mut b, &mut b_skip, false, 50.0), paused_again);
+		assert!(!b_skip);
+		assert_eq!(
+			local_pause(&mut b, &mut b_skip, false, 50.0),
+			Reaction {
+				pause_mpv: true,
+				resume: true,
+				..Default::default()
+			}
+		);
+		// Nobody plays until the room resumes.
+		assert!(b.paused);
+		remote_resume(&mut a);
+		remote_resume(&mut b);
+		// mpv unpausing because of the `Resume` doesn't go back to the room.
+		assert_eq!(local_pause(&mut a, &mut a_skip, false, 50.0), Reaction::default());
+		assert_eq!(local_pause(&mut b, &mut b_skip, false, 50.0), Reaction::default());
+
+		// Seeking while playing pauses mpv first.
+		assert_eq!(
+			local_seek(&mut b, false, 80.0),
+			Reaction {
+				pause_mpv: true,
+				seek: Some(80.0),
+				..Default::default()
+			}
+		);
+	}
+
+	#[test]
+	fn join_pause_policies() {
+		let party_of_two = |join_pause, paused| SharedState {
+			party_count: 2,
+			paused,
+			join_pause,
+			..Default::default()
+		};
+
+		let mut a = party_of_two(JoinPause::Never, false);
+		assert_eq!(party_changed(&mut a, 3), (false, false));
+		assert_eq!(a.party_count, 3);
+		assert!(!a.paused);
+
+		let mut a = party_of_two(JoinPause::OnlyIfPlaying, true);
+		assert_eq!(party_changed(&mut a, 3), (false, false));
+		let mut a = party_of_two(JoinPause::OnlyIfPlaying, false);
+		assert_eq!(party_changed(&mut a, 3), (true, true));
+
+		// Leaving still pauses.
+		let mut a = party_of_two(JoinPause::Never, false);
+		assert_eq!(party_changed(&mut a, 1), (true, false));
+	}
+
+	#[test]
+	fn server_lists() {
+		let list = "# my relays\n\n  wss://example.com/simulcast-mpv  \nws://backup.example.com\n";
+		assert_eq!(parse_server_list(list).unwrap(), "wss://example.com/simulcast-mpv");
+		assert!(parse_server_list("").is_err());
+		assert!(parse_server_list("# nothing here\n").is_err());
+	}
+
+	#[test]
+	fn chapter_positions() {
+		let starts = [0.0, 90.0, 600.0];
+		assert_eq!(chapter_position(&starts, 30.0), Some((0, 30.0)));
+		assert_eq!(chapter_position(&starts, 100.0), Some((1, 10.0)));
+		assert_eq!(chapter_position(&starts, 600.0), Some((2, 0.0)));
+		// Files that don't start with a chapter.
+		assert_eq!(chapter_position(&[10.0], 5.0), None);
+		assert_eq!(chapter_position(&[], 5.0), None);
+	}
+
+	#[test]
+	fn relay_room_validation() {
+		assert_eq!(parse_relay_room("  abcd1234\n").as_deref(), Ok("abcd1234"));
+		assert!(parse_relay_room("").is_err());
+		assert!(parse_relay_room("   ").is_err());
+		assert!(parse_relay_room("ab\u{7}cd").is_err());
+		assert!(parse_relay_room(&"a".repeat(MAX_RELAY_ROOM_LEN)).is_ok());
+		assert!(parse_relay_room(&"a".repeat(MAX_RELAY_ROOM_LEN + 1)).is_err());
+	}
+
+	/// Drives clients with fake mpvs through a real relay.
+	#[cfg(feature = "server")]
+	mod relay {
+		use super::*;
+
+		/// Stands in for mpv. Clones share the same properties & events.
+		#[derive(Clone, Default)]
+		struct FakeMpv(Arc<Mutex<FakeMpvState>>);
+
+		#[derive(Default)]
+		struct FakeMpvState {
+			properties: std::collections::HashMap<String, serde_json::Value>,
+			set_property_calls: Vec<(String, serde_json::Value)>,
+			events: std::collections::VecDeque<serde_json::Value>,
+		}
+
+		impl FakeMpv {
+			fn property(&self, name: &str) -> serde_json::Value {
+				self.0.lock().unwrap().properties.get(name).cloned().unwrap_or_default()
+			}
+
+			fn last_pause(&self) -> Option<serde_json::Value> {
+				let fake = self.0.lock().unwrap();
+				let (_, value) = fake.set_property_calls.iter().rev().find(|(name, _)| name == "pause")?;
+				Some(value.clone())
+			}
+		}
+
+		impl MpvIpc for FakeMpv {
+			fn send(&mut self, json: &serde_json::Value) -> anyhow::Result<serde_json::Value> {
+				let command = &json["command"];
+				let mut fake = self.0.lock().unwrap();
+				match command[0].as_str() {
+					Some("get_property") => {
+						return Ok(match fake.properties.get(command[1].as_str().unwrap()) {
+							Some(v) => json!({"error": "success", "data": v}),
+							None => json!({"error": "property unavailable"}),
+						});
 					}
-					"playback-time" => {
-						// tick += 1;
-						// mpv.run_command(MpvCommand::ShowText {
-						// 	text: tick.to_string(),
-						// 	duration_ms: Some(100),
-						// 	level: None,
-						// })?;
+					Some("set_property") => {
+						let name = command[1].as_str().unwrap().to_string();
+						fake.properties.insert(name.clone(), command[2].clone());
+						fake.set_property_calls.push((name, command[2].clone()));
+					}
+					Some("osd-auto") if command[1] == "seek" && command[3] == "absolute+exact" => {
+						let time: f64 = command[2].as_str().unwrap().parse()?;
+						fake.properties.insert("playback-time/full".into(), json!(time));
 					}
 					_ => (),
 				}
+				Ok(json!({"error": "success"}))
 			}
-			"seek" => {
-				// This is dumb but necessary. We need *some* wait here otherwise it's desynced.
-				// Related place to edit in server.rs. Ctrl+f "BROCCOLI".
-				std::thread::sleep(Duration::from_millis(100));
 
-				let time = mpv_query.get_property("playback-time/full")?.as_f64().unwrap();
-				let paused = mpv_query.get_property("pause")?.as_bool().unwrap();
-				let mut state = state.lock().unwrap();
+			fn listen_for_event(&mut self) -> anyhow::Result<serde_json::Value> {
+				self.0
+					.lock()
+					.unwrap()
+					.events
+					.pop_front()
+					.ok_or(anyhow!("out of events"))
+			}
+		}
+
+		/// Connects a client with a fake mpv to `relay`. Events pushed to the fake mpv are handled by `run_events()`.
+		fn test_client(relay: std::net::SocketAddr) -> (FakeMpv, Events, PropertyObservers<Events>) {
+			test_client_with(relay, |_, _| ())
+		}
 
-				debug!("Event::Seek. time = {}. expected = {}", time, state.time);
+		/// `test_client()` with a chance to change its state & mpv's properties before it connects.
+		fn test_client_with(
+			relay: std::net::SocketAddr,
+			setup: impl FnOnce(&mut SharedState, &mut FakeMpvState),
+		) -> (FakeMpv, Events, PropertyObservers<Events>) {
+			let mpv = FakeMpv::default();
+			{
+				let mut fake = mpv.0.lock().unwrap();
+				let _ = fake.properties.insert("playback-time/full".into(), json!(0.0));
+				let _ = fake.properties.insert("pause".into(), json!(false));
+			}
+			let state = Arc::new(Mutex::new(SharedState {
+				party_count: 0,
+				paused: false,
+				time: 0.0,
+				room_code: String::new(),
+				room_hash: get_room_hash("movie.mkv", "abcd1234", RoomCodeRules::Classic),
+				room_preimage: room_preimage("movie.mkv", "abcd1234", RoomCodeRules::Classic),
+				nickname: String::new(),
+				session: String::new(),
+				acks: false,
+				solo_resume: false,
+				osd: OsdStrings::default(),
+				share_title: false,
+				roster: vec![],
+				leader: String::new(),
+				room_name: String::new(),
+				at_end: false,
+				pending: PendingPlayback::default(),
+				buffering: false,
+				connection_status: "connecting",
+				sync_profile: SyncProfile::Normal,
+				observer: false,
+				join_countdown: 0,
+				join_pause: JoinPause::Always,
+			}));
+			setup(&mut state.lock().unwrap(), &mut mpv.0.lock().unwrap());
+
+			let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+			let mut mpv_ws = mpv.clone();
+			let state_ws = state.clone();
+			let ping = PingSettings {
+				interval: Duration::from_secs(1),
+				timeout: Duration::from_secs(10),
+			};
+			tokio::spawn(async move {
+				let url = format!("ws://{relay}/");
+				ws_thread(
+					url,
+					None,
+					ping,
+					None,
+					false,
+					"abcd1234",
+					None,
+					&mut mpv_ws,
+					&mut receiver,
+					state_ws,
+				)
+				.await
+			});
+
+			let events = Events {
+				mpv: Box::new(mpv.clone()),
+				rt: tokio::runtime::Handle::current(),
+				state,
+				sender,
+				relay_room: "abcd1234".to_string(),
+				room_code_rules: RoomCodeRules::Classic,
+				room_password: None,
+				client_sock: String::new(),
+				sync_mode: SyncMode::Time,
+				need_to_skip_first_unpause: false,
+				seeking: false,
+				A_spam_last: std::time::SystemTime::now(),
+				A_spam_count: 0,
+				A_spam_cooldown: std::time::SystemTime::UNIX_EPOCH,
+			};
+			let mut observers = PropertyObservers::default();
+			observers
+				.observe(&mut mpv.clone(), "user-data/simulcast/fuckmpv", Events::on_fuckmpv)
+				.unwrap();
+			(mpv, events, observers)
+		}
 
-				if (time - state.time).abs() > 0.03 {
-					// seems like we seeked...
+		fn run_events(
+			mpv: &FakeMpv,
+			events: &mut Events,
+			observers: &mut PropertyObservers<Events>,
+			canned: &[serde_json::Value],
+		) {
+			mpv.0.lock().unwrap().events.extend(canned.iter().cloned());
+			while let Ok(value) = mpv.clone().listen_for_event() {
+				assert!(handle_event(events, observers, &value).unwrap());
+			}
+		}
 
-					state.time = time;
-					let party_count = state.party_count;
+		async fn wait_until(what: &str, mut done: impl FnMut() -> bool) {
+			for _ in 0..500 {
+				if done() {
+					return;
+				}
+				tokio::time::sleep(Duration::from_millis(10)).await;
+			}
+			panic!("timed out waiting for {what}");
+		}
 
-					if party_count > 1 {
-						state.paused = true;
-					}
+		#[tokio::test]
+		async fn join_seek_resume() {
+			let relay = crate::server::spawn_test_relay().await;
+
+			let (mpv_a, mut a, mut observers_a) = test_client(relay);
+			wait_until("a to join", || a.state.lock().unwrap().party_count == 1).await;
+			let (mpv_b, b, _) = test_client(relay);
+			wait_until("the party", || {
+				a.state.lock().unwrap().party_count == 2 && b.state.lock().unwrap().party_count == 2
+			})
+			.await;
+			assert_eq!(mpv_a.last_pause(), Some(json!(true)));
+			assert_eq!(mpv_b.last_pause(), Some(json!(true)));
+
+			// a seeks to 1:40...
+			let _ = mpv_a
+				.0
+				.lock()
+				.unwrap()
+				.properties
+				.insert("playback-time/full".into(), json!(100.0));
+			run_events(
+				&mpv_a,
+				&mut a,
+				&mut observers_a,
+				&[json!({"event": "seek"}), json!({"event": "playback-restart"})],
+			);
+			wait_until("b to seek", || mpv_b.property("playback-time/full") == json!(100.0)).await;
+			assert_eq!(mpv_b.last_pause(), Some(json!(true)));
+			assert!(b.state.lock().unwrap().paused);
+
+			// ...then hits space.
+			run_events(
+				&mpv_a,
+				&mut a,
+				&mut observers_a,
+				&[
+					json!({"event": "property-change", "id": 1, "name": "user-data/simulcast/fuckmpv", "data": "queue_resume"}),
+				],
+			);
+			wait_until("everyone to resume", || {
+				mpv_a.last_pause() == Some(json!(false)) && mpv_b.last_pause() == Some(json!(false))
+			})
+			.await;
+			assert_eq!(mpv_a.property("user-data/simulcast/fuckmpv"), json!("."));
+		}
 
-					drop(state);
+		#[tokio::test]
+		async fn migrating_moves_everyone() {
+			let relay = crate::server::spawn_test_relay().await;
+
+			let (_, mut a, _) = test_client(relay);
+			let (_, b, _) = test_client(relay);
+			wait_until("the party", || {
+				a.state.lock().unwrap().party_count == 2 && b.state.lock().unwrap().party_count == 2
+			})
+			.await;
+
+			a.migrate_room();
+			let (room_code, room_hash) = {
+				let a = a.state.lock().unwrap();
+				(a.room_code.clone(), a.room_hash.clone())
+			};
+			assert_ne!(
+				room_hash,
+				get_room_hash("movie.mkv", "abcd1234", RoomCodeRules::Classic)
+			);
+			wait_until("b to follow", || b.state.lock().unwrap().room_code == room_code).await;
+			assert_eq!(b.state.lock().unwrap().room_hash, room_hash);
+			wait_until("the party", || {
+				a.state.lock().unwrap().party_count == 2 && b.state.lock().unwrap().party_count == 2
+			})
+			.await;
+		}
 
-					if party_count > 1 && !paused {
-						mpv_query.set_property("pause", &json!(true))?;
-					}
+		#[tokio::test]
+		async fn long_titles_still_join() {
+			let relay = crate::server::spawn_test_relay().await;
+
+			// ~900 bytes. Way over the relay's 512 byte limit if it went out as is.
+			let (_, a, _) = test_client_with(relay, |state, mpv| {
+				state.share_title = true;
+				let _ = mpv.properties.insert("media-title".into(), json!("字".repeat(300)));
+			});
+			let (_, b, _) = test_client(relay);
+			wait_until("the party", || {
+				a.state.lock().unwrap().party_count == 2 && b.state.lock().unwrap().party_count == 2
+			})
+			.await;
+			assert_eq!(a.state.lock().unwrap().connection_status, "connected");
+		}
 
-					if party_count > 1 {
-						let _ = sender.send(WsMessage::AbsoluteSeek(time));
-					}
-				}
+		#[tokio::test]
+		async fn idle_leaves_the_room() {
+			let relay = crate::server::spawn_test_relay().await;
+
+			let (mpv_a, mut a, mut observers_a) = test_client(relay);
+			observers_a
+				.observe(&mut mpv_a.clone(), "filename", Events::on_filename)
+				.unwrap();
+			let (_, b, _) = test_client(relay);
+			wait_until("the party", || {
+				a.state.lock().unwrap().party_count == 2 && b.state.lock().unwrap().party_count == 2
+			})
+			.await;
+			let filename = |data| json!({"event": "property-change", "id": 2, "name": "filename", "data": data});
+
+			// Same file again (a playlist looping).
+			run_events(&mpv_a, &mut a, &mut observers_a, &[filename(json!("movie.mkv"))]);
+			assert_eq!(a.state.lock().unwrap().party_count, 2);
+
+			// The file's closed & mpv's idling.
+			run_events(&mpv_a, &mut a, &mut observers_a, &[filename(json!(null))]);
+			{
+				let state = a.state.lock().unwrap();
+				assert_eq!(state.party_count, 0);
+				assert!(state.room_hash.is_empty());
+				assert!(state.roster.is_empty());
+			}
+			wait_until("a to leave", || b.state.lock().unwrap().party_count == 1).await;
+
+			run_events(&mpv_a, &mut a, &mut observers_a, &[filename(json!("movie.mkv"))]);
+			wait_until("a to come back", || {
+				a.state.lock().unwrap().party_count == 2 && b.state.lock().unwrap().party_count == 2
+			})
+			.await;
+		}
+
+		#[tokio::test]
+		async fn clearing_the_room_code_without_a_file_leaves() {
+			let relay = crate::server::spawn_test_relay().await;
+
+			// Neither fake mpv has a "filename" so it's like nothing's loaded.
+			let (mpv_a, mut a, mut observers_a) = test_client(relay);
+			observers_a
+				.observe(&mut mpv_a.clone(), "filename", Events::on_filename)
+				.unwrap();
+			let (_, b, _) = test_client(relay);
+			wait_until("the party", || {
+				a.state.lock().unwrap().party_count == 2 && b.state.lock().unwrap().party_count == 2
+			})
+			.await;
+
+			a.on_input_reader(String::new()).unwrap();
+			{
+				let state = a.state.lock().unwrap();
+				assert_eq!(state.party_count, 0);
+				assert!(state.room_hash.is_empty());
 			}
-			_ => (),
+			wait_until("a to leave", || b.state.lock().unwrap().party_count == 1).await;
+
+			let filename = json!({"event": "property-change", "id": 2, "name": "filename", "data": "movie.mkv"});
+			run_events(&mpv_a, &mut a, &mut observers_a, &[filename]);
+			wait_until("a to join the file's room", || {
+				a.state.lock().unwrap().party_count == 2 && b.state.lock().unwrap().party_count == 2
+			})
+			.await;
 		}
 	}
-
-	Ok(())
 }
@@ -0,0 +1,48 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+// Copyright 2023-2025 rtldg <rtldg@protonmail.com>
+
+//! The pieces of `simulcast-mpv` for embedding somewhere else instead of running the executable.
+//!
+//! - `client` feature: [`SimulcastClient`] syncs an mpv instance through a relay.
+//! - `server` feature: [`RelayServer`] is the relay.
+//!
+//! ```no_run
+//! # #[cfg(feature = "server")]
+//! # async fn relay() -> anyhow::Result<()> {
+//! let relay = simulcast_mpv::RelayServer::bind(
+//!     "127.0.0.1:30777".parse()?,
+//!     "https://github.com/rtldg/simulcast-mpv".parse()?,
+//!     simulcast_mpv::message::PingSettings {
+//!         interval: std::time::Duration::from_secs(1),
+//!         timeout: std::time::Duration::from_secs(10),
+//!     },
+//! )
+//! .await?;
+//! relay.serve(std::future::pending()).await
+//! # }
+//! # #[cfg(feature = "client")]
+//! # fn client() -> anyhow::Result<()> {
+//! let mut client = simulcast_mpv::SimulcastClient::new(Some("/tmp/mpvsocket".to_string()));
+//! client.nickname = Some("me".to_string());
+//! client.run()
+//! # }
+//! ```
+
+#![forbid(unsafe_code)]
+
+#[cfg(feature = "client")]
+pub mod client;
+pub mod message;
+#[cfg(feature = "client")]
+pub mod mpvipc;
+#[cfg(feature = "client")]
+pub mod osd;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(feature = "client")]
+pub mod update;
+
+#[cfg(feature = "client")]
+pub use client::SimulcastClient;
+#[cfg(feature = "server")]
+pub use server::RelayServer;
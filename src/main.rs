@@ -3,20 +3,18 @@
 
 #![forbid(unsafe_code)]
 
+use anyhow::Context;
+use clap::{Args, Parser, Subcommand};
+use log::info;
+use simulcast_mpv::message;
 #[cfg(feature = "client")]
-mod client;
-mod message;
-#[cfg(feature = "client")]
-mod mpvipc;
+use simulcast_mpv::mpvipc::MpvIpc;
 #[cfg(feature = "server")]
-mod server;
-
+use simulcast_mpv::server;
 #[cfg(feature = "client")]
-use anyhow::Context;
-use clap::{Parser, Subcommand};
-use log::info;
+use simulcast_mpv::{client, mpvipc, osd, update};
 #[cfg(feature = "client")]
-use std::io::Read;
+use std::io::{Read, Write};
 
 #[derive(Debug, Parser)]
 #[command(author, version, about, long_about = None, flatten_help = true, disable_help_subcommand = true, infer_subcommands = true)]
@@ -30,46 +28,381 @@ struct Cli {
 	/// -vvvv show trace
 	#[command(flatten)]
 	verbose: clap_verbosity_flag::Verbosity<clap_verbosity_flag::InfoLevel>,
-	/// Toggles whether to block and wait for you to press ENTER during install.
+	/// Toggles whether to block and wait for you to press ENTER during install/uninstall.
 	#[cfg(feature = "client")]
-	#[arg(long, default_value_t = false)]
+	#[arg(long, global = true, default_value_t = false)]
 	noninteractive: bool,
+	/// Don't overwrite an existing simulcast-mpv.lua (or executable) that differs from this version's during install.
+	#[cfg(feature = "client")]
+	#[arg(long, default_value_t = false)]
+	no_overwrite: bool,
+	/// JSON file with default settings, like `{"relay_url": "wss://...", "nickname": "me"}`. Flags & env vars win.
+	/// Defaults to simulcast-mpv.json next to the executable (if it exists).
+	#[arg(long, global = true, env = "SIMULCAST_CONFIG")]
+	config: Option<std::path::PathBuf>,
+}
+
+/// `--config` has to be read before `Cli::parse()` so its settings can act as defaults.
+fn config_path() -> Option<std::path::PathBuf> {
+	let mut args = std::env::args_os();
+	while let Some(arg) = args.next() {
+		if arg == "--config" {
+			return args.next().map(Into::into);
+		}
+		if let Some(path) = arg.to_str().and_then(|a| a.strip_prefix("--config=")) {
+			return Some(path.into());
+		}
+	}
+	if let Some(path) = std::env::var_os("SIMULCAST_CONFIG") {
+		return Some(path.into());
+	}
+	let mut path = std::env::current_exe().ok()?;
+	path.set_file_name("simulcast-mpv.json");
+	path.exists().then_some(path)
+}
+
+/// Turns each `"some_flag": value` into `SIMULCAST_SOME_FLAG=value` unless that's already set.
+/// That gives CLI > env (& .env files) > config file > built-in default.
+fn load_config_file(path: &std::path::Path) -> anyhow::Result<()> {
+	let text = std::fs::read_to_string(path).with_context(|| format!("couldn't read config '{}'", path.display()))?;
+	let config: serde_json::Map<String, serde_json::Value> =
+		serde_json::from_str(&text).with_context(|| format!("config '{}' should be a JSON object", path.display()))?;
+	for (key, value) in config {
+		let var = format!("SIMULCAST_{}", key.to_uppercase().replace('-', "_"));
+		if std::env::var_os(&var).is_some() {
+			continue;
+		}
+		let value = match value {
+			serde_json::Value::String(s) => s,
+			serde_json::Value::Number(n) => n.to_string(),
+			serde_json::Value::Bool(b) => b.to_string(),
+			_ => anyhow::bail!(
+				"'{key}' in config '{}' should be a string, number, or bool",
+				path.display()
+			),
+		};
+		std::env::set_var(var, value);
+	}
+	Ok(())
+}
+
+#[derive(Debug, Args)]
+struct PingArgs {
+	/// Seconds between the relay's pings (or between the client's checks for them).
+	#[arg(long, env = "SIMULCAST_PING_INTERVAL", default_value_t = 1.0)]
+	ping_interval: f64,
+	/// Seconds without a ping/pong before the connection is considered dead.
+	/// Raise this on flaky/high-latency connections. The client's timeout should be larger than the relay's ping interval.
+	#[arg(long, env = "SIMULCAST_PING_TIMEOUT", default_value_t = 10.0)]
+	ping_timeout: f64,
+}
+
+impl PingArgs {
+	fn settings(&self) -> anyhow::Result<message::PingSettings> {
+		let interval = std::time::Duration::try_from_secs_f64(self.ping_interval)?;
+		let timeout = std::time::Duration::try_from_secs_f64(self.ping_timeout)?;
+		anyhow::ensure!(!interval.is_zero(), "--ping-interval must be greater than 0");
+		anyhow::ensure!(
+			timeout > interval,
+			"--ping-timeout ({}s) must be greater than --ping-interval ({}s)",
+			self.ping_timeout,
+			self.ping_interval
+		);
+		Ok(message::PingSettings { interval, timeout })
+	}
+}
+
+#[cfg(feature = "client")]
+#[derive(Debug, Args)]
+struct DriftArgs {
+	/// Seek back in line when we're this many seconds away from the room's leader. Off by default.
+	/// The leader needs this on too since that's what makes it share its position.
+	#[arg(long, env = "SIMULCAST_DRIFT_THRESHOLD")]
+	drift_threshold: Option<f64>,
+	/// Seconds between position checks for --drift-threshold.
+	#[arg(long, env = "SIMULCAST_DRIFT_INTERVAL", default_value_t = 5.0)]
+	drift_interval: f64,
+}
+
+#[cfg(feature = "client")]
+impl DriftArgs {
+	fn settings(&self) -> anyhow::Result<Option<client::DriftSettings>> {
+		let Some(threshold) = self.drift_threshold else {
+			return Ok(None);
+		};
+		anyhow::ensure!(threshold > 0.0, "--drift-threshold must be greater than 0");
+		let interval = std::time::Duration::try_from_secs_f64(self.drift_interval)?;
+		anyhow::ensure!(!interval.is_zero(), "--drift-interval must be greater than 0");
+		Ok(Some(client::DriftSettings { threshold, interval }))
+	}
+}
+
+#[cfg(feature = "server")]
+#[derive(Debug, Args)]
+struct BindArgs {
+	/// Address to bind to. Can be repeated or comma-separated (`0.0.0.0,::`) to listen on IPv4 & IPv6 at once
+	#[arg(
+		long,
+		env = "SIMULCAST_BIND_ADDRESS",
+		default_value = "127.0.0.1",
+		value_delimiter = ','
+	)]
+	bind_address: Vec<std::net::IpAddr>,
+	/// Port to bind to
+	#[arg(long, env = "SIMULCAST_BIND_PORT", default_value_t = 30777)]
+	bind_port: u16,
+	/// Listen on a unix socket instead of --bind-address/--bind-port. Handy behind a reverse proxy on the same host.
+	#[arg(long, env = "SIMULCAST_BIND_UNIX")]
+	bind_unix: Option<std::path::PathBuf>,
+}
+
+#[cfg(feature = "server")]
+#[derive(Debug, Args)]
+struct RelayArgs {
+	#[command(flatten)]
+	bind: BindArgs,
+	/// Repository URL (for AGPL-3.0 reasons).
+	#[arg(long, env = "SIMULCAST_REPO_URL")]
+	repo_url: http::Uri,
+	/// Log output format.
+	#[arg(long, env = "SIMULCAST_LOG_FORMAT", value_enum, default_value_t = server::LogFormat::Text)]
+	log_format: server::LogFormat,
+	#[command(flatten)]
+	ping: PingArgs,
+	/// Largest websocket message or long-polling body (in bytes) accepted from clients.
+	#[arg(long, env = "SIMULCAST_MAX_MESSAGE_SIZE", default_value_t = server::DEFAULT_MAX_MESSAGE_SIZE)]
+	max_message_size: usize,
+	/// Largest websocket frame (in bytes) accepted from clients. Can't be larger than --max-message-size.
+	#[arg(long, env = "SIMULCAST_MAX_FRAME_SIZE", default_value_t = server::DEFAULT_MAX_FRAME_SIZE)]
+	max_frame_size: usize,
+	/// Disconnect clients that send more than this many messages in 10 seconds. 0 turns it off.
+	#[arg(long, env = "SIMULCAST_RATE_LIMIT", default_value_t = server::DEFAULT_RATE_LIMIT)]
+	rate_limit: u32,
+	/// Refuse new connections past this many connected clients. 0 turns it off.
+	#[arg(long, env = "SIMULCAST_MAX_CONNECTIONS", default_value_t = server::DEFAULT_MAX_CONNECTIONS)]
+	max_connections: usize,
+	/// Serve this file (a list of relay urls like docs/servers.txt) at /servers.txt for clients' --discovery-url.
+	#[arg(long, env = "SIMULCAST_DISCOVERY_FILE")]
+	discovery_file: Option<std::path::PathBuf>,
+	/// Localhost address (like 127.0.0.1:30999) for an admin socket. Off by default.
+	/// Takes one JSON command per line & replies with one JSON line:
+	/// `{"cmd": "rooms"}` lists rooms & their members (with ids),
+	/// `{"cmd": "kick", "id": 3}` disconnects member 3,
+	/// `{"cmd": "clear", "room": "brave-otter-42"}` kicks everyone in a room (by key or short name).
+	#[arg(long, env = "SIMULCAST_ADMIN_SOCKET")]
+	admin_socket: Option<std::net::SocketAddr>,
+	/// Give rooms without a room password a short name (like brave-otter-42) that friends can join with `@brave-otter-42`.
+	/// Off by default: names are easy to guess so anyone could end up in a stranger's room.
+	#[arg(long, env = "SIMULCAST_SHORT_ROOM_NAMES")]
+	short_room_names: bool,
+	/// Save rooms' short names to this JSON file so they keep working after a restart. Needs --short-room-names.
+	/// Off by default: names only last as long as their room.
+	#[arg(long, env = "SIMULCAST_ROOM_NAMES_FILE", requires = "short_room_names")]
+	room_names_file: Option<std::path::PathBuf>,
+	/// Days until a saved short name that nobody's used is forgotten.
+	#[arg(long, env = "SIMULCAST_ROOM_NAMES_EXPIRY_DAYS", default_value_t = 30)]
+	room_names_expiry_days: u64,
+	/// Append one JSON line per message members send to this file: the time, the message kind, the room hash, & the member id.
+	/// Never what's in the message. Off by default.
+	#[arg(long, env = "SIMULCAST_AUDIT_LOG")]
+	audit_log: Option<std::path::PathBuf>,
+	/// Size in MiB at which --audit-log is moved to `<path>.1` & started over.
+	#[arg(long, env = "SIMULCAST_AUDIT_LOG_MAX_MB", default_value_t = server::DEFAULT_AUDIT_LOG_MAX_MB)]
+	audit_log_max_mb: u64,
+	/// Serve wss:// with this PEM certificate chain instead of needing a reverse proxy. Needs --tls-key.
+	/// The HTTP endpoints (like /now-playing) aren't served over TLS. Send SIGHUP to reload a renewed certificate.
+	#[arg(long, env = "SIMULCAST_TLS_CERT", requires = "tls_key")]
+	tls_cert: Option<std::path::PathBuf>,
+	/// PEM private key for --tls-cert.
+	#[arg(long, env = "SIMULCAST_TLS_KEY", requires = "tls_cert")]
+	tls_key: Option<std::path::PathBuf>,
+}
+
+#[cfg(feature = "client")]
+#[derive(Debug, Args)]
+struct ClientArgs {
+	/// Relay-server used by both users for synchronization.
+	/// If this is empty then it'll read the server from https://github.com/rtldg/simulcast-mpv/blob/master/docs/servers.txt
+	#[arg(long, env = "SIMULCAST_RELAY_URL")]
+	relay_url: Option<http::Uri>,
+	/// Where to read the relay url from when --relay-url is empty. A list of relay urls (one per line) like docs/servers.txt.
+	#[arg(long, env = "SIMULCAST_DISCOVERY_URL", default_value = client::DEFAULT_DISCOVERY_URL)]
+	discovery_url: String,
+	/// The room/code for both users to use for synchronizing.
+	/// Rooms are based on the media-title/file-name so you could edit this for a little bit of "salt"
+	#[arg(long, env = "SIMULCAST_RELAY_ROOM", default_value = "abcd1234", value_parser = client::parse_relay_room)]
+	relay_room: String,
+	/// Optional password for the room. Only users with the same password will be put in the same room.
+	#[arg(long, env = "SIMULCAST_ROOM_PASSWORD")]
+	room_password: Option<String>,
+	/// A code to share with the others instead of a room code, relay room & password.
+	/// Everyone with the same invite ends up in the same private room no matter what file they're playing.
+	#[arg(long, env = "SIMULCAST_INVITE")]
+	invite: Option<String>,
+	/// Name shown to the other members of the room.
+	#[arg(long, env = "SIMULCAST_NICKNAME")]
+	nickname: Option<String>,
+	/// Extra PEM certificate(s) to trust for wss:// relays. Useful for private relays with self-signed certs.
+	#[arg(long, env = "SIMULCAST_CA_CERT")]
+	ca_cert: Option<std::path::PathBuf>,
+	/// mpv's socket path (input-ipc-server) that we connect to. Looks in the usual places if omitted.
+	#[arg(long, env = "SIMULCAST_CLIENT_SOCK")]
+	client_sock: Option<String>,
+	#[command(flatten)]
+	ping: PingArgs,
+	#[command(flatten)]
+	drift: DriftArgs,
+	/// What seeks are synced by. `chapters` helps when everyone's rip is a slightly different length.
+	#[arg(long, env = "SIMULCAST_SYNC_MODE", value_enum, default_value_t = client::SyncMode::Time)]
+	sync_mode: client::SyncMode,
+	/// How tightly to keep everyone together. `tight` catches smaller seeks, sends changes sooner & turns on drift correction
+	/// (unless --drift-threshold is set). `relaxed` ignores small seeks & waits longer before sending changes.
+	#[arg(long, env = "SIMULCAST_SYNC_PROFILE", value_enum, default_value_t = client::SyncProfile::Normal)]
+	sync_profile: client::SyncProfile,
+	/// Ask the relay to confirm pauses/seeks/resumes and show "sync failed" on mpv's OSD when it doesn't.
+	#[arg(long, env = "SIMULCAST_ACKS")]
+	acks: bool,
+	/// Unpause when everyone else leaves the room instead of staying paused.
+	#[arg(long, env = "SIMULCAST_SOLO_RESUME")]
+	solo_resume: bool,
+	/// Follow the room without ever pausing/seeking it for everyone. Pausing & seeking only happen on your end.
+	#[arg(long, env = "SIMULCAST_OBSERVER")]
+	observer: bool,
+	/// Seconds of "new viewer joining, pausing in 3..." before pausing for someone joining. 0 pauses right away.
+	#[arg(long, env = "SIMULCAST_JOIN_COUNTDOWN", default_value_t = 3)]
+	join_countdown: u32,
+	/// Whether someone joining pauses you. `never` & `only-if-playing` are for parties that are already in sync.
+	#[arg(long, env = "SIMULCAST_JOIN_PAUSE", value_enum, default_value_t = client::JoinPause::Always)]
+	join_pause: client::JoinPause,
+	/// How filenames & room codes are cleaned up before they're hashed into a room.
+	/// Everyone in a party needs the same rules. `classic` matches older clients.
+	#[arg(long, env = "SIMULCAST_ROOM_CODE_RULES", value_enum, default_value_t = client::RoomCodeRules::Classic)]
+	room_code_rules: client::RoomCodeRules,
+	/// JSON file with OSD text to use instead of the English defaults. Like `{"party_count": "Teilnehmer: {count}"}`.
+	#[arg(long, env = "SIMULCAST_OSD_STRINGS")]
+	osd_strings: Option<std::path::PathBuf>,
+	/// Seconds to wait for mpv to answer a command before giving up on it. 0 waits forever.
+	#[arg(long, env = "SIMULCAST_MPV_TIMEOUT", default_value_t = mpvipc::DEFAULT_TIMEOUT.as_secs_f64())]
+	mpv_timeout: f64,
+	/// Tell the relay the media title so it can show what's being watched. Off by default: only a hash of the file name is sent.
+	#[arg(long, env = "SIMULCAST_SHARE_TITLE")]
+	share_title: bool,
+	/// Don't write `user-data/simulcast/heartbeat` every 100ms. The Lua script then assumes the client is alive.
+	#[arg(long, env = "SIMULCAST_NO_HEARTBEAT")]
+	no_heartbeat: bool,
+	/// TESTING TOOL. Don't use a real relay. Sync with a pretend partner on a relay inside the client instead,
+	/// so your own pauses/seeks/resumes come back through the relay after this many milliseconds of simulated latency.
+	#[arg(long, env = "SIMULCAST_SOLO_TEST", num_args = 0..=1, default_missing_value = "150")]
+	solo_test: Option<u64>,
+	/// Log level (like `debug` or `off`). Overrides -q/-v so it can be set from the .env/config file when mpv starts us.
+	#[arg(long, env = "SIMULCAST_LOG_LEVEL")]
+	log_level: Option<log::LevelFilter>,
+	/// Where the log file goes. Defaults to the temp directory.
+	#[arg(long, env = "SIMULCAST_LOG_DIR")]
+	log_dir: Option<std::path::PathBuf>,
+	/// Only log to stdout.
+	#[arg(long, env = "SIMULCAST_NO_LOG_FILE", conflicts_with = "log_dir")]
+	no_log_file: bool,
 }
 
 #[derive(Debug, Subcommand)]
 enum Commands {
 	#[cfg(feature = "client")]
-	Client {
-		/// Relay-server used by both users for synchronization.
-		/// If this is empty then it'll read the server from https://github.com/rtldg/simulcast-mpv/blob/master/docs/servers.txt
-		#[arg(long, env = "SIMULCAST_RELAY_URL")]
-		relay_url: Option<http::Uri>,
-		/// The room/code for both users to use for synchronizing.
-		/// Rooms are based on the media-title/file-name so you could edit this for a little bit of "salt"
-		#[arg(long, env = "SIMULCAST_RELAY_ROOM", default_value = "abcd1234")]
-		relay_room: String,
-		/// mpv's socket path (input-ipc-server) that we connect to.
-		#[arg(long, env = "SIMULCAST_CLIENT_SOCK")]
-		client_sock: String,
-	},
+	Client(Box<ClientArgs>),
 	#[cfg(feature = "server")]
-	Relay {
-		/// Address to bind to
-		#[arg(long, env = "SIMULCAST_BIND_ADDRESS", default_value = "127.0.0.1")]
-		bind_address: std::net::IpAddr,
-		/// Port to bind to
-		#[arg(long, env = "SIMULCAST_BIND_PORT", default_value_t = 30777)]
-		bind_port: u16,
+	Relay(Box<RelayArgs>),
+	/// Prints a systemd unit that runs the relay with these settings. Save it as /etc/systemd/system/simulcast-mpv.service
+	#[cfg(feature = "server")]
+	GenerateService {
+		#[command(flatten)]
+		bind: BindArgs,
 		/// Repository URL (for AGPL-3.0 reasons).
 		#[arg(long, env = "SIMULCAST_REPO_URL")]
 		repo_url: http::Uri,
+		/// Make a user unit (for `systemctl --user`, saved in ~/.config/systemd/user/) instead.
+		#[arg(long)]
+		user: bool,
 	},
 	#[cfg(feature = "client")]
 	InputReader {
-		/// mpv's socket path (input-ipc-server) that we connect to.
+		/// mpv's socket path (input-ipc-server) that we connect to. Looks in the usual places if omitted.
+		#[arg(long, env = "SIMULCAST_CLIENT_SOCK")]
+		client_sock: Option<String>,
+	},
+	/// Prints what a running client is doing (from mpv's user-data/simulcast/* properties).
+	#[cfg(feature = "client")]
+	Status {
+		/// mpv's socket path (input-ipc-server) that we connect to. Looks in the usual places if omitted.
 		#[arg(long, env = "SIMULCAST_CLIENT_SOCK")]
-		client_sock: String,
+		client_sock: Option<String>,
+		/// Print newline-delimited JSON instead.
+		#[arg(long)]
+		json: bool,
+		/// Keep streaming mpv's events & a property snapshot every few seconds. Implies --json.
+		#[arg(long)]
+		follow: bool,
 	},
+	/// Checks that a relay is reachable & new enough. Prints its version, repo, & round trip time.
+	#[cfg(feature = "client")]
+	CheckRelay {
+		/// The relay to check. Falls back to --discovery-url like the client does.
+		#[arg(long, env = "SIMULCAST_RELAY_URL")]
+		relay_url: Option<http::Uri>,
+		/// Where to read the relay url from when --relay-url is empty.
+		#[arg(long, env = "SIMULCAST_DISCOVERY_URL", default_value = client::DEFAULT_DISCOVERY_URL)]
+		discovery_url: String,
+		/// PEM file with extra CA certificates to trust.
+		#[arg(long, env = "SIMULCAST_CA_CERT")]
+		ca_cert: Option<std::path::PathBuf>,
+	},
+	/// Prints the room hash a file (or room code) & relay room end up in. For "why aren't we in the same room?".
+	#[cfg(feature = "client")]
+	RoomHash {
+		/// The file being played. Only the name matters (like mpv's `filename` property).
+		#[arg(long, required_unless_present = "room", conflicts_with = "room")]
+		file: Option<std::path::PathBuf>,
+		/// A room code typed in with the input reader.
+		#[arg(long)]
+		room: Option<String>,
+		/// Arbitrary string that is hashed with the room code.
+		#[arg(long, env = "SIMULCAST_RELAY_ROOM", default_value = "abcd1234", value_parser = client::parse_relay_room)]
+		relay_room: String,
+		/// See `client --room-code-rules`.
+		#[arg(long, env = "SIMULCAST_ROOM_CODE_RULES", value_enum, default_value_t = client::RoomCodeRules::Classic)]
+		room_code_rules: client::RoomCodeRules,
+	},
+	/// Writes versions, the relay, the client's state, & the end of its log to one file for bug reports.
+	/// Custom room codes, room passwords, & invites are blanked out.
+	#[cfg(feature = "client")]
+	Diagnostics {
+		/// mpv's socket path (input-ipc-server). Looks in the usual places if omitted. mpv doesn't have to be running.
+		#[arg(long, env = "SIMULCAST_CLIENT_SOCK")]
+		client_sock: Option<String>,
+		/// The relay the client uses. Falls back to --discovery-url like the client does.
+		#[arg(long, env = "SIMULCAST_RELAY_URL")]
+		relay_url: Option<http::Uri>,
+		/// Where to read the relay url from when --relay-url is empty.
+		#[arg(long, env = "SIMULCAST_DISCOVERY_URL", default_value = client::DEFAULT_DISCOVERY_URL)]
+		discovery_url: String,
+		/// The client's room password, so it can be blanked out.
+		#[arg(long, env = "SIMULCAST_ROOM_PASSWORD")]
+		room_password: Option<String>,
+		/// The client's invite, so it can be blanked out.
+		#[arg(long, env = "SIMULCAST_INVITE")]
+		invite: Option<String>,
+		/// Where the client's logs are. Defaults to the temp directory.
+		#[arg(long, env = "SIMULCAST_LOG_DIR")]
+		log_dir: Option<std::path::PathBuf>,
+		/// The file to write.
+		#[arg(long, default_value = "simulcast-mpv-diagnostics.txt")]
+		output: std::path::PathBuf,
+	},
+	/// Removes simulcast-mpv.lua & the simulcast-mpv executable from mpv's scripts directory.
+	#[cfg(feature = "client")]
+	Uninstall,
+	/// Replaces this executable with the latest release from GitHub (if there's a newer one).
+	#[cfg(feature = "client")]
+	Update,
 }
 
 fn main() -> anyhow::Result<()> {
@@ -80,42 +413,212 @@ fn main() -> anyhow::Result<()> {
 	}
 	// Load "$PWD/simulcast-mpv.env" (which probably doesn't exist).
 	let _ = dotenvy::from_filename_override("simulcast-mpv.env");
+	if let Some(path) = config_path() {
+		load_config_file(&path)?;
+	}
 
 	let args = Cli::parse();
 
 	if let Some(command) = args.command {
 		let res = match command {
 			#[cfg(feature = "server")]
-			Commands::Relay {
-				bind_address,
-				bind_port,
-				repo_url,
-			} => server::server(args.verbose.log_level_filter(), bind_address, bind_port, &repo_url),
+			Commands::Relay(relay_args) => {
+				let RelayArgs {
+					bind,
+					repo_url,
+					log_format,
+					ping,
+					max_message_size,
+					max_frame_size,
+					rate_limit,
+					max_connections,
+					discovery_file,
+					admin_socket,
+					short_room_names,
+					room_names_file,
+					room_names_expiry_days,
+					audit_log,
+					audit_log_max_mb,
+					tls_cert,
+					tls_key,
+				} = *relay_args;
+				ping.settings().and_then(|ping| {
+					server::server(args.verbose.log_level_filter(), log_format, async move {
+						let mut relay = server::RelayServer::bind_flags(
+							&bind.bind_address,
+							bind.bind_port,
+							bind.bind_unix,
+							repo_url,
+							ping,
+						)
+						.await?
+						.rate_limit(rate_limit)
+						.max_connections(max_connections)
+						.short_room_names(short_room_names)
+						.message_size(max_message_size, max_frame_size)?;
+						if let Some((cert, key)) = tls_cert.zip(tls_key) {
+							relay = relay.tls(cert, key)?;
+						}
+						if let Some(path) = discovery_file {
+							relay = relay.discovery_file(path);
+						}
+						if let Some(addr) = admin_socket {
+							relay = relay.admin_socket(addr)?;
+						}
+						if let Some(path) = room_names_file {
+							let expiry = std::time::Duration::from_secs(room_names_expiry_days * 24 * 60 * 60);
+							relay = relay.room_names_file(path, expiry)?;
+						}
+						if let Some(path) = audit_log {
+							relay = relay.audit_log(path, audit_log_max_mb)?;
+						}
+						Ok(relay)
+					})
+				})
+			}
+			#[cfg(feature = "server")]
+			Commands::GenerateService { bind, repo_url, user } => std::env::current_exe()
+				.context("couldn't find this executable's path")
+				.map(|exe| {
+					print!(
+						"{}",
+						server::systemd_unit(
+							&exe,
+							&bind.bind_address,
+							bind.bind_port,
+							bind.bind_unix.as_deref(),
+							&repo_url,
+							user
+						)
+					)
+				}),
+			#[cfg(feature = "client")]
+			Commands::Client(client_args) => {
+				let ClientArgs {
+					relay_url,
+					discovery_url,
+					relay_room,
+					room_password,
+					invite,
+					nickname,
+					ca_cert,
+					client_sock,
+					ping,
+					drift,
+					sync_mode,
+					sync_profile,
+					acks,
+					solo_resume,
+					observer,
+					join_countdown,
+					join_pause,
+					room_code_rules,
+					osd_strings,
+					mpv_timeout,
+					share_title,
+					no_heartbeat,
+					solo_test,
+					log_level,
+					log_dir,
+					no_log_file,
+				} = *client_args;
+				ping.settings().and_then(|ping| {
+					client::client(
+						log_level.unwrap_or(args.verbose.log_level_filter()),
+						(!no_log_file).then(|| log_dir.unwrap_or_else(std::env::temp_dir)),
+						client::SimulcastClient {
+							relay_url,
+							discovery_url,
+							relay_room,
+							room_password,
+							invite,
+							nickname,
+							ca_cert,
+							client_sock,
+							ping,
+							drift: drift.settings()?,
+							sync_mode,
+							sync_profile,
+							acks,
+							solo_resume,
+							observer,
+							join_countdown,
+							join_pause,
+							room_code_rules,
+							osd: osd_strings
+								.as_deref()
+								.map(osd::OsdStrings::load)
+								.transpose()?
+								.unwrap_or_default(),
+							mpv_timeout: Some(std::time::Duration::try_from_secs_f64(mpv_timeout)?)
+								.filter(|timeout| !timeout.is_zero()),
+							share_title,
+							heartbeat: !no_heartbeat,
+							solo_test: solo_test.map(std::time::Duration::from_millis),
+						},
+					)
+				})
+			}
+			#[cfg(feature = "client")]
+			Commands::InputReader { client_sock } => find_client_sock(client_sock).and_then(|s| input_reader(&s)),
+			#[cfg(feature = "client")]
+			Commands::Status {
+				client_sock,
+				json,
+				follow,
+			} => find_client_sock(client_sock).and_then(|s| status(&s, json, follow)),
 			#[cfg(feature = "client")]
-			Commands::Client {
+			Commands::CheckRelay {
 				relay_url,
+				discovery_url,
+				ca_cert,
+			} => client::check_relay(relay_url, &discovery_url, ca_cert.as_deref()),
+			#[cfg(feature = "client")]
+			Commands::RoomHash {
+				file,
+				room,
 				relay_room,
+				room_code_rules,
+			} => {
+				let code = match (file, room) {
+					(Some(file), _) => file
+						.file_name()
+						.map(|name| name.to_string_lossy().into_owned())
+						.ok_or_else(|| anyhow::anyhow!("--file doesn't have a file name"))?,
+					(None, room) => room.unwrap_or_default(),
+				};
+				client::print_room_hash(&code, &relay_room, room_code_rules);
+				Ok(())
+			}
+			#[cfg(feature = "client")]
+			Commands::Diagnostics {
 				client_sock,
-			} => client::client(args.verbose.log_level_filter(), relay_url, relay_room, client_sock),
+				relay_url,
+				discovery_url,
+				room_password,
+				invite,
+				log_dir,
+				output,
+			} => client::diagnostics(
+				find_client_sock(client_sock).ok().as_deref(),
+				relay_url,
+				&discovery_url,
+				room_password.as_deref(),
+				invite.as_deref(),
+				&log_dir.unwrap_or_else(std::env::temp_dir),
+				&output,
+			),
 			#[cfg(feature = "client")]
-			Commands::InputReader { client_sock } => input_reader(&client_sock),
+			Commands::Uninstall => wait_for_enter(uninstall(), args.noninteractive),
+			#[cfg(feature = "client")]
+			Commands::Update => wait_for_enter(update::update(), args.noninteractive),
 		};
 		info!("res = {res:?}");
 		res
 	} else {
 		#[cfg(feature = "client")]
 		{
-			let res = install();
-			if args.noninteractive {
-				res
-			} else {
-				if let Err(e) = res {
-					println!("\n{e:?}");
-				}
-				println!("\nPress ENTER to exit...");
-				let _ = std::io::stdin().read(&mut [0u8]).unwrap();
-				Ok(()) // Slurp it so it doesn't double print...
-			}
+			wait_for_enter(install(args.no_overwrite, args.noninteractive), args.noninteractive)
 		}
 		#[cfg(not(feature = "client"))]
 		{
@@ -124,6 +627,33 @@ fn main() -> anyhow::Result<()> {
 	}
 }
 
+/// Keeps the console window open (when double-clicked on Windows) so the output can be read.
+#[cfg(feature = "client")]
+fn wait_for_enter(res: anyhow::Result<()>, noninteractive: bool) -> anyhow::Result<()> {
+	if noninteractive {
+		res
+	} else {
+		if let Err(e) = res {
+			println!("\n{e:?}");
+		}
+		println!("\nPress ENTER to exit...");
+		let _ = std::io::stdin().read(&mut [0u8]).unwrap();
+		Ok(()) // Slurp it so it doesn't double print...
+	}
+}
+
+/// `--client-sock` or wherever mpv's socket was found.
+#[cfg(feature = "client")]
+fn find_client_sock(client_sock: Option<String>) -> anyhow::Result<String> {
+	if let Some(client_sock) = client_sock {
+		return Ok(client_sock);
+	}
+	let client_sock = mpvipc::find_socket()?;
+	// stderr so it doesn't end up in `status --json` output.
+	eprintln!("found mpv's socket at '{client_sock}'");
+	Ok(client_sock)
+}
+
 #[cfg(feature = "client")]
 fn input_reader(client_sock: &str) -> anyhow::Result<()> {
 	let mut mpv = mpvipc::Mpv::connect(client_sock)?;
@@ -136,9 +666,69 @@ fn input_reader(client_sock: &str) -> anyhow::Result<()> {
 }
 
 #[cfg(feature = "client")]
-fn install() -> anyhow::Result<()> {
-	let current_exe = std::env::current_exe()?;
+fn status(client_sock: &str, json: bool, follow: bool) -> anyhow::Result<()> {
+	let Ok(mut mpv) = mpvipc::Mpv::connect(client_sock) else {
+		println!("Couldn't connect to mpv at '{client_sock}'.");
+		println!("Is mpv running? Check `input-ipc-server` in mpv's console (` key) for the right socket path.");
+		return Err(anyhow::anyhow!("failed to connect to mpv socket '{client_sock}'"));
+	};
+	mpv.events(false);
+
+	if follow {
+		return status_follow(mpv, client_sock);
+	}
+	if json {
+		println!("{}", status_snapshot(&mut mpv));
+		return mpv.detach();
+	}
+
+	let width = client::STATUS_PROPERTIES.iter().map(|p| p.len()).max().unwrap_or(0);
+	for property in client::STATUS_PROPERTIES {
+		let value = match mpv.get_property(property) {
+			Ok(v) => v.to_string(),
+			Err(_) => "(unavailable. is the simulcast-mpv client running?)".to_string(),
+		};
+		println!("{property:width$}  {value}");
+	}
+	mpv.detach()
+}
+
+/// `{"snapshot": {"user-data/simulcast/...": value (or null), ...}}`
+#[cfg(feature = "client")]
+fn status_snapshot(mpv: &mut dyn MpvIpc) -> serde_json::Value {
+	let properties: serde_json::Map<String, serde_json::Value> = client::STATUS_PROPERTIES
+		.iter()
+		.map(|property| (property.to_string(), mpv.get_property(property).unwrap_or_default()))
+		.collect();
+	serde_json::json!({ "snapshot": properties })
+}
+
+/// Prints every mpv event as-is (they all have an "event" key) plus a snapshot every few seconds until mpv quits.
+#[cfg(feature = "client")]
+fn status_follow(mut snapshots: mpvipc::Mpv, client_sock: &str) -> anyhow::Result<()> {
+	let mut events = mpvipc::Mpv::connect(client_sock)?;
+	for (id, property) in client::STATUS_PROPERTIES.iter().enumerate() {
+		events.observe_property(id as i32 + 1, property)?;
+	}
+
+	// `writeln!` instead of `println!` so a closed pipe (like `| head`) ends things instead of panicking.
+	std::thread::spawn(move || loop {
+		if writeln!(std::io::stdout(), "{}", status_snapshot(&mut snapshots)).is_err() {
+			return;
+		}
+		std::thread::sleep(std::time::Duration::from_secs(5));
+	});
+
+	while let Ok(event) = events.listen_for_event() {
+		if writeln!(std::io::stdout(), "{event}").is_err() || event["event"] == "shutdown" {
+			break;
+		}
+	}
+	Ok(())
+}
 
+#[cfg(feature = "client")]
+fn scripts_dir(current_exe: &std::path::Path) -> std::path::PathBuf {
 	let mut mpv_dir = None;
 
 	if let Ok(var) = std::env::var("MPV_HOME") {
@@ -161,34 +751,110 @@ fn install() -> anyhow::Result<()> {
 		}
 	}
 
-	let scripts_dir = mpv_dir
+	mpv_dir
 		.unwrap_or_else(|| {
-			directories::UserDirs::new().unwrap().home_dir().join(if cfg!(windows) {
-				"AppData\\Roaming\\mpv"
+			let home = directories::UserDirs::new().unwrap().home_dir().to_owned();
+			if cfg!(windows) {
+				home.join("AppData\\Roaming\\mpv")
+			} else if cfg!(target_os = "macos") {
+				// mpv reads ~/.config/mpv first but some macOS builds (like the app bundle) use ~/Library/Application Support/mpv.
+				// Prefer whichever one already exists instead of creating a fresh config dir that mpv might ignore.
+				let candidates = [home.join(".config/mpv"), home.join("Library/Application Support/mpv")];
+				candidates.iter().find(|p| p.exists()).unwrap_or(&candidates[0]).clone()
 			} else {
-				".config/mpv"
-			})
+				home.join(".config/mpv")
+			}
 		})
-		.join("scripts");
+		.join("scripts")
+}
+
+#[cfg(feature = "client")]
+fn target_exe(scripts_dir: &std::path::Path) -> std::path::PathBuf {
+	scripts_dir.join(if cfg!(windows) {
+		"simulcast-mpv.exe"
+	} else {
+		"simulcast-mpv"
+	})
+}
+
+/// Files that are identical to what we'd write are always refreshed.
+/// Files that differ are skipped with `--no-overwrite` and otherwise prompted about (if `ask`).
+#[cfg(feature = "client")]
+fn should_overwrite(path: &std::path::Path, contents: &[u8], no_overwrite: bool, ask: bool) -> anyhow::Result<bool> {
+	let Ok(existing) = std::fs::read(path) else {
+		return Ok(true);
+	};
+	if blake3::hash(&existing) == blake3::hash(contents) {
+		return Ok(true);
+	}
+	if no_overwrite {
+		println!(
+			"- Skipping {} (differs from this version & --no-overwrite was used)",
+			path.display()
+		);
+		return Ok(false);
+	}
+	if ask {
+		print!("- {} differs from this version's. Overwrite it? [y/N] ", path.display());
+		std::io::stdout().flush()?;
+		let mut answer = String::new();
+		let _ = std::io::stdin().read_line(&mut answer)?;
+		if !answer.trim().eq_ignore_ascii_case("y") {
+			println!("- Skipping {}", path.display());
+			return Ok(false);
+		}
+	}
+	Ok(true)
+}
+
+#[cfg(feature = "client")]
+fn install(no_overwrite: bool, noninteractive: bool) -> anyhow::Result<()> {
+	let current_exe = std::env::current_exe()?;
+	let scripts_dir = scripts_dir(&current_exe);
 
 	println!("- Creating {}", scripts_dir.display());
 	std::fs::create_dir_all(&scripts_dir).with_context(|| format!("Failed to create {}", scripts_dir.display()))?;
 
-	// TODO: Option to not overwrite if the file exists...
 	let lua_file = scripts_dir.join("simulcast-mpv.lua");
-	println!("- Writing  {}", lua_file.display());
-	std::fs::write(&lua_file, include_str!("simulcast-mpv.lua"))
-		.with_context(|| format!("Failed to write {}", lua_file.display()))?;
+	let lua = include_str!("simulcast-mpv.lua");
+	// People might've tweaked their Lua so ask before overwriting it.
+	if should_overwrite(&lua_file, lua.as_bytes(), no_overwrite, !noninteractive)? {
+		println!("- Writing  {}", lua_file.display());
+		std::fs::write(&lua_file, lua).with_context(|| format!("Failed to write {}", lua_file.display()))?;
+	}
 
-	let target_exe = scripts_dir.join(if cfg!(windows) {
-		"simulcast-mpv.exe"
-	} else {
-		"simulcast-mpv"
-	});
+	let target_exe = target_exe(&scripts_dir);
 	if target_exe != current_exe {
-		println!("- Writing  {}...", target_exe.display());
-		let _ = std::fs::copy(&current_exe, &target_exe)
-			.with_context(|| format!("Failed to write {}", target_exe.display()))?;
+		// A different executable is the normal "upgrading" case so only --no-overwrite applies to it.
+		let current =
+			std::fs::read(&current_exe).with_context(|| format!("Failed to read {}", current_exe.display()))?;
+		if should_overwrite(&target_exe, &current, no_overwrite, false)? {
+			println!("- Writing  {}...", target_exe.display());
+			let _ = std::fs::copy(&current_exe, &target_exe)
+				.with_context(|| format!("Failed to write {}", target_exe.display()))?;
+		}
+	}
+
+	println!("\nDONE!");
+
+	Ok(())
+}
+
+#[cfg(feature = "client")]
+fn uninstall() -> anyhow::Result<()> {
+	let current_exe = std::env::current_exe()?;
+	let scripts_dir = scripts_dir(&current_exe);
+
+	for file in [scripts_dir.join("simulcast-mpv.lua"), target_exe(&scripts_dir)] {
+		if !file.exists() {
+			println!("- Skipping {} (already gone)", file.display());
+			continue;
+		}
+		println!("- Removing {}", file.display());
+		if let Err(e) = std::fs::remove_file(&file) {
+			// Windows won't let us delete ourselves if we're the one running from the scripts directory.
+			println!("  WARNING: failed to remove {}: {e}", file.display());
+		}
 	}
 
 	println!("\nDONE!");
@@ -8,31 +8,288 @@ pub enum WsMessage {
 	// Used to query the server's version & repository.
 	// Client<->Server.
 	Info(String),
+	// Structured version of the server's `Info` reply (plus some load numbers). Sent right after it.
+	// Only server->client.
+	ServerInfo {
+		version: String,
+		repo: String,
+		uptime_secs: u64,
+		connected: usize,
+		rooms: usize,
+	},
+	// The client's version & nickname. Sent right after `Info`.
+	// Only client->server.
+	Info2 {
+		version: String,
+		#[serde(default)]
+		nickname: String,
+	},
+	// Random token the client makes once per run. Sent right after `Info2`.
+	// Reconnecting with the same token within a few seconds takes the old room slot back.
+	// Only client->server.
+	Session(String),
+	// `--share-title`: the media title of the sender's file. Sent right after `Session`.
+	// The relay only shows it in its log & `/now-playing`.
+	// Only client->server.
+	Title(String),
+	// `--acks`: the client wants an `Ack`/`Nack` for every `Resume`, `AbsoluteSeek` & `ChapterSeek` it sends.
+	// Only client->server.
+	EnableAcks,
+	// `--observer`: the client follows the room but never moves it. Sent before `Join`.
+	// The server doesn't count it in `Party`, only makes it leader if nobody else is in the room,
+	// & drops anything from it that would move the room (see `WsMessage::drives_playback()`).
+	// Only client->server.
+	Observer,
+	// The client's new nickname. Can be sent any time after `Info2`. The server trims it like `Info2`'s.
+	// Only client->server.
+	Nick(String),
+	// Member `old` is now `new` ("nickname (version)" like `Roster`). Followed by a new `Roster`.
+	// Only server->client.
+	NickChanged {
+		old: String,
+		new: String,
+	},
+	// The room got the client's `kind` message (see `WsMessage::ack_kind()`).
+	// Only server->client.
+	Ack(String),
+	// The client's `kind` message went nowhere.
+	// Only server->client.
+	Nack {
+		kind: String,
+		reason: String,
+	},
+	// `msg` for/from the connection's session in room `room_hash` instead of its own room.
+	// Lets one connection (like a bot) be in several rooms. Each room session is its own member that
+	// gets (wrapped) `Ping`s of its own to `Pong`. It joins `room_hash` unless `msg` is a `Join`/`Join2`.
+	// Client<->Server.
+	InRoom {
+		room_hash: String,
+		msg: Box<WsMessage>,
+	},
+	// Sent right before the server disconnects a client for spamming messages.
+	// Only server->client.
+	RateLimited,
+	// The client is too old for this server. The server closes the connection after sending this.
+	// Only server->client.
+	Incompatible {
+		min_version: String,
+	},
 
 	//
 	// Only client->server.
 	Join(String),
+	// Join with a room password.
+	// `auth_tag` is a keyed hash of `room_hash` so the server never sees the password.
+	// Only members with matching `auth_tag`s are grouped together.
+	// Only client->server.
+	Join2 {
+		room_hash: String,
+		auth_tag: String,
+	},
+	// "Everyone move to this room with me". The other members follow with their own `Join`/`Join2`.
+	// Everyone derives the new custom room code from the old room's & `nonce` so the relay never sees it.
+	// Client<->Server.
+	MigrateRoom {
+		nonce: String,
+		room_hash: String,
+		auth_tag: String,
+	},
+	// The room's short name (like "brave-otter-42") that can be used instead of the hash in `Join`.
+	// Empty for password-protected rooms. Sent when joining a room.
+	// Only server->client.
+	RoomName(String),
 	// Number of current users in the party.
 	// Implies pause (if count != 1 || previous >= 1).
 	// Only server->client.
 	Party(u32),
+	// "nickname (version)" of every member in the room. Sent alongside `Party`.
+	// Only server->client.
+	Roster(Vec<String>),
+	// "nickname (version)" of a member who left (or switched rooms). Sent after the new `Party` count.
+	// Only server->client.
+	MemberLeft(String),
+	// "nickname (version)" of the room's leader (whoever's been in it longest).
+	// Sent when joining a room & to everyone when the leader leaves.
+	// Only server->client.
+	LeaderChanged(String),
 
 	//
 	Resume,
 	// Implies pause.
 	AbsoluteSeek(f64),
+	// `--sync-mode chapters`: like `AbsoluteSeek` but `offset` seconds into chapter number `chapter`.
+	// `time` is the sender's absolute position for members whose file doesn't have that chapter.
+	// Client<->Server.
+	ChapterSeek {
+		chapter: i64,
+		offset: f64,
+		time: f64,
+	},
+	// "Everyone jump to my position". Explicitly requested by the user (unlike seek-following).
+	// The server turns it into an `AbsoluteSeek` for the whole room, including the sender.
+	// Only client->server.
+	ForceResync(f64),
+	// Sent by a client that reconnected mid-session.
+	// The server forwards it to one other member of the room who replies with `State`.
+	// Client<->Server.
+	RequestState,
+	// Playback position & pause state of a room member.
+	// The server only forwards it to members that sent `RequestState`.
+	// Client<->Server.
+	State {
+		time: f64,
+		paused: bool,
+	},
+	// The sender's file ended. Forwarded to the rest of the room so they jump to the end & pause too.
+	// Client<->Server.
+	EndReached,
+	// The sender's mpv stalled to fill its cache (`true`) or caught up (`false`).
+	// Server->client it means "the room is waiting for someone" and `false` is followed by a `Resume`.
+	// Client<->Server.
+	Buffering(bool),
+	// Why the room's about to pause: "seek" (`name` paused or seeked) or "buffering" (`name` is filling its cache).
+	// Sent right before the `AbsoluteSeek`/`ChapterSeek`/`Buffering(true)` it explains. Clients only send "seek" with
+	// an empty `name` (the server fills it in) & only for seeks the user did themselves.
+	// Client<->Server.
+	PauseReason {
+		reason: String,
+		#[serde(default)]
+		name: String,
+	},
+	// Playback position every `--drift-interval` from clients with drift correction on.
+	// The server only forwards the leader's, adjusted for the leader's & recipient's ping.
+	// Client<->Server.
+	Position(f64),
 	//
 	Ping(String),
 	//
 	Pong(String),
 }
 
+/// How often the relay pings & how long either side waits for a ping/pong before giving up.
+#[derive(Clone, Copy, Debug)]
+pub struct PingSettings {
+	pub interval: std::time::Duration,
+	pub timeout: std::time::Duration,
+}
+
+/// "v2.1.0" -> [2, 1, 0]
+pub fn parse_version(version: &str) -> Option<Vec<u64>> {
+	version
+		.trim()
+		.trim_start_matches('v')
+		.split('.')
+		.map(|part| part.parse().ok())
+		.collect()
+}
+
+/// `WsMessage::Title`'s text. Both ends trim it the same way.
+/// 100 characters is at most 400 bytes so it always fits under the relay's default `--max-message-size` of 512.
+pub fn clean_title(title: &str) -> String {
+	title.trim().chars().filter(|c| !c.is_control()).take(100).collect()
+}
+
+/// Why `WsMessage::parse()` failed.
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+	/// Looks like a message but the type is one we don't know. Probably from a newer version, so it's safe to ignore.
+	UnknownVariant(String),
+	/// Not JSON or not shaped like any message.
+	Invalid(String),
+}
+
 impl WsMessage {
+	/// Tells messages from newer versions apart from garbage.
+	pub fn parse(text: &str) -> Result<WsMessage, ParseError> {
+		let e = match serde_json::from_str(text) {
+			Ok(msg) => return Ok(msg),
+			Err(e) => e,
+		};
+		let value: serde_json::Value = serde_json::from_str(text).map_err(|_| ParseError::Invalid(e.to_string()))?;
+		let tag = match value {
+			serde_json::Value::String(tag) => tag,
+			serde_json::Value::Object(obj) if obj.len() == 1 => obj.into_iter().next().unwrap().0,
+			_ => return Err(ParseError::Invalid(e.to_string())),
+		};
+		// serde doesn't have a nicer way to tell these apart.
+		if e.to_string().starts_with("unknown variant") {
+			Err(ParseError::UnknownVariant(tag))
+		} else {
+			Err(ParseError::Invalid(e.to_string()))
+		}
+	}
+
 	/// The `Message::Text` type stores a `Bytes` internally which clones cheaply so let's just prepare that early so we don't have to allocate as much 😇
 	pub fn to_websocket_msg(&self) -> tokio_tungstenite::tungstenite::protocol::Message {
 		tokio_tungstenite::tungstenite::protocol::Message::Text(serde_json::to_string(self).unwrap().into())
 	}
 
+	/// The variant's name without anything it carries. For the relay's `--audit-log`.
+	pub fn kind(&self) -> &'static str {
+		match self {
+			WsMessage::Info(_) => "Info",
+			WsMessage::ServerInfo { .. } => "ServerInfo",
+			WsMessage::Info2 { .. } => "Info2",
+			WsMessage::Session(_) => "Session",
+			WsMessage::Title(_) => "Title",
+			WsMessage::EnableAcks => "EnableAcks",
+			WsMessage::Observer => "Observer",
+			WsMessage::Nick(_) => "Nick",
+			WsMessage::NickChanged { .. } => "NickChanged",
+			WsMessage::Ack(_) => "Ack",
+			WsMessage::Nack { .. } => "Nack",
+			WsMessage::InRoom { .. } => "InRoom",
+			WsMessage::RateLimited => "RateLimited",
+			WsMessage::Incompatible { .. } => "Incompatible",
+			WsMessage::Join(_) => "Join",
+			WsMessage::Join2 { .. } => "Join2",
+			WsMessage::MigrateRoom { .. } => "MigrateRoom",
+			WsMessage::RoomName(_) => "RoomName",
+			WsMessage::Party(_) => "Party",
+			WsMessage::Roster(_) => "Roster",
+			WsMessage::MemberLeft(_) => "MemberLeft",
+			WsMessage::LeaderChanged(_) => "LeaderChanged",
+			WsMessage::Resume => "Resume",
+			WsMessage::AbsoluteSeek(_) => "AbsoluteSeek",
+			WsMessage::ChapterSeek { .. } => "ChapterSeek",
+			WsMessage::ForceResync(_) => "ForceResync",
+			WsMessage::RequestState => "RequestState",
+			WsMessage::State { .. } => "State",
+			WsMessage::EndReached => "EndReached",
+			WsMessage::Buffering(_) => "Buffering",
+			WsMessage::PauseReason { .. } => "PauseReason",
+			WsMessage::Position(_) => "Position",
+			WsMessage::Ping(_) => "Ping",
+			WsMessage::Pong(_) => "Pong",
+		}
+	}
+
+	/// The messages that `EnableAcks` gets an `Ack`/`Nack` for.
+	pub fn ack_kind(&self) -> Option<&'static str> {
+		match self {
+			WsMessage::Resume => Some("Resume"),
+			WsMessage::AbsoluteSeek(_) => Some("AbsoluteSeek"),
+			WsMessage::ChapterSeek { .. } => Some("ChapterSeek"),
+			_ => None,
+		}
+	}
+
+	/// Messages that move the rest of the room. `Observer`s don't get to send these.
+	pub fn drives_playback(&self) -> bool {
+		matches!(
+			self,
+			WsMessage::Resume
+				| WsMessage::AbsoluteSeek(_)
+				| WsMessage::ChapterSeek { .. }
+				| WsMessage::ForceResync(_)
+				| WsMessage::MigrateRoom { .. }
+				| WsMessage::EndReached
+				| WsMessage::Buffering(_)
+				| WsMessage::PauseReason { .. }
+				| WsMessage::Position(_)
+		)
+	}
+
 	pub fn send_helper(&self) -> tokio_tungstenite::tungstenite::protocol::Message {
 		match self {
 			WsMessage::Ping(_) | WsMessage::Pong(_) => (),
@@ -41,3 +298,32 @@ impl WsMessage {
 		self.to_websocket_msg()
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn unknown_messages_are_not_garbage() {
+		assert_eq!(WsMessage::parse(r#"{"Party":2}"#), Ok(WsMessage::Party(2)));
+		assert_eq!(WsMessage::parse(r#""Resume""#), Ok(WsMessage::Resume));
+
+		// From some future version.
+		assert_eq!(
+			WsMessage::parse(r#"{"Teleport":{"x":1}}"#),
+			Err(ParseError::UnknownVariant("Teleport".to_string()))
+		);
+		assert_eq!(
+			WsMessage::parse(r#""Teleport""#),
+			Err(ParseError::UnknownVariant("Teleport".to_string()))
+		);
+
+		// A known type with the wrong contents is still garbage.
+		assert!(matches!(
+			WsMessage::parse(r#"{"Party":"two"}"#),
+			Err(ParseError::Invalid(_))
+		));
+		assert!(matches!(WsMessage::parse("{not json"), Err(ParseError::Invalid(_))));
+		assert!(matches!(WsMessage::parse("[1, 2]"), Err(ParseError::Invalid(_))));
+	}
+}
@@ -2,48 +2,160 @@
 // Copyright 2024-2025 rtldg <rtldg@protonmail.com>
 
 use anyhow::anyhow;
-use interprocess::local_socket::{prelude::*, GenericFilePath, RecvHalf, SendHalf, Stream};
+use interprocess::local_socket::{prelude::*, GenericFilePath, Stream};
+use serde::de::DeserializeOwned;
 use serde_json::{json, Value};
 use std::{
 	collections::VecDeque,
 	io::{prelude::*, BufReader},
+	time::{Duration, Instant},
 };
 
+/// How many times to try reconnecting when the socket breaks before deciding mpv is gone.
+const RECONNECT_ATTEMPTS: u32 = 2;
+
+/// `--mpv-timeout`: how long a command waits for mpv's reply.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// mpv didn't answer within `Mpv::set_timeout()`'s timeout. Its reply is skipped if it shows up later.
+#[derive(Debug)]
+pub struct Timeout(pub Duration);
+
+impl std::fmt::Display for Timeout {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "mpv didn't answer within {:?}", self.0)
+	}
+}
+
+impl std::error::Error for Timeout {}
+
+pub fn is_timeout(e: &anyhow::Error) -> bool {
+	e.downcast_ref::<Timeout>().is_some()
+}
+
+/// mpv answered a command with an `error` other than "success" (like "property unavailable" while a file's loading).
+#[derive(Debug)]
+pub struct MpvError {
+	pub command: String,
+	pub error: String,
+}
+
+impl std::fmt::Display for MpvError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "mpv command '{}' failed: {}", self.command, self.error)
+	}
+}
+
+impl std::error::Error for MpvError {}
+
 pub struct Mpv {
-	reader: BufReader<RecvHalf>,
-	writer: SendHalf,
+	// Normalized socket path, for `reconnect()`.
+	path: String,
+	// Written to through `get_mut()`.
+	reader: BufReader<Stream>,
+	// `None` blocks forever.
+	timeout: Option<Duration>,
+	// Replies to commands that timed out. mpv answers in order so these are the next replies.
+	stale_replies: usize,
+	// Whatever's been read of the current line. Kept across timeouts.
+	line: Vec<u8>,
+	// A write timed out partway through a line. mpv would glue the next command onto it so `send()` reconnects first.
+	broken: bool,
 
 	event_queue: Option<VecDeque<Value>>,
+	// Observed properties so `detach()` can clean them up & `reconnect()` can re-observe them.
+	observed: Vec<(i32, String)>,
 }
 
 impl Mpv {
-	/// On Windows: `pipe` should be a string similar to r"\\.\pipe\mysocketnamehere"
+	/// On Windows: `pipe` should be a string similar to r"\\.\pipe\mysocketnamehere" (a bare "mysocketnamehere" works too)
 	/// On Linux: `pipe` should be a local file path for a unix-socket such as "/tmp/mpv.sock"
 	pub fn connect(pipe: &str) -> anyhow::Result<Mpv> {
-		let name = pipe.to_fs_name::<GenericFilePath>()?;
+		let pipe = if cfg!(windows) {
+			windows_pipe_path(pipe)
+		} else {
+			pipe.to_string()
+		};
+		let name = pipe.as_str().to_fs_name::<GenericFilePath>()?;
 		let stream = Stream::connect(name)?;
-		let (r, s) = stream.split();
 
 		Ok(Mpv {
-			reader: BufReader::new(r),
-			writer: s,
+			path: pipe,
+			reader: BufReader::new(stream),
+			timeout: None,
+			stale_replies: 0,
+			line: Vec::with_capacity(128),
+			broken: false,
 
 			event_queue: Some(VecDeque::new()),
+			observed: Vec::new(),
 		})
 	}
 
+	/// Also tells mpv to stop (or start) sending us events so they don't pile up on connections that ignore them.
 	pub fn events(&mut self, enabled: bool) {
 		if enabled {
-			let _ = self.event_queue.get_or_insert_with(|| VecDeque::new());
+			let _ = self.event_queue.get_or_insert_with(VecDeque::new);
+			let _ = self.enable_event("all");
 		} else {
 			self.event_queue = None;
+			let _ = self.disable_event("all");
+		}
+	}
+
+	/// Takes the events that showed up while `send()` waited for replies & haven't been `listen_for_event()`'d yet. Oldest first.
+	/// For throwing away a backlog that went stale during something slow. Events mpv hasn't sent yet aren't touched.
+	pub fn drain_events(&mut self) -> Vec<Value> {
+		self.event_queue
+			.as_mut()
+			.map(|queue| queue.drain(..).collect())
+			.unwrap_or_default()
+	}
+
+	/// Bounds every read & write on this connection so a stuck mpv can't wedge whoever's using it.
+	/// Don't use a timeout on a connection that sits in `listen_for_event()`. It'd spin.
+	pub fn set_timeout(&mut self, timeout: Option<Duration>) -> anyhow::Result<()> {
+		self.reader.get_ref().set_nonblocking(timeout.is_some())?;
+		self.timeout = timeout;
+		Ok(())
+	}
+
+	/// `raw_command()` that gives up after `timeout` (instead of the connection's usual timeout).
+	pub fn command_with_timeout(&mut self, command: &Value, timeout: Duration) -> anyhow::Result<Value> {
+		let old = self.timeout;
+		self.set_timeout(Some(timeout))?;
+		let reply = self.raw_command(command);
+		self.set_timeout(old)?;
+		reply
+	}
+
+	/// Non-blocking IO until the deadline. Polled because there's nothing to wait on across platforms.
+	fn poll_io<T>(
+		&mut self,
+		deadline: Option<Instant>,
+		mut io: impl FnMut(&mut Self) -> std::io::Result<T>,
+	) -> anyhow::Result<T> {
+		loop {
+			match io(self) {
+				Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+					if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+						return Err(Timeout(self.timeout.unwrap_or_default()).into());
+					}
+					std::thread::sleep(Duration::from_millis(1));
+				}
+				r => return Ok(r?),
+			}
 		}
 	}
 
 	/// Trims a trailing new-line
 	pub fn read_line(&mut self) -> anyhow::Result<String> {
-		let mut buffer = String::with_capacity(128);
-		let _ = self.reader.read_line(&mut buffer)?;
+		let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+		// Bytes (not `read_line()`) so a character split across `WouldBlock`s isn't thrown away.
+		if self.poll_io(deadline, |mpv| mpv.reader.read_until(b'\n', &mut mpv.line))? == 0 {
+			return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+		}
+		let mut buffer = String::from_utf8(std::mem::replace(&mut self.line, Vec::with_capacity(128)))?;
 		buffer.truncate(buffer.trim_end().len());
 		//log::debug!("{}", buffer);
 		Ok(buffer)
@@ -54,45 +166,109 @@ impl Mpv {
 		Ok(serde_json::from_str(&self.read_line()?)?)
 	}
 
-	// TODO: Check for "error"="success"... (like .get_property() does...)
-	//       And add a custom Error type for it...
-	pub fn send(&mut self, json: &Value) -> anyhow::Result<Value> {
-		// TODO: Use "request_id" & properly filter shit maybe...
-		//let mut json = json.clone();
-		//json["request_id"] = rand::random::<i32>().into();
+	fn write_json(&mut self, json: &Value) -> anyhow::Result<()> {
+		// Serialized first so IO errors show up as `std::io::Error` for `is_io_error()`.
+		let mut line = serde_json::to_vec(json)?;
+		line.push(b'\n');
+		let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+		let mut written = 0;
+		while written < line.len() {
+			match self.poll_io(deadline, |mpv| mpv.reader.get_mut().write(&line[written..])) {
+				Ok(n) => written += n,
+				Err(e) => {
+					self.broken |= written > 0;
+					return Err(e);
+				}
+			}
+		}
+		Ok(())
+	}
 
-		serde_json::to_writer(&mut self.writer, json)?;
-		//log::debug!("{}", json);
-		self.writer.write_all(b"\n")?;
+	fn read_reply(&mut self) -> anyhow::Result<Value> {
 		loop {
-			let v = self.read_value()?;
+			let v = match self.read_value() {
+				Err(e) if is_timeout(&e) => {
+					self.stale_replies += 1;
+					return Err(e);
+				}
+				v => v?,
+			};
 			//log::debug!("got {}", v);
 			if v.get("event").is_some() {
 				if let Some(queue) = self.event_queue.as_mut() {
 					queue.push_back(v);
 				}
+			} else if self.stale_replies > 0 {
+				self.stale_replies -= 1;
 			} else {
 				return Ok(v);
 			}
 		}
 	}
 
-	pub fn raw_command(&mut self, command: &Value) -> anyhow::Result<Value> {
-		let json = json!({
-			"command": command
-		});
-		self.send(&json)
+	/// mpv sometimes drops a pipe without exiting. Connects to the same path again & restores observers/events.
+	fn reconnect(&mut self) -> anyhow::Result<()> {
+		let mut attempt = 1;
+		let mpv = loop {
+			match Mpv::connect(&self.path) {
+				Ok(mpv) => break mpv,
+				Err(e) if attempt >= RECONNECT_ATTEMPTS => return Err(e),
+				Err(_) => {
+					attempt += 1;
+					std::thread::sleep(Duration::from_millis(100));
+				}
+			}
+		};
+		log::warn!("reconnected to mpv socket '{}'", self.path);
+		self.reader = mpv.reader;
+		self.stale_replies = 0;
+		self.line.clear();
+		self.broken = false;
+		self.set_timeout(self.timeout)?;
+		// Not `raw_command()` since that'd try reconnecting again.
+		if self.event_queue.is_none() {
+			self.write_json(&json!({"command": ["disable_event", "all"]}))?;
+			let _ = self.read_reply()?;
+		}
+		for (id, name) in self.observed.clone() {
+			self.write_json(&json!({"command": ["observe_property", id, name]}))?;
+			let _ = self.read_reply()?;
+		}
+		Ok(())
 	}
+}
 
-	pub fn observe_property(&mut self, id: i32, name: &str) -> anyhow::Result<()> {
-		let json = json!({
-			"command": ["observe_property", id, name],
-		});
-		let _ = self.send(&json)?;
-		Ok(())
+fn is_io_error(e: &anyhow::Error) -> bool {
+	e.downcast_ref::<std::io::Error>().is_some()
+}
+
+impl MpvIpc for Mpv {
+	// TODO: Check for "error"="success"... (like .get_property() & .command() do...)
+	fn send(&mut self, json: &Value) -> anyhow::Result<Value> {
+		// TODO: Use "request_id" & properly filter shit maybe...
+		//let mut json = json.clone();
+		//json["request_id"] = rand::random::<i32>().into();
+
+		//log::debug!("{}", json);
+		if self.broken {
+			self.reconnect()?;
+		}
+		match self.write_json(json) {
+			Err(e) if is_io_error(&e) => {
+				self.reconnect().map_err(|_| e)?;
+				self.write_json(json)?;
+			}
+			r => r?,
+		}
+		// mpv might've already ran the command so only reconnect for whoever's next instead of sending it twice.
+		let reply = self.read_reply();
+		if matches!(&reply, Err(e) if is_io_error(e)) {
+			let _ = self.reconnect();
+		}
+		reply
 	}
 
-	pub fn listen_for_event(&mut self) -> anyhow::Result<Value> {
+	fn listen_for_event(&mut self) -> anyhow::Result<Value> {
 		if let Some(queue) = self.event_queue.as_mut() {
 			if let Some(v) = queue.pop_front() {
 				return Ok(v);
@@ -100,14 +276,140 @@ impl Mpv {
 		}
 
 		loop {
-			let v = self.read_value()?;
+			let v = match self.read_value() {
+				Ok(v) => v,
+				Err(e) if is_io_error(&e) => {
+					self.reconnect().map_err(|_| e)?;
+					continue;
+				}
+				Err(e) => return Err(e),
+			};
 			if v.get("event").is_some() {
 				return Ok(v);
 			}
 		}
 	}
 
-	pub fn get_property(&mut self, property: &str) -> anyhow::Result<Value> {
+	fn observe_property(&mut self, id: i32, name: &str) -> anyhow::Result<()> {
+		let _ = self.raw_command(&json!(["observe_property", id, name]))?;
+		self.observed.push((id, name.to_string()));
+		Ok(())
+	}
+
+	fn unobserve_property(&mut self, id: i32) -> anyhow::Result<()> {
+		let _ = self.raw_command(&json!(["unobserve_property", id]))?;
+		self.observed.retain(|(i, _)| *i != id);
+		Ok(())
+	}
+
+	/// Stops observing everything so mpv doesn't log errors for a socket that's about to vanish.
+	/// This doesn't `quit` mpv. That'd close the user's player...
+	fn detach(&mut self) -> anyhow::Result<()> {
+		for (id, _) in std::mem::take(&mut self.observed) {
+			self.unobserve_property(id)?;
+		}
+		self.disable_event("all")?;
+		Ok(())
+	}
+}
+
+/// The mpv commands simulcast uses. `Mpv` is the real IPC connection & tests can swap in a fake.
+/// Only `send` & `listen_for_event` need implementing.
+pub trait MpvIpc: Send {
+	/// Sends `{"command": [...]}` & returns mpv's reply (events that show up in the meantime are queued or dropped).
+	fn send(&mut self, json: &Value) -> anyhow::Result<Value>;
+
+	fn listen_for_event(&mut self) -> anyhow::Result<Value>;
+
+	fn raw_command(&mut self, command: &Value) -> anyhow::Result<Value> {
+		let json = json!({
+			"command": command
+		});
+		self.send(&json)
+	}
+
+	/// `raw_command()` but a failed command is an `MpvError` instead of a reply nobody looks at.
+	/// Returns the reply's `data` (`null` for commands that don't return anything).
+	fn command(&mut self, command: &Value) -> anyhow::Result<Value> {
+		let mut v = self.raw_command(command)?;
+		if v["error"] == "success" {
+			Ok(v["data"].take())
+		} else {
+			Err(MpvError {
+				command: command[0].as_str().unwrap_or_default().to_string(),
+				error: v["error"].as_str().unwrap_or("no reply").to_string(),
+			}
+			.into())
+		}
+	}
+
+	/// The name mpv gave this connection (like "ipc_3").
+	/// mpv doesn't let IPC clients pick their own name so this is what shows up in mpv's log.
+	fn client_name(&mut self) -> anyhow::Result<String> {
+		let v = self.raw_command(&json!(["client_name"]))?;
+		match v["data"].as_str() {
+			Some(name) if v["error"] == "success" => Ok(name.to_string()),
+			_ => Err(anyhow!("client_name failed. value: {v}")),
+		}
+	}
+
+	fn observe_property(&mut self, id: i32, name: &str) -> anyhow::Result<()> {
+		let _ = self.raw_command(&json!(["observe_property", id, name]))?;
+		Ok(())
+	}
+
+	fn unobserve_property(&mut self, id: i32) -> anyhow::Result<()> {
+		let _ = self.raw_command(&json!(["unobserve_property", id]))?;
+		Ok(())
+	}
+
+	/// `name` can be "all".
+	fn enable_event(&mut self, name: &str) -> anyhow::Result<()> {
+		let _ = self.raw_command(&json!(["enable_event", name]))?;
+		Ok(())
+	}
+
+	/// `name` can be "all".
+	fn disable_event(&mut self, name: &str) -> anyhow::Result<()> {
+		let _ = self.raw_command(&json!(["disable_event", name]))?;
+		Ok(())
+	}
+
+	/// Cleans up before the connection is dropped.
+	fn detach(&mut self) -> anyhow::Result<()> {
+		self.disable_event("all")
+	}
+
+	/// Observes `property` until `predicate` accepts its value & returns that value.
+	/// Other events are dropped so don't use this on a connection something else is listening to.
+	/// mpv doesn't send anything while nothing changes so `timeout` is only checked when an event shows up.
+	fn get_property_watch(
+		&mut self,
+		property: &str,
+		timeout: Duration,
+		predicate: &mut dyn FnMut(&Value) -> bool,
+	) -> anyhow::Result<Value> {
+		// Out of the way of `PropertyObservers` ids.
+		const WATCH_ID: i32 = i32::MAX;
+		self.observe_property(WATCH_ID, property)?;
+		let deadline = Instant::now() + timeout;
+		let result = loop {
+			let event = match self.listen_for_event() {
+				Ok(event) => event,
+				Err(e) => break Err(e),
+			};
+			if event["event"] == "property-change" && event["id"] == WATCH_ID && predicate(&event["data"]) {
+				break Ok(event["data"].clone());
+			}
+			if Instant::now() >= deadline {
+				break Err(anyhow!("timed out waiting on {property}"));
+			}
+		};
+		let _ = self.unobserve_property(WATCH_ID);
+		result
+	}
+
+	fn get_property(&mut self, property: &str) -> anyhow::Result<Value> {
 		let json = json!({
 			"command": ["get_property", property],
 		});
@@ -120,7 +422,7 @@ impl Mpv {
 		}
 	}
 
-	pub fn set_property(&mut self, property: &str, value: &Value) -> anyhow::Result<()> {
+	fn set_property(&mut self, property: &str, value: &Value) -> anyhow::Result<()> {
 		let json = json!({
 			"command": ["set_property", property, value],
 		});
@@ -129,7 +431,7 @@ impl Mpv {
 		Ok(())
 	}
 
-	pub fn show_text(&mut self, text: &str, duration_ms: Option<i32>, level: Option<u32>) -> anyhow::Result<()> {
+	fn show_text(&mut self, text: &str, duration_ms: Option<i32>, level: Option<u32>) -> anyhow::Result<()> {
 		let mut json = json!({
 			"command": ["show-text", text],
 		});
@@ -150,3 +452,243 @@ impl Mpv {
 		Ok(())
 	}
 }
+
+/// mpv's `input-ipc-server` takes `\\.\pipe\name` or just `name` on Windows.
+/// People also write `\\.pipe\name` & `pipe\name` so fix those up.
+fn windows_pipe_path(pipe: &str) -> String {
+	const PREFIX: &str = r"\\.\pipe\";
+	if pipe.starts_with(PREFIX) {
+		return pipe.to_string();
+	}
+	let name = pipe.trim_start_matches(['\\', '/', '.']);
+	let name = name
+		.strip_prefix("pipe\\")
+		.or_else(|| name.strip_prefix("pipe/"))
+		.unwrap_or(name);
+	format!("{PREFIX}{name}")
+}
+
+/// Where mpv's socket usually is if someone set `input-ipc-server` themselves. `MPV_IPC_SERVER` goes first.
+fn socket_candidates() -> Vec<String> {
+	let mut candidates = vec![];
+	if let Ok(path) = std::env::var("MPV_IPC_SERVER") {
+		if !path.is_empty() {
+			candidates.push(path);
+		}
+	}
+	if cfg!(windows) {
+		candidates.push(r"\\.\pipe\mpvsocket".to_string());
+		candidates.push(r"\\.\pipe\mpv-pipe".to_string());
+	} else {
+		candidates.push("/tmp/mpvsocket".to_string());
+		if let Ok(dir) = std::env::var("XDG_RUNTIME_DIR") {
+			candidates.push(format!("{dir}/mpv.sock"));
+		}
+	}
+	candidates
+}
+
+/// For when `--client-sock` isn't given. Returns the first of `socket_candidates()` that mpv answers on.
+pub fn find_socket() -> anyhow::Result<String> {
+	let candidates = socket_candidates();
+	for path in &candidates {
+		if Mpv::connect(path).is_ok() {
+			return Ok(path.clone());
+		}
+	}
+	Err(anyhow!(
+		"couldn't find mpv's socket (tried {}). pass --client-sock with mpv's input-ipc-server path",
+		candidates.join(", ")
+	))
+}
+
+type PropertyHandler<C> = Box<dyn FnMut(&mut C, Value) -> anyhow::Result<()>>;
+
+/// Routes `property-change` events to typed handlers so adding a new property doesn't need another match arm & a hand-picked observe id.
+/// `C` is whatever the handlers need to share (sockets, state, etc).
+pub struct PropertyObservers<C> {
+	handlers: Vec<(i32, String, PropertyHandler<C>)>,
+}
+
+impl<C> Default for PropertyObservers<C> {
+	fn default() -> Self {
+		PropertyObservers { handlers: Vec::new() }
+	}
+}
+
+impl<C> PropertyObservers<C> {
+	/// Observes `name` on `mpv` & calls `handler` whenever it changes.
+	/// Changes that don't deserialize into `T` (like `null` when a property is unavailable) are logged & skipped. Use `Option<T>` to see those.
+	pub fn observe<T, F>(&mut self, mpv: &mut dyn MpvIpc, name: &str, mut handler: F) -> anyhow::Result<()>
+	where
+		T: DeserializeOwned,
+		F: FnMut(&mut C, T) -> anyhow::Result<()> + 'static,
+	{
+		let id = self.handlers.len() as i32 + 1;
+		mpv.observe_property(id, name)?;
+		let property = name.to_string();
+		self.handlers.push((
+			id,
+			name.to_string(),
+			Box::new(move |ctx, data| match serde_json::from_value::<T>(data.clone()) {
+				Ok(v) => handler(ctx, v),
+				Err(_) => {
+					log::debug!("{property} property-change with unexpected data: {data}");
+					Ok(())
+				}
+			}),
+		));
+		Ok(())
+	}
+
+	/// Calls the handler for a `property-change` event. Other events are ignored.
+	pub fn dispatch(&mut self, ctx: &mut C, event: &Value) -> anyhow::Result<()> {
+		if event["event"] != "property-change" {
+			return Ok(());
+		}
+		let id = event["id"].as_i64();
+		let Some((_, _, handler)) = self
+			.handlers
+			.iter_mut()
+			.find(|(i, name, _)| Some(*i as i64) == id && event["name"] == name.as_str())
+		else {
+			log::debug!("property-change without a handler: {event}");
+			return Ok(());
+		};
+		handler(ctx, event["data"].clone())
+	}
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn late_replies_are_skipped() {
+		let path = std::env::temp_dir().join(format!("simulcast-mpv-test-{}.sock", std::process::id()));
+		let _ = std::fs::remove_file(&path);
+		let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+		let fake_mpv = std::thread::spawn(move || {
+			let (stream, _) = listener.accept().unwrap();
+			let mut writer = stream.try_clone().unwrap();
+			let mut lines = BufReader::new(stream).lines();
+			// Stuck on the first command for a while. Then answers both in order.
+			let _ = lines.next();
+			std::thread::sleep(Duration::from_millis(200));
+			writer
+				.write_all(b"{\"data\":\"first\",\"error\":\"success\"}\n")
+				.unwrap();
+			let _ = lines.next();
+			writer
+				.write_all(b"{\"data\":\"second\",\"error\":\"success\"}\n")
+				.unwrap();
+		});
+
+		let mut mpv = Mpv::connect(path.to_str().unwrap()).unwrap();
+		let e = mpv
+			.command_with_timeout(&json!(["get_property", "a"]), Duration::from_millis(50))
+			.unwrap_err();
+		assert!(is_timeout(&e));
+		std::thread::sleep(Duration::from_millis(300));
+		assert_eq!(mpv.get_property("b").unwrap(), json!("second"));
+
+		fake_mpv.join().unwrap();
+		let _ = std::fs::remove_file(&path);
+	}
+
+	#[test]
+	fn stalled_writes_reconnect() {
+		let path = std::env::temp_dir().join(format!("simulcast-mpv-stall-{}.sock", std::process::id()));
+		let _ = std::fs::remove_file(&path);
+		let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+		let fake_mpv = std::thread::spawn(move || {
+			// Never reads the first connection so the socket's buffer fills up.
+			let (_stalled, _) = listener.accept().unwrap();
+			let (stream, _) = listener.accept().unwrap();
+			let mut writer = stream.try_clone().unwrap();
+			let mut lines = BufReader::new(stream).lines();
+			let command: Value = serde_json::from_str(&lines.next().unwrap().unwrap()).unwrap();
+			assert_eq!(command, json!({"command": ["get_property", "b"]}));
+			writer
+				.write_all(b"{\"data\":\"fresh\",\"error\":\"success\"}\n")
+				.unwrap();
+		});
+
+		let mut mpv = Mpv::connect(path.to_str().unwrap()).unwrap();
+		let huge = "a".repeat(16 * 1024 * 1024);
+		let e = mpv
+			.command_with_timeout(&json!(["get_property", huge]), Duration::from_millis(50))
+			.unwrap_err();
+		assert!(is_timeout(&e));
+		assert_eq!(mpv.get_property("b").unwrap(), json!("fresh"));
+
+		fake_mpv.join().unwrap();
+		let _ = std::fs::remove_file(&path);
+	}
+
+	#[test]
+	fn queued_events_drain() {
+		let path = std::env::temp_dir().join(format!("simulcast-mpv-drain-{}.sock", std::process::id()));
+		let _ = std::fs::remove_file(&path);
+		let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+		let fake_mpv = std::thread::spawn(move || {
+			let (stream, _) = listener.accept().unwrap();
+			let mut writer = stream.try_clone().unwrap();
+			let mut lines = BufReader::new(stream).lines();
+			// Events sneak in before each reply.
+			for i in 0..2 {
+				let _ = lines.next();
+				let replies = [
+					json!({"event": "property-change", "id": i, "name": "pause", "data": true}),
+					json!({"event": "seek"}),
+					json!({"data": i, "error": "success"}),
+				];
+				for line in replies {
+					writer.write_all(format!("{line}\n").as_bytes()).unwrap();
+				}
+			}
+		});
+
+		let mut mpv = Mpv::connect(path.to_str().unwrap()).unwrap();
+		assert_eq!(mpv.get_property("a").unwrap(), json!(0));
+		assert_eq!(mpv.get_property("b").unwrap(), json!(1));
+		let events = mpv.drain_events();
+		assert_eq!(events.len(), 4);
+		assert_eq!(events[0]["id"], json!(0));
+		assert_eq!(events[1]["event"], json!("seek"));
+		assert_eq!(events[2]["id"], json!(1));
+		assert!(mpv.drain_events().is_empty());
+
+		// Nothing's queued while events are off.
+		mpv.event_queue = None;
+		assert!(mpv.drain_events().is_empty());
+
+		fake_mpv.join().unwrap();
+		let _ = std::fs::remove_file(&path);
+	}
+
+	#[test]
+	fn failed_commands_are_errors() {
+		struct Replies(Vec<Value>);
+		impl MpvIpc for Replies {
+			fn send(&mut self, _json: &Value) -> anyhow::Result<Value> {
+				Ok(self.0.remove(0))
+			}
+			fn listen_for_event(&mut self) -> anyhow::Result<Value> {
+				Err(anyhow!("no events here"))
+			}
+		}
+
+		let mut mpv = Replies(vec![
+			json!({"data": null, "error": "success"}),
+			json!({"error": "property unavailable"}),
+		]);
+		assert_eq!(mpv.command(&json!(["seek", "10", "absolute"])).unwrap(), Value::Null);
+		let e = mpv.command(&json!(["osd-auto", "seek", "10"])).unwrap_err();
+		let e = e.downcast_ref::<MpvError>().unwrap();
+		assert_eq!(
+			(e.command.as_str(), e.error.as_str()),
+			("osd-auto", "property unavailable")
+		);
+	}
+}
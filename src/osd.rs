@@ -0,0 +1,171 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+// Copyright 2023-2025 rtldg <rtldg@protonmail.com>
+
+//! What the client shows on mpv's OSD when something happens in the room.
+//! `--osd-strings` points to a JSON file that replaces any of these (for other languages or just different wording).
+
+use serde::Deserialize;
+
+/// Templates. `{count}`, `{name}`, `{room_code}`, `{old}`, & `{new}` are filled in where they're listed.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct OsdStrings {
+	/// `{count}`
+	pub party_count: String,
+	/// `{name}`
+	pub member_joined: String,
+	/// `{name}`
+	pub member_left: String,
+	pub watching_solo: String,
+	/// `{name}`
+	pub leader_changed: String,
+	/// `{name}`
+	pub room_name: String,
+	/// `{room_code}`
+	pub party_moved: String,
+	pub end_reached: String,
+	pub waiting_for_buffering: String,
+	pub stopped_waiting: String,
+	/// `{name}`
+	pub paused_by: String,
+	/// `{name}`
+	pub paused_for_buffering: String,
+	/// `{count}` (seconds)
+	pub join_countdown: String,
+	/// `{old}` & `{new}`
+	pub renamed: String,
+	pub caught_up: String,
+	pub nobody_to_catch_up_to: String,
+	pub nobody_to_resync_with: String,
+	pub resyncing_everyone: String,
+	pub observers_cant_move_room: String,
+	/// `{room_code}`
+	pub moving_everyone: String,
+}
+
+impl Default for OsdStrings {
+	fn default() -> Self {
+		Self {
+			party_count: "party count: {count}".to_string(),
+			member_joined: "{name} joined".to_string(),
+			member_left: "{name} left".to_string(),
+			watching_solo: "you're watching solo now".to_string(),
+			leader_changed: "{name} is now the leader".to_string(),
+			room_name: "room: {name}".to_string(),
+			party_moved: "the party moved to room '{room_code}'".to_string(),
+			end_reached: "someone reached the end".to_string(),
+			waiting_for_buffering: "waiting for someone to buffer...".to_string(),
+			stopped_waiting: "the room stopped waiting for you to buffer".to_string(),
+			paused_by: "paused by {name}".to_string(),
+			paused_for_buffering: "paused — {name} is buffering".to_string(),
+			join_countdown: "new viewer joining, pausing in {count}...".to_string(),
+			renamed: "{old} is now {new}".to_string(),
+			caught_up: "caught up".to_string(),
+			nobody_to_catch_up_to: "nobody to catch up to".to_string(),
+			nobody_to_resync_with: "nobody to resync with".to_string(),
+			resyncing_everyone: "resyncing everyone to your position".to_string(),
+			observers_cant_move_room: "observers can't move the room".to_string(),
+			moving_everyone: "moving everyone to room '{room_code}'".to_string(),
+		}
+	}
+}
+
+impl OsdStrings {
+	/// Reads an `--osd-strings` file. Anything it leaves out stays English.
+	pub fn load(path: &std::path::Path) -> anyhow::Result<Self> {
+		use anyhow::Context;
+		let text =
+			std::fs::read_to_string(path).with_context(|| format!("couldn't read osd strings '{}'", path.display()))?;
+		serde_json::from_str(&text).with_context(|| format!("bad osd strings in '{}'", path.display()))
+	}
+
+	pub fn party_count(&self, count: u32) -> String {
+		fill(&self.party_count, &[("count", &count.to_string())])
+	}
+
+	pub fn member_joined(&self, name: &str) -> String {
+		fill(&self.member_joined, &[("name", name)])
+	}
+
+	pub fn member_left(&self, name: &str) -> String {
+		fill(&self.member_left, &[("name", name)])
+	}
+
+	pub fn leader_changed(&self, name: &str) -> String {
+		fill(&self.leader_changed, &[("name", name)])
+	}
+
+	pub fn room_name(&self, name: &str) -> String {
+		fill(&self.room_name, &[("name", name)])
+	}
+
+	pub fn party_moved(&self, room_code: &str) -> String {
+		fill(&self.party_moved, &[("room_code", room_code)])
+	}
+
+	pub fn paused_by(&self, name: &str) -> String {
+		fill(&self.paused_by, &[("name", name)])
+	}
+
+	pub fn paused_for_buffering(&self, name: &str) -> String {
+		fill(&self.paused_for_buffering, &[("name", name)])
+	}
+
+	pub fn join_countdown(&self, seconds: u32) -> String {
+		fill(&self.join_countdown, &[("count", &seconds.to_string())])
+	}
+
+	pub fn renamed(&self, old: &str, new: &str) -> String {
+		fill(&self.renamed, &[("old", old), ("new", new)])
+	}
+
+	pub fn moving_everyone(&self, room_code: &str) -> String {
+		fill(&self.moving_everyone, &[("room_code", room_code)])
+	}
+}
+
+/// `fill("{name} left", &[("name", "bob")])` -> "bob left"
+/// One pass over the template so a name like "{new}" isn't filled in again.
+fn fill(template: &str, values: &[(&str, &str)]) -> String {
+	let mut text = String::with_capacity(template.len());
+	let mut rest = template;
+	while let Some(start) = rest.find('{') {
+		text.push_str(&rest[..start]);
+		rest = &rest[start..];
+		let found = rest.find('}').and_then(|end| {
+			let (_, value) = values.iter().find(|(key, _)| *key == &rest[1..end])?;
+			Some((end, value))
+		});
+		let Some((end, value)) = found else {
+			// Not one of ours. Keep the brace.
+			text.push('{');
+			rest = &rest[1..];
+			continue;
+		};
+		text.push_str(value);
+		rest = &rest[end + 1..];
+	}
+	text.push_str(rest);
+	text
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn overrides_keep_the_rest_english() {
+		let osd: OsdStrings = serde_json::from_str(r#"{"party_count": "Teilnehmer: {count}"}"#).unwrap();
+		assert_eq!(osd.party_count(3), "Teilnehmer: 3");
+		assert_eq!(osd.member_left("bob (2.1.0)"), "bob (2.1.0) left");
+		assert_eq!(osd.party_moved("abc"), "the party moved to room 'abc'");
+		assert_eq!(osd.moving_everyone("abc"), "moving everyone to room 'abc'");
+
+		// Names are filled in as-is.
+		assert_eq!(OsdStrings::default().renamed("{new}", "x"), "{new} is now x");
+		assert_eq!(OsdStrings::default().member_left("{name} {"), "{name} { left");
+
+		// Typos shouldn't be silently ignored.
+		assert!(serde_json::from_str::<OsdStrings>(r#"{"party_cuont": "x"}"#).is_err());
+	}
+}
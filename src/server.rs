@@ -1,293 +1,3118 @@
 // SPDX-License-Identifier: AGPL-3.0-or-later
 // Copyright 2023-2025 rtldg <rtldg@protonmail.com>
 
-use crate::message::WsMessage;
+use crate::message::{clean_title, parse_version, ParseError, PingSettings, WsMessage};
+use anyhow::Context;
 use chrono::prelude::*;
-use futures::{SinkExt, StreamExt};
-use log::{debug, info};
+use futures::{SinkExt, StreamExt, TryStreamExt};
+use log::{debug, info, warn};
 use std::{
 	borrow::BorrowMut,
 	collections::HashMap,
 	ops::DerefMut,
-	sync::{Arc, Mutex},
+	sync::{Arc, Mutex, Weak},
 	time::Duration,
 };
 
-use tokio_tungstenite::tungstenite::{protocol::WebSocketConfig, Message};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_tungstenite::tungstenite::{
+	protocol::{frame::coding::CloseCode, CloseFrame, WebSocketConfig},
+	Message,
+};
+
+/// Pings above this are treated as garbage. It's used for `queued_resumes`/`queued_seeks` delays so one bad value would stall the whole room.
+const MAX_PING: f64 = 5.0;
+
+/// Clients older than this get `WsMessage::Incompatible` and are disconnected.
+/// Bump this whenever protocol semantics change in a way that'd desync older clients.
+const MIN_CLIENT_VERSION: &str = "2.1.0";
+
+/// How long a room waits for buffering members before resuming without them.
+const BUFFERING_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// How long a disconnected member with a `Session` keeps their slot in the room.
+const RECONNECT_GRACE: Duration = Duration::from_secs(5);
 
 struct Member {
 	id: u64,
 	ping: f64,
+	// Shown in everyone's `Roster`.
+	name: String,
 	sender: tokio::sync::mpsc::UnboundedSender<Message>,
+	// When they joined the current room. The longest-joined member takes over as leader.
+	joined_at: std::time::Instant,
+	// For `reap_stale_members()`.
+	last_pong: std::time::Instant,
+	// From `Session`. Reconnecting with it within `RECONNECT_GRACE` takes this slot back.
+	session: Option<String>,
+	// Their connection is gone but the slot's being held for them.
+	disconnected: bool,
+	// `--admin-socket` kicks wake this up so `handle_client()` hangs up on them.
+	kick: Arc<tokio::sync::Notify>,
+	// `Observer`: follows the room without being counted in `Party` or leading it.
+	observer: bool,
+	// Set by `migrate_room()` so `handle_client()` knows which room it's in now.
+	moved_to: Arc<Mutex<Option<String>>>,
 }
 
-#[derive(Default)]
 struct Room {
 	queued_resumes: Option<tokio::task::JoinSet<()>>,
+	queued_seeks: Option<tokio::task::JoinSet<()>>,
 	members: Vec<Member>,
+	// Whoever's been in the room longest. `None` only while the room is being created.
+	leader: Option<u64>,
+	// Something like "brave-otter-42" that people can read out & `Join` with. Password rooms don't get one.
+	short_name: Option<String>,
+	// The first `--share-title` title from a member. Most rooms won't have one.
+	title: Option<String>,
+	// Members that reconnected and are waiting for someone's `State`.
+	state_requesters: Vec<u64>,
+	// Members whose mpv is `paused-for-cache`. The rest of the room is paused until this empties out.
+	buffering: Vec<u64>,
+	// Bumped whenever the room starts waiting so an old timeout doesn't end a newer wait.
+	buffering_generation: u64,
+	// For the summary that's logged when the room empties out.
+	created_at: std::time::Instant,
+	peak_members: usize,
+	messages_relayed: u64,
+}
+
+impl Default for Room {
+	fn default() -> Self {
+		Room {
+			queued_resumes: None,
+			queued_seeks: None,
+			members: Vec::new(),
+			leader: None,
+			short_name: None,
+			title: None,
+			state_requesters: Vec::new(),
+			buffering: Vec::new(),
+			buffering_generation: 0,
+			created_at: std::time::Instant::now(),
+			peak_members: 0,
+			messages_relayed: 0,
+		}
+	}
 }
 
-type Rooms = Arc<Mutex<HashMap<String, Room>>>;
+impl Room {
+	/// Sends the seek to *every* member, including whoever sent it, so everyone ends up paused at the same point.
+	/// Staggered by ping (like resumes) so the faster members don't pause before the slower ones.
+	fn seek(&mut self, t: f64) {
+		self.seek_with(&WsMessage::AbsoluteSeek(t));
+	}
 
-static REPO_URL: std::sync::OnceLock<http::Uri> = std::sync::OnceLock::new();
+	/// `seek()` with an `AbsoluteSeek` or `ChapterSeek`.
+	fn seek_with(&mut self, msg: &WsMessage) {
+		drop(self.queued_resumes.take()); // abort queued resumes...
+		drop(self.queued_seeks.take()); // and seeks that are about to be outdated
 
-fn remove_from_room(id: u64, current_room: &String, rooms: &mut HashMap<String, Room>) -> Member {
-	let members = &mut rooms.get_mut(current_room).unwrap().members;
-	let i = members.iter().position(|m| m.id == id).unwrap();
-	let me = members.swap_remove(i);
-	if members.is_empty() {
-		rooms.remove(current_room);
-	} else {
-		let len = members.len();
-		let msg = WsMessage::Party(len as u32).send_helper();
-		for member in members {
+		self.queued_seeks = send_ping_compensated(&self.members, &msg.send_helper());
+	}
+
+	/// Pauses everyone else until `id` finishes buffering (or `BUFFERING_TIMEOUT` runs out).
+	fn start_buffering(&mut self, id: u64, room_name: &str, rooms: &Rooms) {
+		if self.buffering.contains(&id) {
+			return;
+		}
+		drop(self.queued_resumes.take());
+
+		let name = self
+			.members
+			.iter()
+			.find(|m| m.id == id)
+			.map(|m| m.name.clone())
+			.unwrap_or_default();
+		let reason = WsMessage::PauseReason {
+			reason: "buffering".to_string(),
+			name,
+		}
+		.send_helper();
+		let msg = WsMessage::Buffering(true).send_helper();
+		for member in &self.members {
+			if member.id != id {
+				let _ = member.sender.send(reason.clone());
+				let _ = member.sender.send(msg.clone());
+			}
+		}
+
+		if !self.buffering.is_empty() {
+			self.buffering.push(id);
+			return;
+		}
+		self.buffering.push(id);
+		self.buffering_generation += 1;
+
+		let generation = self.buffering_generation;
+		let room_name = room_name.to_string();
+		let rooms = rooms.clone();
+		tokio::spawn(async move {
+			tokio::time::sleep(BUFFERING_TIMEOUT).await;
+			let Some(room) = rooms.lock().unwrap().get(&room_name).cloned() else {
+				return;
+			};
+			let mut room = room.lock().unwrap();
+			if room.buffering_generation != generation || room.buffering.is_empty() {
+				return;
+			}
+			info!(room = room_name.as_str(), event = "buffering_timeout"; "room {room_name} stopped waiting for {} buffering member(s)", room.buffering.len());
+			room.buffering.clear();
+			room.finish_buffering();
+		});
+	}
+
+	/// `id` finished buffering (or left). Resumes the room once nobody's buffering.
+	fn stop_buffering(&mut self, id: u64) {
+		let Some(i) = self.buffering.iter().position(|&b| b == id) else {
+			return;
+		};
+		let _ = self.buffering.swap_remove(i);
+		if self.buffering.is_empty() {
+			self.finish_buffering();
+		}
+	}
+
+	fn finish_buffering(&mut self) {
+		let msg = WsMessage::Buffering(false).send_helper();
+		for member in &self.members {
 			let _ = member.sender.send(msg.clone());
 		}
+		drop(self.queued_resumes.take());
+		self.queued_resumes = send_ping_compensated(&self.members, &WsMessage::Resume.send_helper());
 	}
-	me
 }
 
-async fn handle_websocket(
-	stream: tokio::net::TcpStream,
-	id: u64,
-	addr: std::net::SocketAddr,
-	rooms: Rooms,
-	connected_counter: Arc<()>,
-) -> anyhow::Result<()> {
-	let mut current_room = String::new();
-	let ret = handle_websocket_inner(stream, id, &mut current_room, rooms.clone()).await;
-	if current_room != "" {
-		let mut rooms = rooms.lock().unwrap();
-		let _ = remove_from_room(id, &current_room, rooms.deref_mut());
+/// Sends `msg` to every member but delays it by `highest_ping - member.ping` so it arrives for everyone at the same time.
+/// Returns the delayed sends (if there are any). Dropping the `JoinSet` aborts them.
+fn send_ping_compensated(members: &[Member], msg: &Message) -> Option<tokio::task::JoinSet<()>> {
+	let highest_ping = members.iter().map(|m| m.ping).max_by(|a, b| a.total_cmp(b))?;
+
+	let mut set = tokio::task::JoinSet::new();
+	for member in members {
+		let delay = Duration::from_secs_f64(highest_ping - member.ping);
+		if delay.is_zero() {
+			let _ = member.sender.send(msg.clone());
+			continue;
+		}
+		let sender = member.sender.clone();
+		let msg = msg.clone();
+		set.spawn(async move {
+			tokio::time::sleep(delay).await;
+			let _ = sender.send(msg);
+		});
 	}
-	let num_connected = Arc::strong_count(&connected_counter) - 2; // -1 for ourself & -1 for the original
-	info!("finished with client {id} {addr} ({num_connected} clients connected) {ret:?}");
-	ret
+	(!set.is_empty()).then_some(set)
 }
 
-async fn handle_websocket_inner(
-	stream: tokio::net::TcpStream,
-	id: u64,
-	current_room: &mut String,
-	rooms: Rooms,
-) -> anyhow::Result<()> {
-	let ws = tokio_tungstenite::accept_async_with_config(
-		stream,
-		Some(
-			WebSocketConfig::default()
-				.max_message_size(Some(512))
-				.max_frame_size(Some(800))
-				.accept_unmasked_frames(false),
-		),
+/// The outer lock is for finding rooms & moving members between them. Everything else only locks the one room
+/// so busy rooms don't hold each other up. Always lock the outer one first if you need both.
+type Rooms = Arc<Mutex<RoomMap>>;
+type RoomMap = HashMap<String, Arc<Mutex<Room>>>;
+
+/// `key`'s room. The `rooms` lock is only held long enough to find it.
+/// `None` if it's gone, like when the reaper or a `MigrateRoom` got there first.
+fn room_of(rooms: &Rooms, key: &str) -> Option<Arc<Mutex<Room>>> {
+	rooms.lock().unwrap().get(key).cloned()
+}
+
+/// Clients that fell back to HTTP long-polling because something between them & us blocks websockets. Keyed by session id.
+type LongPollSessions = Arc<Mutex<HashMap<String, LongPollSession>>>;
+
+struct LongPollSession {
+	// Messages POSTed to `/longpoll/send`.
+	incoming: futures::channel::mpsc::UnboundedSender<Message>,
+	// Messages for the client. Handed out by `/longpoll/recv`.
+	outgoing: Arc<tokio::sync::Mutex<tokio::sync::mpsc::UnboundedReceiver<Message>>>,
+}
+
+/// How long `/longpoll/recv` waits for something to send. Clients are pinged every second anyway so this is rarely hit.
+const LONGPOLL_WAIT: Duration = Duration::from_secs(20);
+
+/// Half the round-trip of the timestamp we sent in `Ping`, clamped to `0..=MAX_PING`.
+/// The timestamp is ours so a weird value means the client mangled it or our clock jumped.
+fn ping_from_pong(id: u64, sent: &str, now: DateTime<Utc>) -> Option<f64> {
+	let Ok(sent) = DateTime::parse_from_rfc3339(sent) else {
+		warn!("client {id} sent a pong with a bad timestamp '{sent}'");
+		return None;
+	};
+	let ping = now.signed_duration_since(sent).num_microseconds().unwrap_or(i64::MAX) as f64 / 2_000_000.0;
+	if ping < 0.0 {
+		warn!("client {id} pong is from the future ({ping}s). clock skew? clamping ping to 0");
+		Some(0.0)
+	} else if ping > MAX_PING {
+		warn!("client {id} ping of {ping}s is absurd. clock skew? clamping ping to {MAX_PING}");
+		Some(MAX_PING)
+	} else {
+		Some(ping)
+	}
+}
+
+/// `--max-message-size` & `--max-frame-size`. Clients only send small messages so these are kept tiny.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 512;
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 512;
+
+/// `--rate-limit`: messages a client can send per `RATE_LIMIT_WINDOW` before it's disconnected. 0 turns it off.
+/// Clients send a pong every second & debounce everything else so normal use is far below this.
+pub const DEFAULT_RATE_LIMIT: u32 = 50;
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(10);
+
+// For `/metrics`.
+static RATE_LIMITED_TOTAL: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+/// `--max-connections`: new connections past this are dropped right after being accepted. 0 turns it off.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 1000;
+
+/// How often `--room-names-file` is written (besides at shutdown).
+const SAVED_NAMES_INTERVAL: Duration = Duration::from_secs(60);
+
+pub const DEFAULT_AUDIT_LOG_MAX_MB: u64 = 10;
+
+fn websocket_config(max_message_size: usize, max_frame_size: usize) -> WebSocketConfig {
+	WebSocketConfig::default()
+		.max_message_size(Some(max_message_size))
+		.max_frame_size(Some(max_frame_size))
+		.accept_unmasked_frames(false)
+}
+
+// Client ids. `InRoom` sessions take them too.
+static LATEST_ID: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+/// Most `InRoom` sessions one connection can have.
+const MAX_ROOM_SESSIONS: usize = 16;
+
+/// A relay's flags. Set with `RelayServer`'s builder methods & shared by all of its connections.
+struct RelaySettings {
+	repo_url: http::Uri,
+	ping: PingSettings,
+	// `--max-message-size` & `--max-frame-size`
+	websocket: WebSocketConfig,
+	rate_limit: u32,
+	max_connections: usize,
+	// `--short-room-names`. Off by default since they're easy to guess.
+	short_room_names: bool,
+	// `--discovery-file`. Read for every request so it can be edited without restarting.
+	discovery_file: Option<std::path::PathBuf>,
+	// `--admin-socket`. Always a localhost address.
+	admin_socket: Option<std::net::SocketAddr>,
+	// `--room-names-file`. Without it short names only last as long as their room.
+	saved_names: Option<Arc<Mutex<SavedNames>>>,
+	// `--audit-log`. Off by default.
+	audit_log: Option<Mutex<AuditLog>>,
+	// For the uptime in `ServerInfo`.
+	started_at: std::time::Instant,
+}
+
+impl RelaySettings {
+	fn new(repo_url: http::Uri, ping: PingSettings) -> Self {
+		RelaySettings {
+			repo_url,
+			ping,
+			websocket: websocket_config(DEFAULT_MAX_MESSAGE_SIZE, DEFAULT_MAX_FRAME_SIZE),
+			rate_limit: DEFAULT_RATE_LIMIT,
+			max_connections: DEFAULT_MAX_CONNECTIONS,
+			short_room_names: false,
+			discovery_file: None,
+			admin_socket: None,
+			saved_names: None,
+			audit_log: None,
+			started_at: std::time::Instant::now(),
+		}
+	}
+
+	fn audit(&self, kind: &str, room_key: &str, id: u64) {
+		let Some(audit_log) = &self.audit_log else {
+			return;
+		};
+		let line = audit_line(kind, room_key, id, Utc::now());
+		if let Err(e) = audit_log.lock().unwrap().write(&line) {
+			warn!("couldn't write to --audit-log: {e}");
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum LogFormat {
+	/// Colored, human-readable lines.
+	Text,
+	/// One JSON object per line. Nicer for Docker/Kubernetes log scrapers.
+	Json,
+}
+
+/// Writes `{"timestamp":...,"level":...,"module":...,"message":...}` plus any key-values from the log macro (like `client_id`).
+fn json_format(
+	w: &mut dyn std::io::Write,
+	_now: &mut flexi_logger::DeferredNow,
+	record: &log::Record,
+) -> std::io::Result<()> {
+	struct Visitor<'a>(&'a mut serde_json::Map<String, serde_json::Value>);
+	impl<'kvs> log::kv::VisitSource<'kvs> for Visitor<'_> {
+		fn visit_pair(&mut self, key: log::kv::Key<'kvs>, value: log::kv::Value<'kvs>) -> Result<(), log::kv::Error> {
+			let value = if let Some(n) = value.to_u64() {
+				n.into()
+			} else {
+				value.to_string().into()
+			};
+			self.0.insert(key.to_string(), value);
+			Ok(())
+		}
+	}
+
+	let mut obj = serde_json::Map::new();
+	obj.insert(
+		"timestamp".into(),
+		Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true).into(),
+	);
+	obj.insert("level".into(), record.level().as_str().into());
+	obj.insert("module".into(), record.module_path().unwrap_or_default().into());
+	obj.insert("message".into(), record.args().to_string().into());
+	let _ = record.key_values().visit(&mut Visitor(&mut obj));
+	write!(w, "{}", serde_json::Value::Object(obj))
+}
+
+/// Tells every member the new party count & who's in the room.
+fn broadcast_party(members: &[Member]) {
+	let roster = roster(members).send_helper();
+	for member in members {
+		let _ = member.sender.send(party_for(members, member).send_helper());
+		let _ = member.sender.send(roster.clone());
+	}
+}
+
+/// For observers coming & going. A new `Party` would pause everyone even though the count didn't change.
+fn broadcast_roster(members: &[Member]) {
+	let roster = roster(members).send_helper();
+	for member in members {
+		let _ = member.sender.send(roster.clone());
+	}
+}
+
+/// Observers don't count.
+fn party_size(members: &[Member]) -> u32 {
+	members.iter().filter(|m| !m.observer).count() as u32
+}
+
+/// Observers count themselves so their client still follows the room like any other party member.
+fn party_for(members: &[Member], member: &Member) -> WsMessage {
+	WsMessage::Party(party_size(members) + member.observer as u32)
+}
+
+fn roster(members: &[Member]) -> WsMessage {
+	WsMessage::Roster(
+		members
+			.iter()
+			.map(|m| match m.observer {
+				true => format!("{} (observing)", m.name),
+				false => m.name.clone(),
+			})
+			.collect(),
 	)
-	.await?;
+}
 
-	// We still want ping calculation even when a user isn't in a room...
-	let mut ping = 0.0;
+/// Whoever's been in the room longest. Observers only if there's nobody else.
+fn next_leader(members: &[Member]) -> Option<&Member> {
+	members
+		.iter()
+		.filter(|m| !m.observer)
+		.min_by_key(|m| m.joined_at)
+		.or_else(|| members.iter().min_by_key(|m| m.joined_at))
+}
 
-	let (mut ws_s, mut ws_r) = ws.split();
-	let (ch_s, mut ch_r) = tokio::sync::mpsc::unbounded_channel();
+/// `EnableAcks`: tells the client whether its `msg` went out to the room.
+fn send_ack(ch_s: &tokio::sync::mpsc::UnboundedSender<Message>, msg: &WsMessage, nack_reason: Option<&str>) {
+	let Some(kind) = msg.ack_kind() else {
+		return;
+	};
+	let reply = match nack_reason {
+		None => WsMessage::Ack(kind.to_string()),
+		Some(reason) => WsMessage::Nack {
+			kind: kind.to_string(),
+			reason: reason.to_string(),
+		},
+	};
+	let _ = ch_s.send(reply.send_helper());
+}
 
-	tokio::spawn(async move {
-		while let Some(msg) = ch_r.recv().await {
-			let _ = ws_s.send(msg).await;
+/// Password-protected members get their own room so strangers with the same room_hash don't mix in.
+fn room_key(room_hash: String, auth_tag: &str) -> String {
+	if room_hash.is_empty() || auth_tag.is_empty() {
+		room_hash
+	} else {
+		format!("{room_hash}:{auth_tag}")
+	}
+}
+
+const ROOM_NAME_ADJECTIVES: &[&str] = &[
+	"brave", "calm", "clever", "cozy", "eager", "fancy", "gentle", "happy", "jolly", "lucky", "mighty", "quiet",
+	"rapid", "shiny", "sleepy", "witty",
+];
+const ROOM_NAME_ANIMALS: &[&str] = &[
+	"badger", "beaver", "crane", "dingo", "falcon", "ferret", "gecko", "heron", "koala", "lemur", "moose", "otter",
+	"panda", "raven", "tapir", "walrus",
+];
+
+/// Random names tried before giving up & leaving a room unnamed. There are only ~23k names.
+const SHORT_NAME_ATTEMPTS: u32 = 100;
+
+/// A "brave-otter-42" that isn't used by another room. Saved names are avoided too unless they've used up most of the names.
+/// `None` if nothing free turned up (the relay has a lot of named rooms).
+fn new_short_name(rooms: &RoomMap, saved: Option<&SavedNames>) -> Option<String> {
+	use rand::seq::SliceRandom;
+	use rand::Rng;
+	let mut rng = rand::thread_rng();
+	for attempt in 0..SHORT_NAME_ATTEMPTS {
+		let name = format!(
+			"{}-{}-{}",
+			ROOM_NAME_ADJECTIVES.choose(&mut rng).unwrap(),
+			ROOM_NAME_ANIMALS.choose(&mut rng).unwrap(),
+			rng.gen_range(10..100)
+		);
+		let saved_by_another =
+			attempt < SHORT_NAME_ATTEMPTS / 2 && saved.is_some_and(|saved| saved.key_of(&name).is_some());
+		if !saved_by_another && !short_name_in_use(&name, rooms) {
+			return Some(name);
 		}
-	});
+	}
+	warn!("no free short name after {SHORT_NAME_ATTEMPTS} tries. leaving the room without one");
+	None
+}
 
-	// Using an `Instant` instead of `intervals_since_last_pong` because it's less prone to breaking in case the interval duration is ever changed for some reason.
-	let mut last_pong_time = std::time::Instant::now();
+fn short_name_in_use(name: &str, rooms: &RoomMap) -> bool {
+	rooms
+		.values()
+		.any(|r| r.lock().unwrap().short_name.as_deref() == Some(name))
+}
 
-	let mut interval = tokio::time::interval(Duration::from_secs(1));
-	loop {
-		tokio::select! {
-			_ = interval.tick() => {
-				let now = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
-				ch_s.send(WsMessage::Ping(now).send_helper())?;
+/// The name `--room-names-file` has for the room (if nobody else has it now) or a new one.
+fn short_name_for(key: &str, rooms: &RoomMap, saved: Option<&Mutex<SavedNames>>) -> Option<String> {
+	let Some(saved) = saved else {
+		return new_short_name(rooms, None);
+	};
+	let mut saved = saved.lock().unwrap();
+	let name = match saved.name_of(key) {
+		Some(name) if !short_name_in_use(name, rooms) => name.to_string(),
+		_ => new_short_name(rooms, Some(&saved))?,
+	};
+	saved.remember(key, &name, Utc::now().timestamp());
+	Some(name)
+}
 
-				if last_pong_time.elapsed() > Duration::from_secs(10) {
-					anyhow::bail!("client {id} hasn't pong'd for 10s and probably lost connection."); // anyhow::bail!() will return btw...
-				}
-			}
-			msg = ws_r.next() => {
-				let Some(msg) = msg else { return Ok(()); };
-				let msg = msg?.into_text()?;
-				let Ok(msg) = serde_json::from_str(&msg) else {
-					//debug!("unknown message from client {id} msg = {msg}");
-					continue;
-				};
-				match msg {
-					WsMessage::Ping(_) | WsMessage::Pong(_) => (),
-					_ => debug!("recv msg = {msg:?}")
-				}
-				match msg {
-					WsMessage::Info(_) => {
-						// Could be a more strongly-typed info message via json+serde but it doesn't really matter.
-						let s = format!("version {} repo {}", env!("CARGO_PKG_VERSION"), REPO_URL.get().unwrap());
-						let _ = ch_s.send(WsMessage::Info(s).send_helper());
-					}
-					WsMessage::Join(ref new_room) => {
-						if new_room.as_str() == current_room {
-							continue;
-						}
+/// `Join`ing with a short name puts you in the room that has it. Anything else is already a room key.
+fn resolve_short_name(room_hash: String, rooms: &RoomMap, saved: Option<&Mutex<SavedNames>>) -> String {
+	if let Some(key) = rooms
+		.iter()
+		.find(|(_, r)| r.lock().unwrap().short_name.as_ref() == Some(&room_hash))
+		.map(|(key, _)| key.clone())
+	{
+		return key;
+	}
+	// The room emptied out (or the relay restarted) but `--room-names-file` still knows the name.
+	saved
+		.and_then(|saved| saved.lock().unwrap().key_of(&room_hash).map(str::to_string))
+		.unwrap_or(room_hash)
+}
 
-						let mut rooms = rooms.lock().unwrap();
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct SavedName {
+	name: String,
+	// Unix timestamp.
+	last_used: i64,
+}
 
-						let me = if current_room == "" {
-							Member {
-								id,
-								ping,
-								sender: ch_s.clone(),
-							}
-						} else {
-							remove_from_room(id, current_room, rooms.deref_mut())
-						};
+/// `--room-names-file`: room key -> short name. Names that go unused for `expiry` are forgotten.
+#[derive(Debug)]
+struct SavedNames {
+	path: std::path::PathBuf,
+	expiry: Duration,
+	names: HashMap<String, SavedName>,
+}
 
-						if new_room != "" {
-							let room = rooms.entry(new_room.clone()).or_default();
-							room.members.push(me);
-							let len = room.members.len();
-							let msg = WsMessage::Party(len as u32).send_helper();
-							for member in &room.members {
-								let _ = member.sender.send(msg.clone());
-							}
-						}
+impl SavedNames {
+	/// A missing file is the same as an empty one.
+	fn load(path: std::path::PathBuf, expiry: Duration) -> anyhow::Result<Self> {
+		let names = match std::fs::read_to_string(&path) {
+			Ok(json) => serde_json::from_str(&json).with_context(|| format!("couldn't parse {}", path.display()))?,
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+			Err(e) => return Err(anyhow::Error::new(e).context(format!("couldn't read {}", path.display()))),
+		};
+		let mut saved = SavedNames { path, expiry, names };
+		saved.expire(Utc::now().timestamp());
+		Ok(saved)
+	}
 
-						current_room.clone_from(new_room);
-					}
-					WsMessage::Party(_) => { /* we shouldn't be receiving this */ }
-					WsMessage::Resume => {
-						if current_room == "" {
-							continue;
-						}
+	fn expire(&mut self, now: i64) {
+		let expiry = self.expiry.as_secs() as i64;
+		self.names.retain(|_, saved| now - saved.last_used <= expiry);
+	}
 
-						let msg = WsMessage::Resume.send_helper();
+	fn name_of(&self, key: &str) -> Option<&str> {
+		self.names.get(key).map(|saved| saved.name.as_str())
+	}
 
-						let mut rooms = rooms.lock().unwrap();
-						let room = rooms.get_mut(current_room).unwrap();
+	fn key_of(&self, name: &str) -> Option<&str> {
+		self.names
+			.iter()
+			.find(|(_, saved)| saved.name == name)
+			.map(|(key, _)| key.as_str())
+	}
 
-						// We can reach this with pause mismatches and shit...
-						if let Some(queued) = room.queued_resumes.borrow_mut() {
-							while queued.try_join_next().is_some() {}
-							if queued.is_empty() {
-								room.queued_resumes = None;
-							}
-						}
+	/// Also takes the name away from any other room that had it.
+	fn remember(&mut self, key: &str, name: &str, now: i64) {
+		self.names.retain(|k, saved| saved.name != name || k == key);
+		let _ = self.names.insert(
+			key.to_string(),
+			SavedName {
+				name: name.to_string(),
+				last_used: now,
+			},
+		);
+	}
+}
 
-						// An existing queue is occuring and we probably shouldn't hit this but...
-						if room.queued_resumes.is_some() {
-							continue;
-						}
+/// `--audit-log`: one JSON line per message a member sends, with what kind it was but never what's in it.
+/// Moved to `<path>.1` (replacing the last one) once it reaches `max_size` bytes.
+struct AuditLog {
+	path: std::path::PathBuf,
+	file: std::fs::File,
+	size: u64,
+	max_size: u64,
+}
 
-						let highest_ping = room
-							.members
-							.iter()
-							.map(|m| m.ping)
-							.max_by(|a, b| a.total_cmp(b))
-							.unwrap();
+impl AuditLog {
+	fn open(path: std::path::PathBuf, max_size: u64) -> anyhow::Result<Self> {
+		let file = std::fs::OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(&path)
+			.with_context(|| format!("couldn't open {}", path.display()))?;
+		let size = file.metadata()?.len();
+		Ok(AuditLog {
+			path,
+			file,
+			size,
+			max_size,
+		})
+	}
 
-						let mut set = tokio::task::JoinSet::new();
-						for member in &room.members {
-							// let id = member.id;
-							let sender = member.sender.clone();
-							let delay = Duration::from_secs_f64(highest_ping - member.ping);
-							let msg = msg.clone();
-							set.spawn(async move {
-								if !delay.is_zero() {
-									tokio::time::sleep(delay).await;
-								}
-								let _ = sender.send(msg);
-							});
-						}
-						room.queued_resumes = Some(set);
-					}
-					WsMessage::AbsoluteSeek(t) => {
-						if current_room == "" {
-							continue;
-						}
+	fn write(&mut self, line: &str) -> std::io::Result<()> {
+		use std::io::Write;
+		let len = line.len() as u64 + 1;
+		if self.size > 0 && self.size + len > self.max_size {
+			let mut old = self.path.clone().into_os_string();
+			old.push(".1");
+			std::fs::rename(&self.path, old)?;
+			*self = AuditLog::open(self.path.clone(), self.max_size).map_err(std::io::Error::other)?;
+		}
+		self.file.write_all(format!("{line}\n").as_bytes())?;
+		self.size += len;
+		Ok(())
+	}
+}
 
-						let msg = WsMessage::AbsoluteSeek(t).send_helper();
+/// Only the room hash. Not the `Join2` auth tag that's part of password-protected room keys.
+fn audit_line(kind: &str, room_key: &str, id: u64, now: DateTime<Utc>) -> String {
+	serde_json::json!({
+		"time": now.to_rfc3339_opts(SecondsFormat::Millis, true),
+		"kind": kind,
+		"room": room_key.split(':').next().unwrap_or_default(),
+		"member": id,
+	})
+	.to_string()
+}
 
-						let mut rooms = rooms.lock().unwrap();
-						let room = rooms.get_mut(current_room).unwrap();
-						drop(room.queued_resumes.take()); // abort queued resumes...
+/// Writes `--room-names-file`. Rooms that still exist count as using their names.
+async fn save_room_names(rooms: &Rooms, saved: &Mutex<SavedNames>) {
+	let live: Vec<(String, String)> = rooms
+		.lock()
+		.unwrap()
+		.iter()
+		.filter_map(|(key, room)| Some((key.clone(), room.lock().unwrap().short_name.clone()?)))
+		.collect();
+	let (path, json) = {
+		let mut saved = saved.lock().unwrap();
+		let now = Utc::now().timestamp();
+		for (key, name) in &live {
+			saved.remember(key, name, now);
+		}
+		saved.expire(now);
+		(saved.path.clone(), serde_json::to_string(&saved.names).unwrap())
+	};
+	// Renamed into place so a crash mid-write doesn't lose every name.
+	let tmp = path.with_extension("tmp");
+	let res = async {
+		tokio::fs::write(&tmp, json).await?;
+		tokio::fs::rename(&tmp, &path).await
+	}
+	.await;
+	if let Err(e) = res {
+		warn!("couldn't save room names to {}: {e}", path.display());
+	}
+}
 
-						for member in &room.members {
-							// NOTE: We might need to send the seek to the same user that sent the seek.
-							// It can be a bit desynced if we don't...
-							// It depends on if we have a sleep in the Event::Seek though... BROCCOLI
-							if member.id != id {
-								let _ = member.sender.send(msg.clone());
-							}
-						}
-					}
-					WsMessage::Ping(_) => { /* we shouldn't be recieving this */ }
-					WsMessage::Pong(ref s) => {
-						let elapsed = Utc::now()
-							.signed_duration_since(DateTime::parse_from_rfc3339(s)?)
-							.to_std()?
-							.as_secs_f64();
-						ping = elapsed / 2.0;
-						//debug!("  ping = {ping}s");
+/// Puts `me` into the slot they left when their connection dropped. Nobody else hears about it.
+/// Returns `me` back if there's no slot to take.
+fn rejoin_room(me: Member, new_room: &str, rooms: &RoomMap) -> Option<Member> {
+	let Some(room) = rooms.get(new_room) else {
+		return Some(me);
+	};
+	let mut room = room.lock().unwrap();
+	let room = &mut *room;
+	let Some(slot) = room
+		.members
+		.iter_mut()
+		.find(|m| m.disconnected && m.session.is_some() && m.session == me.session)
+	else {
+		return Some(me);
+	};
+	let old_id = slot.id;
+	*slot = Member {
+		joined_at: slot.joined_at,
+		..me
+	};
+	if room.leader == Some(old_id) {
+		room.leader = Some(slot.id);
+	}
+	let id = slot.id;
+	let sender = slot.sender.clone();
+	room.stop_buffering(old_id);
 
-						last_pong_time = std::time::Instant::now();
+	let room_name = room.short_name.clone().unwrap_or_default();
+	let leader = room.leader.unwrap();
+	let leader_name = room.members.iter().find(|m| m.id == leader).unwrap().name.clone();
+	let _ = sender.send(WsMessage::RoomName(room_name).send_helper());
+	let _ = sender.send(WsMessage::LeaderChanged(leader_name).send_helper());
+	let me = room.members.iter().find(|m| m.id == id).unwrap();
+	let _ = sender.send(party_for(&room.members, me).send_helper());
+	let _ = sender.send(roster(&room.members).send_helper());
+	info!(client_id = id, room = new_room, event = "rejoin", old_client_id = old_id; "client {id} took back client {old_id}'s slot in room {new_room}");
+	None
+}
 
-						if current_room != "" {
-							let mut rooms = rooms.lock().unwrap();
-							let room = rooms.get_mut(current_room).unwrap();
-							room.members.iter_mut().find(|m| m.id == id).unwrap().ping = ping;
-						}
-					}
-				}
+/// Adds `me` to `new_room` (creating it if needed) & tells everyone.
+fn join_room(me: Member, new_room: &str, rooms: &mut RoomMap, settings: &RelaySettings) {
+	let Some(me) = rejoin_room(me, new_room, rooms) else {
+		return;
+	};
+	let id = me.id;
+	if !rooms.contains_key(new_room) {
+		// Password rooms stay unlisted.
+		let short_name = (settings.short_room_names && !new_room.contains(':'))
+			.then(|| short_name_for(new_room, rooms, settings.saved_names.as_deref()))
+			.flatten();
+		let _ = rooms.insert(
+			new_room.to_string(),
+			Arc::new(Mutex::new(Room {
+				short_name,
+				..Default::default()
+			})),
+		);
+	}
+	let mut room = rooms[new_room].lock().unwrap();
+	let room = &mut *room;
+	let room_name = room.short_name.clone().unwrap_or_default();
+	let _ = me.sender.send(WsMessage::RoomName(room_name).send_helper());
+	let observer_leads = room
+		.leader
+		.is_some_and(|leader| room.members.iter().any(|m| m.id == leader && m.observer));
+	if observer_leads && !me.observer {
+		room.leader = Some(id);
+		let msg = WsMessage::LeaderChanged(me.name.clone()).send_helper();
+		for member in &room.members {
+			let _ = member.sender.send(msg.clone());
+		}
+	}
+	let leader = *room.leader.get_or_insert(id);
+	let leader_name = if leader == id {
+		me.name.clone()
+	} else {
+		room.members.iter().find(|m| m.id == leader).unwrap().name.clone()
+	};
+	let _ = me.sender.send(WsMessage::LeaderChanged(leader_name).send_helper());
+	let observer = me.observer;
+	room.members.push(Member {
+		joined_at: std::time::Instant::now(),
+		..me
+	});
+	room.peak_members = room.peak_members.max(room.members.len());
+	if observer {
+		let me = room.members.last().unwrap();
+		let _ = me.sender.send(party_for(&room.members, me).send_helper());
+		broadcast_roster(&room.members);
+	} else {
+		broadcast_party(&room.members);
+	}
+	info!(client_id = id, room = new_room, event = "join", members = room.members.len(); "client {id} joined room {new_room} ({} members)", room.members.len());
+}
+
+/// `MigrateRoom`: moves everyone in `from` to `to` (alongside anyone already there) under one `rooms` lock,
+/// so nobody's left behind (older clients don't know `MigrateRoom`) & nobody can join `from` halfway through.
+/// Held slots are dropped since their connections are gone.
+fn migrate_room(from: &str, to: &str, rooms: &mut RoomMap, settings: &RelaySettings) {
+	let Some(room) = rooms.remove(from) else {
+		return;
+	};
+	let (mut members, leader) = {
+		let mut room = room.lock().unwrap();
+		(std::mem::take(&mut room.members), room.leader)
+	};
+	// The leader first & then by join order so the same person leads if `to` is a new room.
+	members.sort_by_key(|m| (Some(m.id) != leader, m.joined_at));
+	for member in members.into_iter().filter(|m| !m.disconnected) {
+		*member.moved_to.lock().unwrap() = Some(to.to_string());
+		join_room(member, to, rooms, settings);
+	}
+}
+
+/// Gives the room `title` if it doesn't have one yet.
+fn share_title(title: &Option<String>, room_key: &str, rooms: &RoomMap) {
+	let (Some(title), Some(room)) = (title, rooms.get(room_key)) else {
+		return;
+	};
+	let mut room = room.lock().unwrap();
+	let room = &mut *room;
+	let title = room.title.get_or_insert_with(|| title.clone());
+	info!(room = room_key, event = "title", members = room.members.len(); "{} people watching {title} in room {room_key}", room.members.len());
+}
+
+/// "nickname (version)" for the roster & logs.
+fn member_name(nickname: &str, version: &str) -> String {
+	let nickname: String = nickname.trim().chars().filter(|c| !c.is_control()).take(32).collect();
+	let nickname = if nickname.is_empty() { "anonymous" } else { &nickname };
+	format!("{nickname} ({version})")
+}
+
+/// `Nick`: tells the room member `id` is now `new_name`.
+fn rename_member(id: u64, new_name: &str, current_room: &str, rooms: &RoomMap) {
+	let Some(room) = rooms.get(current_room) else {
+		return;
+	};
+	let mut room = room.lock().unwrap();
+	let Some(me) = room.members.iter_mut().find(|m| m.id == id) else {
+		return;
+	};
+	let old = std::mem::replace(&mut me.name, new_name.to_string());
+	let renamed = WsMessage::NickChanged {
+		old,
+		new: new_name.to_string(),
+	}
+	.send_helper();
+	for member in &room.members {
+		let _ = member.sender.send(renamed.clone());
+	}
+	broadcast_roster(&room.members);
+}
+
+/// `None` if they're not in `current_room` anymore.
+fn remove_from_room(id: u64, current_room: &String, rooms: &mut RoomMap) -> Option<Member> {
+	let room = rooms.get(current_room)?.clone();
+	let mut room = room.lock().unwrap();
+	let room = &mut *room;
+	let i = room.members.iter().position(|m| m.id == id)?;
+	let me = room.members.swap_remove(i);
+	if room.members.is_empty() {
+		let _ = rooms.remove(current_room);
+		let duration = room.created_at.elapsed().as_secs();
+		info!(
+			room = current_room.as_str(),
+			event = "room_closed",
+			duration_secs = duration,
+			peak_members = room.peak_members,
+			messages_relayed = room.messages_relayed;
+			"room {current_room} closed after {duration}s (peak {} members, {} messages relayed)",
+			room.peak_members,
+			room.messages_relayed
+		);
+	} else {
+		if me.observer {
+			broadcast_roster(&room.members);
+		} else {
+			broadcast_party(&room.members);
+		}
+		let left = WsMessage::MemberLeft(me.name.clone()).send_helper();
+		for member in &room.members {
+			let _ = member.sender.send(left.clone());
+		}
+		room.stop_buffering(id);
+		if room.leader == Some(id) {
+			// `members` isn't in join order because of `swap_remove()`.
+			let new_leader = next_leader(&room.members).unwrap();
+			room.leader = Some(new_leader.id);
+			let msg = WsMessage::LeaderChanged(new_leader.name.clone()).send_helper();
+			for member in &room.members {
+				let _ = member.sender.send(msg.clone());
 			}
 		}
 	}
+	Some(me)
 }
 
-async fn async_server(addr: std::net::SocketAddr) -> anyhow::Result<()> {
-	let listener = tokio::net::TcpListener::bind(addr).await?;
-	info!("listening on {addr}");
+/// Removes members that haven't pong'd for longer than `older_than`.
+/// `handle_client()` gives up on them at `--ping-timeout` by itself so these are from connections that went away without cleaning up.
+fn reap_stale_members(rooms: &mut RoomMap, older_than: Duration) -> usize {
+	let stale: Vec<(String, u64)> = rooms
+		.iter()
+		.flat_map(|(key, room)| {
+			room.lock()
+				.unwrap()
+				.members
+				.iter()
+				.filter(|m| m.last_pong.elapsed() > older_than)
+				.map(|m| (key.clone(), m.id))
+				.collect::<Vec<_>>()
+		})
+		.collect();
+	for (room, id) in &stale {
+		let Some(me) = remove_from_room(*id, room, rooms) else {
+			continue;
+		};
+		warn!(client_id = id, room = room.as_str(), event = "reaped"; "reaped client {id} from room {room}. last pong was {:?} ago", me.last_pong.elapsed());
+		// Hang up on them like `kick_member()` does in case their connection's still around & thinks it's in the room.
+		me.kick.notify_one();
+	}
+	stale.len()
+}
 
-	let rooms: Rooms = Default::default();
-	let mut latest_id = 0;
-	let connected_counter = Arc::new(());
+struct HttpRequest {
+	method: String,
+	path: String,
+	query: String,
+	head_len: usize,
+	content_length: usize,
+}
 
+/// Returns the request if the connection is a plain HTTP request instead of a websocket upgrade.
+async fn peek_http_request(stream: &tokio::net::TcpStream) -> anyhow::Result<Option<HttpRequest>> {
+	let mut buf = [0u8; 2048];
+	let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
 	loop {
-		if let Ok((stream, addr)) = listener.accept().await {
-			latest_id += 1;
-			let rooms = rooms.clone();
-			let num_connected = Arc::strong_count(&connected_counter);
-			info!("accepted client {latest_id} {addr} ({num_connected} clients connected)");
-			tokio::spawn(handle_websocket(
-				stream,
-				latest_id,
-				addr,
-				rooms,
-				connected_counter.clone(),
-			));
+		let n = tokio::time::timeout_at(deadline, stream.peek(&mut buf)).await??;
+		if n == 0 {
+			anyhow::bail!("connection closed before sending a request");
+		}
+		// Find the end in the raw bytes since lossy decoding can shift offsets around.
+		let Some(end) = buf[..n].windows(4).position(|w| w == b"\r\n\r\n") else {
+			if n == buf.len() {
+				// Too big for us. Let tungstenite deal with it.
+				return Ok(None);
+			}
+			// peek() doesn't wait for *more* data so give the rest of the request some time to show up.
+			tokio::time::sleep(Duration::from_millis(10)).await;
+			continue;
+		};
+		let head = String::from_utf8_lossy(&buf[..end]);
+		let is_upgrade = head.lines().skip(1).any(|line| {
+			let line = line.to_ascii_lowercase();
+			line.starts_with("upgrade:") && line.contains("websocket")
+		});
+		if is_upgrade {
+			return Ok(None);
 		}
+		let mut request_line = head.split_whitespace();
+		let method = request_line.next().unwrap_or("GET").to_string();
+		let target = request_line.next().unwrap_or("/");
+		let (path, query) = target.split_once('?').unwrap_or((target, ""));
+		let content_length = head
+			.lines()
+			.skip(1)
+			.find_map(|line| {
+				let (name, value) = line.split_once(':')?;
+				name.eq_ignore_ascii_case("content-length")
+					.then(|| value.trim().parse().ok())?
+			})
+			.unwrap_or(0);
+		return Ok(Some(HttpRequest {
+			method,
+			path: path.to_string(),
+			query: query.to_string(),
+			head_len: end + 4,
+			content_length,
+		}));
 	}
 }
 
-pub fn server(
-	verbosity: log::LevelFilter,
-	bind_address: std::net::IpAddr,
-	bind_port: u16,
-	repo_url: &http::Uri,
+/// `/health` for load balancers, `/metrics` for Prometheus, and the repo URL for everything else (AGPL reasons).
+async fn serve_http(
+	stream: &mut tokio::net::TcpStream,
+	request: &HttpRequest,
+	rooms: &Rooms,
+	num_connected: usize,
+	settings: &RelaySettings,
 ) -> anyhow::Result<()> {
-	let verbosity = if true { log::LevelFilter::Debug } else { verbosity };
-	flexi_logger::Logger::with(
-		flexi_logger::LogSpecification::builder()
-			.default(verbosity)
-			.module("rustls", log::LevelFilter::Warn)
-			.module("tokio_tungstenite", log::LevelFilter::Warn)
-			.module("tungstenite", log::LevelFilter::Warn)
-			.build(),
-	)
-	.format(flexi_logger::colored_default_format)
-	.start()?;
+	// Eat the request so closing the socket doesn't RST the response away.
+	stream.read_exact(&mut vec![0u8; request.head_len]).await?;
 
-	let _ = REPO_URL.get_or_init(|| repo_url.clone());
-	let addr = std::net::SocketAddr::new(bind_address, bind_port);
-	let rt = tokio::runtime::Runtime::new()?;
-	rt.block_on(async move { async_server(addr).await })
+	let body = match request.path.as_str() {
+		// `ends_with()` so it still works behind a reverse proxy path like `/simulcast-mpv/servers.txt`.
+		path if path.ends_with("/servers.txt") && settings.discovery_file.is_some() => {
+			match tokio::fs::read_to_string(settings.discovery_file.as_ref().unwrap()).await {
+				Ok(list) => list,
+				Err(e) => {
+					warn!("couldn't read --discovery-file: {e}");
+					return write_http_response(stream, "500 Internal Server Error", "").await;
+				}
+			}
+		}
+		"/health" => "ok\n".to_string(),
+		// Only rooms where someone used `--share-title`.
+		"/now-playing" => {
+			let rooms = rooms.lock().unwrap();
+			let mut playing: Vec<_> = rooms
+				.values()
+				.filter_map(|room| {
+					let room = room.lock().unwrap();
+					Some((room.members.len(), room.title.clone()?))
+				})
+				.collect();
+			playing.sort_unstable_by(|a, b| b.cmp(a));
+			playing
+				.into_iter()
+				.map(|(members, title)| format!("{members} watching {title}\n"))
+				.collect()
+		}
+		"/metrics" => {
+			let num_rooms = rooms.lock().unwrap().len();
+			let rate_limited = RATE_LIMITED_TOTAL.load(std::sync::atomic::Ordering::Relaxed);
+			format!(
+				"# HELP simulcast_rooms Number of active rooms.\n\
+				 # TYPE simulcast_rooms gauge\n\
+				 simulcast_rooms {num_rooms}\n\
+				 # HELP simulcast_connected_clients Number of connected clients.\n\
+				 # TYPE simulcast_connected_clients gauge\n\
+				 simulcast_connected_clients {num_connected}\n\
+				 # HELP simulcast_rate_limited_total Number of clients disconnected by --rate-limit.\n\
+				 # TYPE simulcast_rate_limited_total counter\n\
+				 simulcast_rate_limited_total {rate_limited}\n"
+			)
+		}
+		_ => format!(
+			"simulcast-mpv relay version {} repo {}\n",
+			env!("CARGO_PKG_VERSION"),
+			settings.repo_url
+		),
+	};
+	write_http_response(stream, "200 OK", &body).await
+}
+
+async fn write_http_response(stream: &mut tokio::net::TcpStream, status: &str, body: &str) -> anyhow::Result<()> {
+	let response = format!(
+		"HTTP/1.1 {status}\r\ncontent-type: text/plain; charset=utf-8\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+		body.len()
+	);
+	stream.write_all(response.as_bytes()).await?;
+	stream.shutdown().await?;
+	Ok(())
+}
+
+/// The fallback for clients that can't use websockets. Bodies are the same `WsMessage` JSON, one per line.
+/// - `POST .../longpoll/connect` -> a session id for the other endpoints
+/// - `POST .../longpoll/send?session=<id>` -> messages from the client
+/// - `GET .../longpoll/recv?session=<id>` -> messages for the client (waits up to `LONGPOLL_WAIT` for some)
+/// - `POST .../longpoll/close?session=<id>`
+#[allow(clippy::too_many_arguments)]
+async fn serve_longpoll(
+	stream: &mut tokio::net::TcpStream,
+	request: &HttpRequest,
+	id: u64,
+	addr: std::net::SocketAddr,
+	rooms: &Rooms,
+	sessions: &LongPollSessions,
+	connected_counter: &Arc<()>,
+	settings: &Arc<RelaySettings>,
+) -> anyhow::Result<()> {
+	// `/longpoll/send` bodies are one `WsMessage` so they get the same limit as websocket messages.
+	let max_body = settings.websocket.max_message_size.unwrap_or(DEFAULT_MAX_MESSAGE_SIZE);
+	if request.content_length > max_body {
+		return write_http_response(stream, "413 Payload Too Large", "").await;
+	}
+	let mut buf = vec![0u8; request.head_len + request.content_length];
+	stream.read_exact(&mut buf).await?;
+	let body = String::from_utf8_lossy(&buf[request.head_len..]);
+
+	let session = request
+		.query
+		.split('&')
+		.find_map(|kv| kv.strip_prefix("session="))
+		.unwrap_or_default();
+	let endpoint = request.path.rsplit('/').next().unwrap_or_default();
+
+	let gone = ("404 Not Found", "no such session\n".to_string());
+	let (status, body) = match (request.method.as_str(), endpoint) {
+		("POST", "connect") => (
+			"200 OK",
+			longpoll_connect(id, addr, rooms, sessions, connected_counter, settings),
+		),
+		("POST", "send") => match sessions.lock().unwrap().get(session) {
+			Some(s) => {
+				for line in body.lines().filter(|line| !line.is_empty()) {
+					let _ = s.incoming.unbounded_send(Message::text(line));
+				}
+				("200 OK", String::new())
+			}
+			None => gone,
+		},
+		("GET", "recv") => {
+			let outgoing = sessions.lock().unwrap().get(session).map(|s| s.outgoing.clone());
+			match outgoing {
+				Some(outgoing) => match longpoll_recv(&outgoing).await {
+					Some(messages) => ("200 OK", messages),
+					None => gone,
+				},
+				None => gone,
+			}
+		}
+		("POST", "close") => {
+			// The session's task notices its `incoming` closed & cleans up the rest.
+			let _ = sessions.lock().unwrap().remove(session);
+			("200 OK", String::new())
+		}
+		_ => ("404 Not Found", "not found\n".to_string()),
+	};
+	write_http_response(stream, status, &body).await
+}
+
+/// Starts handling a long-polling client like a websocket one. Returns the session id.
+fn longpoll_connect(
+	id: u64,
+	addr: std::net::SocketAddr,
+	rooms: &Rooms,
+	sessions: &LongPollSessions,
+	connected_counter: &Arc<()>,
+	settings: &Arc<RelaySettings>,
+) -> String {
+	let session = format!("{:032x}", rand::random::<u128>());
+	let (incoming_s, incoming_r) = futures::channel::mpsc::unbounded();
+	let (ch_s, ch_r) = tokio::sync::mpsc::unbounded_channel();
+	let _ = sessions.lock().unwrap().insert(
+		session.clone(),
+		LongPollSession {
+			incoming: incoming_s,
+			outgoing: Arc::new(tokio::sync::Mutex::new(ch_r)),
+		},
+	);
+	info!(client_id = id, addr:% = addr, event = "longpoll"; "client {id} {addr} is long-polling");
+
+	let rooms = rooms.clone();
+	let sessions = sessions.clone();
+	let (connected_counter, settings) = (connected_counter.clone(), settings.clone());
+	let session_id = session.clone();
+	tokio::spawn(async move {
+		let mut current_room = String::new();
+		let ret = handle_client(
+			incoming_r.map(Ok),
+			ch_s,
+			id,
+			&mut current_room,
+			rooms.clone(),
+			&Arc::downgrade(&connected_counter),
+			&settings,
+		)
+		.await;
+		let _ = sessions.lock().unwrap().remove(&session_id);
+		client_finished(id, &addr.to_string(), &current_room, &rooms, &connected_counter, &ret);
+	});
+	session
+}
+
+/// Starts a connection's session for `room_hash`: a separate member that shares the connection.
+/// Everything it's sent is wrapped in `InRoom` with the same `room_hash`.
+fn spawn_room_session(
+	parent_id: u64,
+	room_hash: &str,
+	ch_s: &tokio::sync::mpsc::UnboundedSender<Message>,
+	rooms: &Rooms,
+	connected_counter: &Weak<()>,
+	settings: &Arc<RelaySettings>,
+) -> futures::channel::mpsc::UnboundedSender<Message> {
+	let id = LATEST_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed) + 1;
+	let (incoming_s, incoming_r) = futures::channel::mpsc::unbounded();
+	let (session_s, mut session_r) = tokio::sync::mpsc::unbounded_channel();
+	info!(client_id = id, parent_client_id = parent_id, event = "room_session"; "client {parent_id} started session {id} for room {room_hash}");
+
+	let tag = serde_json::to_string(room_hash).unwrap();
+	let ch_s = ch_s.clone();
+	tokio::spawn(async move {
+		while let Some(msg) = session_r.recv().await {
+			// A `Close` (rate limited, etc) only ends the session. Not the whole connection.
+			if let Message::Text(text) = msg {
+				let wrapped = format!(r#"{{"InRoom":{{"room_hash":{tag},"msg":{}}}}}"#, text.as_str());
+				if ch_s.send(Message::text(wrapped)).is_err() {
+					break;
+				}
+			}
+		}
+	});
+
+	let rooms = rooms.clone();
+	let (connected_counter, settings) = (connected_counter.clone(), settings.clone());
+	tokio::spawn(async move {
+		let mut current_room = String::new();
+		let ret = handle_client(
+			incoming_r.map(Ok),
+			session_s,
+			id,
+			&mut current_room,
+			rooms.clone(),
+			&connected_counter,
+			&settings,
+		)
+		.await;
+		let addr = format!("(client {parent_id}'s session)");
+		// Only gone once the relay's shutting down.
+		if let Some(connected_counter) = connected_counter.upgrade() {
+			client_finished(id, &addr, &current_room, &rooms, &connected_counter, &ret);
+		}
+	});
+	incoming_s
+}
+
+/// Waits for messages for a long-polling client. `None` once its session is over.
+async fn longpoll_recv(outgoing: &tokio::sync::Mutex<tokio::sync::mpsc::UnboundedReceiver<Message>>) -> Option<String> {
+	let mut outgoing = outgoing.lock().await;
+	let mut next = match tokio::time::timeout(LONGPOLL_WAIT, outgoing.recv()).await {
+		Ok(Some(msg)) => Some(msg),
+		Ok(None) => return None,
+		Err(_) => return Some(String::new()),
+	};
+	let mut messages = String::new();
+	while let Some(msg) = next {
+		// Skips the `Close` that comes with `Incompatible`. The session ends right after anyway.
+		if let Message::Text(text) = msg {
+			messages.push_str(text.as_str());
+			messages.push('\n');
+		}
+		next = outgoing.try_recv().ok();
+	}
+	Some(messages)
+}
+
+async fn handle_tcp(
+	mut stream: tokio::net::TcpStream,
+	id: u64,
+	addr: std::net::SocketAddr,
+	rooms: Rooms,
+	sessions: LongPollSessions,
+	connected_counter: Arc<()>,
+	settings: Arc<RelaySettings>,
+) -> anyhow::Result<()> {
+	if let Some(request) = peek_http_request(&stream).await? {
+		let ret = if request.path.contains("/longpoll/") {
+			serve_longpoll(
+				&mut stream,
+				&request,
+				id,
+				addr,
+				&rooms,
+				&sessions,
+				&connected_counter,
+				&settings,
+			)
+			.await
+		} else {
+			let num_connected = Arc::strong_count(&connected_counter) - 2; // -1 for ourself & -1 for the original
+			serve_http(&mut stream, &request, &rooms, num_connected, &settings).await
+		};
+		debug!(client_id = id, addr:% = addr, event = "http"; "served http '{} {}' to client {id} {addr} {ret:?}", request.method, request.path);
+		return ret;
+	}
+
+	handle_websocket(stream, id, addr.to_string(), rooms, connected_counter, settings).await
+}
+
+/// `--tls-cert`: websockets only. The HTTP endpoints peek at the request, which doesn't work through TLS.
+async fn handle_tls(
+	stream: tokio::net::TcpStream,
+	acceptor: tokio_rustls::TlsAcceptor,
+	id: u64,
+	addr: std::net::SocketAddr,
+	rooms: Rooms,
+	connected_counter: Arc<()>,
+	settings: Arc<RelaySettings>,
+) -> anyhow::Result<()> {
+	let stream = match tokio::time::timeout(Duration::from_secs(5), acceptor.accept(stream)).await {
+		Ok(Ok(stream)) => stream,
+		Ok(Err(e)) => {
+			debug!(client_id = id, addr:% = addr, event = "tls_failed"; "tls handshake with client {id} {addr} failed: {e}");
+			return Err(e.into());
+		}
+		Err(_) => anyhow::bail!("client {id} {addr} took too long for the tls handshake"),
+	};
+	handle_websocket(stream, id, addr.to_string(), rooms, connected_counter, settings).await
+}
+
+async fn handle_websocket<S>(
+	stream: S,
+	id: u64,
+	addr: String,
+	rooms: Rooms,
+	connected_counter: Arc<()>,
+	settings: Arc<RelaySettings>,
+) -> anyhow::Result<()>
+where
+	S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+	let mut current_room = String::new();
+	let ret = handle_websocket_inner(
+		stream,
+		id,
+		&mut current_room,
+		rooms.clone(),
+		&connected_counter,
+		&settings,
+	)
+	.await;
+	client_finished(id, &addr, &current_room, &rooms, &connected_counter, &ret);
+	ret
+}
+
+/// Takes a disconnected client out of its room.
+fn client_finished(
+	id: u64,
+	addr: &str,
+	current_room: &String,
+	rooms: &Rooms,
+	connected_counter: &Arc<()>,
+	ret: &anyhow::Result<()>,
+) {
+	let mut rooms_guard = rooms.lock().unwrap();
+	// A `MigrateRoom` could've moved us since our last message.
+	let current_room = &match rooms_guard.get(current_room) {
+		Some(room) if room.lock().unwrap().members.iter().any(|m| m.id == id) => current_room.clone(),
+		_ => rooms_guard
+			.iter()
+			.find(|(_, room)| room.lock().unwrap().members.iter().any(|m| m.id == id))
+			.map(|(key, _)| key.clone())
+			.unwrap_or_default(),
+	};
+	let room = rooms_guard.get(current_room).cloned();
+	let mut room = room.as_ref().map(|room| room.lock().unwrap());
+	// The reaper might've beaten us to it.
+	if let Some(me) = room
+		.as_mut()
+		.and_then(|room| room.members.iter_mut().find(|m| m.id == id))
+	{
+		if me.session.is_some() {
+			// Give them a moment to reconnect before everyone's told they left.
+			me.disconnected = true;
+			let rooms = rooms.clone();
+			let current_room = current_room.clone();
+			tokio::spawn(async move {
+				tokio::time::sleep(RECONNECT_GRACE).await;
+				let mut rooms = rooms.lock().unwrap();
+				if rooms.get(&current_room).is_some_and(|room| {
+					room.lock()
+						.unwrap()
+						.members
+						.iter()
+						.any(|m| m.id == id && m.disconnected)
+				}) {
+					let _ = remove_from_room(id, &current_room, rooms.deref_mut());
+				}
+			});
+		} else {
+			drop(room);
+			let _ = remove_from_room(id, current_room, rooms_guard.deref_mut());
+		}
+	}
+	drop(rooms_guard);
+	let num_connected = Arc::strong_count(connected_counter) - 2; // -1 for ourself & -1 for the original
+	info!(client_id = id, addr:% = addr, event = "disconnect", connected = num_connected; "finished with client {id} {addr} ({num_connected} clients connected) {ret:?}");
+}
+
+async fn handle_websocket_inner<S>(
+	stream: S,
+	id: u64,
+	current_room: &mut String,
+	rooms: Rooms,
+	connected_counter: &Arc<()>,
+	settings: &Arc<RelaySettings>,
+) -> anyhow::Result<()>
+where
+	S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+	let ws = tokio_tungstenite::accept_async_with_config(stream, Some(settings.websocket)).await?;
+
+	let (mut ws_s, ws_r) = ws.split();
+	let (ch_s, mut ch_r) = tokio::sync::mpsc::unbounded_channel();
+
+	tokio::spawn(async move {
+		while let Some(msg) = ch_r.recv().await {
+			let _ = ws_s.send(msg).await;
+		}
+	});
+
+	handle_client(
+		ws_r.err_into(),
+		ch_s,
+		id,
+		current_room,
+		rooms,
+		&Arc::downgrade(connected_counter),
+		settings,
+	)
+	.await
+}
+
+/// Everything after the transport (websocket or long-poll) is setup.
+/// `incoming` is what the client sends & `ch_s` is what gets sent to the client.
+async fn handle_client<St>(
+	mut incoming: St,
+	ch_s: tokio::sync::mpsc::UnboundedSender<Message>,
+	id: u64,
+	current_room: &mut String,
+	rooms: Rooms,
+	// `Weak` so `InRoom` sessions don't count as connections.
+	connected_counter: &Weak<()>,
+	settings: &Arc<RelaySettings>,
+) -> anyhow::Result<()>
+where
+	St: futures::Stream<Item = anyhow::Result<Message>> + Unpin,
+{
+	// We still want ping calculation even when a user isn't in a room...
+	let mut ping = 0.0;
+	// Older clients don't send a nickname (or version).
+	let mut name = "anonymous (unknown version)".to_string();
+	let mut client_version = "unknown version".to_string();
+	// Older clients don't send a `Session` either.
+	let mut session = None;
+	let mut acks = false;
+	let mut observer = false;
+	// `--share-title`
+	let mut title = None;
+	let kick = Arc::new(tokio::sync::Notify::new());
+	let moved_to = Arc::new(Mutex::new(None));
+	// `InRoom` sessions by room hash. Dropping these ends the sessions.
+	let mut room_sessions: HashMap<String, futures::channel::mpsc::UnboundedSender<Message>> = HashMap::new();
+
+	// Using an `Instant` instead of `intervals_since_last_pong` because it's less prone to breaking in case the interval duration is ever changed for some reason.
+	let mut last_pong_time = std::time::Instant::now();
+
+	let rate_limit = settings.rate_limit as usize;
+	// When each message in the last `RATE_LIMIT_WINDOW` arrived.
+	let mut recent_messages = std::collections::VecDeque::new();
+	// One `Pong` per `Ping` we sent isn't rate limited. Otherwise a short --ping-interval trips --rate-limit by itself.
+	let mut unanswered_pings: u32 = 0;
+
+	let ping_settings = settings.ping;
+	let mut interval = tokio::time::interval(ping_settings.interval);
+	loop {
+		tokio::select! {
+			_ = interval.tick() => {
+				let now = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+				ch_s.send(WsMessage::Ping(now).send_helper())?;
+				unanswered_pings = unanswered_pings.saturating_add(1);
+
+				if last_pong_time.elapsed() > ping_settings.timeout {
+					anyhow::bail!("client {id} hasn't pong'd for {:?} and probably lost connection.", ping_settings.timeout); // anyhow::bail!() will return btw...
+				}
+			}
+			_ = kick.notified() => {
+				let _ = ch_s.send(Message::Close(Some(CloseFrame {
+					code: CloseCode::Policy,
+					reason: "kicked".into(),
+				})));
+				return Ok(());
+			}
+			msg = incoming.next() => {
+				let Some(msg) = msg else { return Ok(()); };
+				if let Some(new_room) = moved_to.lock().unwrap().take() {
+					*current_room = new_room;
+				}
+				let msg = msg?.into_text()?;
+				let parsed = WsMessage::parse(&msg);
+
+				if matches!(parsed, Ok(WsMessage::Pong(_))) && unanswered_pings > 0 {
+					unanswered_pings -= 1;
+				} else if rate_limit > 0 {
+					let now = std::time::Instant::now();
+					while recent_messages.front().is_some_and(|t| now.duration_since(*t) > RATE_LIMIT_WINDOW) {
+						let _ = recent_messages.pop_front();
+					}
+					recent_messages.push_back(now);
+					if recent_messages.len() > rate_limit {
+						let _ = RATE_LIMITED_TOTAL.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+						warn!(client_id = id, room = current_room.as_str(), event = "rate_limited"; "client {id} sent more than {rate_limit} messages in {RATE_LIMIT_WINDOW:?}. disconnecting");
+						let _ = ch_s.send(WsMessage::RateLimited.send_helper());
+						let _ = ch_s.send(Message::Close(Some(CloseFrame {
+							code: CloseCode::Policy,
+							reason: "rate limited".into(),
+						})));
+						return Ok(());
+					}
+				}
+
+				let msg = match parsed {
+					Ok(msg) => msg,
+					Err(ParseError::UnknownVariant(variant)) => {
+						debug!("ignoring '{variant}' message from client {id} (client is probably newer than us)");
+						continue;
+					}
+					Err(ParseError::Invalid(e)) => {
+						debug!("invalid message from client {id} ({e})");
+						continue;
+					}
+				};
+				match msg {
+					WsMessage::Ping(_) | WsMessage::Pong(_) => (),
+					_ => {
+						debug!("recv msg = {msg:?}");
+						// These are logged with the room they're going to.
+						if !matches!(msg, WsMessage::Join(_) | WsMessage::Join2 { .. } | WsMessage::MigrateRoom { .. }) {
+							settings.audit(msg.kind(), current_room, id);
+						}
+					}
+				}
+				if observer && msg.drives_playback() {
+					debug!("client {id} is an observer. not passing that on");
+					if acks {
+						send_ack(&ch_s, &msg, Some("observers can't move the room"));
+					}
+					continue;
+				}
+				match msg {
+					WsMessage::Info(_) => {
+						// Could be a more strongly-typed info message via json+serde but it doesn't really matter.
+						let s = format!("version {} repo {}", env!("CARGO_PKG_VERSION"), settings.repo_url);
+						let _ = ch_s.send(WsMessage::Info(s).send_helper());
+						// Older clients ignore this.
+						let server_info = WsMessage::ServerInfo {
+							version: env!("CARGO_PKG_VERSION").to_string(),
+							repo: settings.repo_url.to_string(),
+							uptime_secs: settings.started_at.elapsed().as_secs(),
+							connected: connected_counter.strong_count() - 1, // -1 for the original
+							rooms: rooms.lock().unwrap().len(),
+						};
+						let _ = ch_s.send(server_info.send_helper());
+					}
+					WsMessage::Join(_) | WsMessage::Join2 { .. } => {
+						let mut rooms = rooms.lock().unwrap();
+
+						let kind = msg.kind();
+						let new_room = match msg {
+							WsMessage::Join2 { room_hash, auth_tag } => room_key(room_hash, &auth_tag),
+							WsMessage::Join(room_hash) => resolve_short_name(room_hash, &rooms, settings.saved_names.as_deref()),
+							_ => unreachable!(),
+						};
+						settings.audit(kind, &new_room, id);
+
+						if new_room.as_str() == current_room {
+							continue;
+						}
+
+						// `None` if the reaper or an admin kick already took us out (& we got this before the kick).
+						let removed = (!current_room.is_empty())
+							.then(|| remove_from_room(id, current_room, rooms.deref_mut()))
+							.flatten();
+						let me = match removed {
+							Some(me) => me,
+							None => Member {
+								id,
+								ping,
+								name: name.clone(),
+								sender: ch_s.clone(),
+								joined_at: std::time::Instant::now(),
+								last_pong: last_pong_time,
+								session: session.clone(),
+								disconnected: false,
+								kick: kick.clone(),
+								observer,
+								moved_to: moved_to.clone(),
+							},
+						};
+
+						if !new_room.is_empty() {
+							join_room(me, &new_room, rooms.deref_mut(), settings);
+							share_title(&title, &new_room, rooms.deref_mut());
+						}
+
+						*current_room = new_room;
+					}
+					WsMessage::MigrateRoom { ref room_hash, ref auth_tag, .. } => {
+						if current_room.is_empty() || room_hash.is_empty() {
+							continue;
+						}
+						let new_room = room_key(room_hash.clone(), auth_tag);
+						settings.audit(msg.kind(), &new_room, id);
+						if new_room == *current_room {
+							continue;
+						}
+
+						let mut rooms = rooms.lock().unwrap();
+						let Some(room) = rooms.get(current_room.as_str()) else {
+							continue;
+						};
+						{
+							let mut room = room.lock().unwrap();
+							room.messages_relayed += 1;
+							// So newer clients know the new room code. Their `Join` for it that follows is a no-op.
+							let forward = msg.send_helper();
+							for member in room.members.iter().filter(|m| m.id != id) {
+								let _ = member.sender.send(forward.clone());
+							}
+						}
+						info!(client_id = id, room = current_room.as_str(), event = "migrate"; "client {id} is moving room {current_room} to {new_room}");
+						migrate_room(current_room, &new_room, rooms.deref_mut(), settings);
+						share_title(&title, &new_room, rooms.deref_mut());
+						let _ = moved_to.lock().unwrap().take();
+						*current_room = new_room;
+					}
+					WsMessage::Info2 { version, nickname } => {
+						let too_old = match (parse_version(&version), parse_version(MIN_CLIENT_VERSION)) {
+							(Some(version), Some(min_version)) => version < min_version,
+							_ => true,
+						};
+						if too_old {
+							info!("client {id} version '{version}' is older than {MIN_CLIENT_VERSION}. disconnecting");
+							let _ = ch_s.send(
+								WsMessage::Incompatible {
+									min_version: MIN_CLIENT_VERSION.to_string(),
+								}
+								.send_helper(),
+							);
+							let _ = ch_s.send(Message::Close(Some(CloseFrame {
+								code: CloseCode::Policy,
+								reason: "incompatible client version".into(),
+							})));
+							return Ok(());
+						}
+						info!(client_id = id, version = version.as_str(), event = "version"; "client {id} is version {version}");
+						name = member_name(&nickname, &version);
+						client_version = version;
+					}
+					WsMessage::Nick(nickname) => {
+						let new_name = member_name(&nickname, &client_version);
+						if new_name == name {
+							continue;
+						}
+						info!(client_id = id, room = current_room.as_str(), event = "nick"; "client {id} is now {new_name}");
+						rename_member(id, &new_name, current_room, &rooms.lock().unwrap());
+						name = new_name;
+					}
+					WsMessage::Session(token) => {
+						let token: String = token.trim().chars().take(64).collect();
+						session = (!token.is_empty()).then_some(token);
+					}
+					WsMessage::EnableAcks => acks = true,
+					WsMessage::Observer => observer = true,
+					WsMessage::Title(new_title) => {
+						let new_title = clean_title(&new_title);
+						title = (!new_title.is_empty()).then_some(new_title);
+						share_title(&title, current_room, rooms.lock().unwrap().deref_mut());
+					}
+					WsMessage::InRoom { room_hash, msg } => {
+						if room_hash.is_empty() || matches!(*msg, WsMessage::InRoom { .. }) {
+							continue;
+						}
+						if !room_sessions.contains_key(&room_hash) {
+							if room_sessions.len() >= MAX_ROOM_SESSIONS {
+								debug!("client {id} already has {MAX_ROOM_SESSIONS} room sessions");
+								continue;
+							}
+							let room_session = spawn_room_session(id, &room_hash, &ch_s, &rooms, connected_counter, settings);
+							if !matches!(*msg, WsMessage::Join(_) | WsMessage::Join2 { .. }) {
+								let _ = room_session.unbounded_send(WsMessage::Join(room_hash.clone()).to_websocket_msg());
+							}
+							let _ = room_sessions.insert(room_hash.clone(), room_session);
+						}
+						if room_sessions[&room_hash].unbounded_send(msg.to_websocket_msg()).is_err() {
+							// The session ended. The next `InRoom` starts a new one.
+							let _ = room_sessions.remove(&room_hash);
+						}
+					}
+					WsMessage::Incompatible { .. } | WsMessage::Ack(_) | WsMessage::Nack { .. } => { /* we shouldn't be receiving this */ }
+					WsMessage::ServerInfo { .. } | WsMessage::Party(_) | WsMessage::Roster(_) | WsMessage::NickChanged { .. } | WsMessage::MemberLeft(_) | WsMessage::LeaderChanged(_) | WsMessage::RateLimited | WsMessage::RoomName(_) => { /* we shouldn't be receiving this */ }
+					WsMessage::Resume => {
+						if current_room.is_empty() {
+							if acks {
+								send_ack(&ch_s, &msg, Some("not in a room"));
+							}
+							continue;
+						}
+
+						let resume = WsMessage::Resume.send_helper();
+
+						let Some(room) = room_of(&rooms, current_room) else {
+							continue;
+						};
+						let mut room = room.lock().unwrap();
+						room.messages_relayed += 1;
+
+						// We can reach this with pause mismatches and shit...
+						if let Some(queued) = room.queued_resumes.borrow_mut() {
+							while queued.try_join_next().is_some() {}
+							if queued.is_empty() {
+								room.queued_resumes = None;
+							}
+						}
+
+						// An existing queue is occuring and we probably shouldn't hit this but...
+						if room.queued_resumes.is_none() {
+							room.queued_resumes = send_ping_compensated(&room.members, &resume);
+						}
+						if acks {
+							send_ack(&ch_s, &msg, None);
+						}
+					}
+					WsMessage::AbsoluteSeek(_) | WsMessage::ChapterSeek { .. } => {
+						if current_room.is_empty() {
+							if acks {
+								send_ack(&ch_s, &msg, Some("not in a room"));
+							}
+							continue;
+						}
+
+						let Some(room) = room_of(&rooms, current_room) else {
+							continue;
+						};
+						let mut room = room.lock().unwrap();
+						room.messages_relayed += 1;
+						room.seek_with(&msg);
+						if acks {
+							send_ack(&ch_s, &msg, None);
+						}
+					}
+					WsMessage::ForceResync(t) => {
+						if current_room.is_empty() {
+							continue;
+						}
+
+						info!(client_id = id, room = current_room.as_str(), event = "force_resync"; "client {id} forced a resync to {t}");
+						let Some(room) = room_of(&rooms, current_room) else {
+							continue;
+						};
+						let mut room = room.lock().unwrap();
+						room.messages_relayed += 1;
+						room.seek(t);
+					}
+					WsMessage::EndReached => {
+						if current_room.is_empty() {
+							continue;
+						}
+
+						let Some(room) = room_of(&rooms, current_room) else {
+							continue;
+						};
+						let mut room = room.lock().unwrap();
+						room.messages_relayed += 1;
+						drop(room.queued_resumes.take()); // abort queued resumes...
+						drop(room.queued_seeks.take());
+
+						let msg = WsMessage::EndReached.send_helper();
+						for member in &room.members {
+							if member.id != id {
+								let _ = member.sender.send(msg.clone());
+							}
+						}
+					}
+					WsMessage::Buffering(buffering) => {
+						if current_room.is_empty() {
+							continue;
+						}
+
+						let Some(room) = room_of(&rooms, current_room) else {
+							continue;
+						};
+						let mut room = room.lock().unwrap();
+						room.messages_relayed += 1;
+						if buffering {
+							room.start_buffering(id, current_room, &rooms);
+						} else {
+							room.stop_buffering(id);
+						}
+					}
+					WsMessage::PauseReason { reason, .. } => {
+						if current_room.is_empty() || reason != "seek" {
+							continue;
+						}
+
+						// Not ping compensated so it's there before the seek.
+						let msg = WsMessage::PauseReason { reason, name: name.clone() }.send_helper();
+						let Some(room) = room_of(&rooms, current_room) else {
+							continue;
+						};
+						for member in &room.lock().unwrap().members {
+							if member.id != id {
+								let _ = member.sender.send(msg.clone());
+							}
+						}
+					}
+					WsMessage::Position(t) => {
+						if current_room.is_empty() {
+							continue;
+						}
+
+						let Some(room) = room_of(&rooms, current_room) else {
+							continue;
+						};
+						let mut room = room.lock().unwrap();
+						if room.leader != Some(id) {
+							continue;
+						}
+						room.messages_relayed += 1;
+						for member in &room.members {
+							if member.id != id {
+								let _ = member.sender.send(WsMessage::Position(t + ping + member.ping).send_helper());
+							}
+						}
+					}
+					WsMessage::RequestState => {
+						if current_room.is_empty() {
+							continue;
+						}
+
+						let Some(room) = room_of(&rooms, current_room) else {
+							continue;
+						};
+						let mut room = room.lock().unwrap();
+						room.messages_relayed += 1;
+
+						// Only ask one member so the requester doesn't get a pile of slightly different answers.
+						// Observers might've wandered off so they're asked last.
+						let mut others: Vec<&Member> = room.members.iter().filter(|m| m.id != id && !m.disconnected).collect();
+						others.sort_by_key(|m| m.observer);
+						let Some(other) = others.first() else {
+							continue;
+						};
+						let _ = other.sender.send(WsMessage::RequestState.send_helper());
+						if !room.state_requesters.contains(&id) {
+							room.state_requesters.push(id);
+						}
+					}
+					WsMessage::State { time, paused } => {
+						if current_room.is_empty() {
+							continue;
+						}
+
+						let msg = WsMessage::State { time, paused }.send_helper();
+
+						let Some(room) = room_of(&rooms, current_room) else {
+							continue;
+						};
+						let mut room = room.lock().unwrap();
+						room.messages_relayed += 1;
+						let requesters = std::mem::take(&mut room.state_requesters);
+						for member in &room.members {
+							if member.id != id && requesters.contains(&member.id) {
+								let _ = member.sender.send(msg.clone());
+							}
+						}
+					}
+					WsMessage::Ping(_) => { /* we shouldn't be recieving this */ }
+					WsMessage::Pong(ref s) => {
+						let Some(new_ping) = ping_from_pong(id, s, Utc::now()) else {
+							continue;
+						};
+						ping = new_ping;
+						//debug!("  ping = {ping}s");
+
+						last_pong_time = std::time::Instant::now();
+
+						if let Some(room) = room_of(&rooms, current_room) {
+							let mut room = room.lock().unwrap();
+							if let Some(member) = room.members.iter_mut().find(|m| m.id == id) {
+								member.ping = ping;
+								member.last_pong = last_pong_time;
+							}
+						}
+					}
+				}
+			}
+		}
+	}
+}
+
+enum Listener {
+	Tcp(Vec<tokio::net::TcpListener>),
+	#[cfg(unix)]
+	Unix(tokio::net::UnixListener, std::path::PathBuf),
+}
+
+/// `--admin-socket` commands. One JSON object per line, like `{"cmd": "kick", "id": 3}`.
+#[derive(serde::Deserialize, Debug)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+enum AdminCommand {
+	/// Every room with its members.
+	Rooms,
+	/// Disconnects member `id` (without holding their slot for a reconnect).
+	Kick { id: u64 },
+	/// Kicks everyone in `room` (a room key or short name).
+	Clear { room: String },
+}
+
+/// Kicks member `id`. Returns false if they're not in a room.
+fn kick_member(id: u64, rooms: &mut RoomMap) -> bool {
+	let Some((room_key, room)) = rooms
+		.iter()
+		.find(|(_, room)| room.lock().unwrap().members.iter().any(|m| m.id == id))
+		.map(|(key, room)| (key.clone(), room.clone()))
+	else {
+		return false;
+	};
+	warn!(client_id = id, room = room_key.as_str(), event = "kicked"; "kicking client {id} from room {room_key}");
+	let mut room = room.lock().unwrap();
+	let me = room.members.iter_mut().find(|m| m.id == id).unwrap();
+	if me.disconnected {
+		// There's no connection to hang up on. Just stop holding the slot.
+		drop(room);
+		let _ = remove_from_room(id, &room_key, rooms);
+	} else {
+		// So `client_finished()` doesn't hold the slot for a reconnect.
+		me.session = None;
+		me.kick.notify_one();
+	}
+	true
+}
+
+fn admin_command(cmd: AdminCommand, rooms: &mut RoomMap) -> serde_json::Value {
+	match cmd {
+		AdminCommand::Rooms => {
+			let rooms: Vec<_> = rooms
+				.iter()
+				.map(|(key, room)| {
+					let room = room.lock().unwrap();
+					let members: Vec<_> = room
+						.members
+						.iter()
+						.map(
+							|m| serde_json::json!({"id": m.id, "name": m.name, "ping": m.ping, "disconnected": m.disconnected}),
+						)
+						.collect();
+					serde_json::json!({"room": key, "name": room.short_name, "title": room.title, "members": members})
+				})
+				.collect();
+			serde_json::json!({ "rooms": rooms })
+		}
+		AdminCommand::Kick { id } => {
+			if kick_member(id, rooms) {
+				serde_json::json!({ "kicked": 1 })
+			} else {
+				serde_json::json!({ "error": format!("no member {id}") })
+			}
+		}
+		AdminCommand::Clear { room } => {
+			// Only rooms that exist can be cleared so saved names don't matter.
+			let room = resolve_short_name(room, rooms, None);
+			let Some(ids) = rooms
+				.get(&room)
+				.map(|room| room.lock().unwrap().members.iter().map(|m| m.id).collect::<Vec<_>>())
+			else {
+				return serde_json::json!({ "error": format!("no room {room}") });
+			};
+			for id in &ids {
+				let _ = kick_member(*id, rooms);
+			}
+			serde_json::json!({ "kicked": ids.len() })
+		}
+	}
+}
+
+async fn serve_admin(listener: tokio::net::TcpListener, rooms: Rooms) {
+	loop {
+		let Ok((stream, addr)) = listener.accept().await else {
+			continue;
+		};
+		let rooms = rooms.clone();
+		tokio::spawn(async move {
+			let ret = handle_admin(stream, &rooms).await;
+			debug!(addr:% = addr, event = "admin"; "finished with admin connection {addr} {ret:?}");
+		});
+	}
+}
+
+async fn handle_admin(stream: tokio::net::TcpStream, rooms: &Rooms) -> anyhow::Result<()> {
+	use tokio::io::AsyncBufReadExt;
+	let (read, mut write) = stream.into_split();
+	let mut lines = tokio::io::BufReader::new(read).lines();
+	while let Some(line) = lines.next_line().await? {
+		if line.trim().is_empty() {
+			continue;
+		}
+		let reply = match serde_json::from_str::<AdminCommand>(&line) {
+			Ok(cmd) => {
+				info!(event = "admin"; "admin command {cmd:?}");
+				admin_command(cmd, &mut rooms.lock().unwrap())
+			}
+			Err(e) => serde_json::json!({ "error": e.to_string() }),
+		};
+		write.write_all(format!("{reply}\n").as_bytes()).await?;
+	}
+	Ok(())
+}
+
+/// Ctrl+C or SIGTERM (what `docker stop` sends).
+async fn shutdown_signal() {
+	#[cfg(unix)]
+	{
+		let Ok(mut sigterm) = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) else {
+			let _ = tokio::signal::ctrl_c().await;
+			return;
+		};
+		tokio::select! {
+			_ = tokio::signal::ctrl_c() => (),
+			_ = sigterm.recv() => (),
+		}
+	}
+	#[cfg(not(unix))]
+	{
+		let _ = tokio::signal::ctrl_c().await;
+	}
+}
+
+/// The relay without the logger & runtime that `server()` sets up. For running it inside something else.
+/// Flags that don't have a builder method here are left at their defaults.
+pub struct RelayServer {
+	listener: Listener,
+	tls: Option<Arc<Tls>>,
+	settings: RelaySettings,
+}
+
+/// `--tls-cert` & `--tls-key`. Reloaded on SIGHUP so a renewed certificate is picked up without a restart.
+struct Tls {
+	cert: std::path::PathBuf,
+	key: std::path::PathBuf,
+	acceptor: std::sync::RwLock<tokio_rustls::TlsAcceptor>,
+}
+
+impl Tls {
+	fn load(cert: &std::path::Path, key: &std::path::Path) -> anyhow::Result<tokio_rustls::TlsAcceptor> {
+		let pem = std::fs::read(cert).with_context(|| format!("failed to read --tls-cert '{}'", cert.display()))?;
+		let certs = rustls_pemfile::certs(&mut pem.as_slice())
+			.collect::<Result<Vec<_>, _>>()
+			.with_context(|| format!("malformed PEM in --tls-cert '{}'", cert.display()))?;
+		anyhow::ensure!(
+			!certs.is_empty(),
+			"no PEM certificates found in --tls-cert '{}'",
+			cert.display()
+		);
+		let pem = std::fs::read(key).with_context(|| format!("failed to read --tls-key '{}'", key.display()))?;
+		let key = rustls_pemfile::private_key(&mut pem.as_slice())
+			.with_context(|| format!("malformed PEM in --tls-key '{}'", key.display()))?
+			.with_context(|| format!("no private key found in --tls-key '{}'", key.display()))?;
+		let config = rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+			.with_safe_default_protocol_versions()?
+			.with_no_client_auth()
+			.with_single_cert(certs, key)
+			.context("--tls-cert & --tls-key don't go together")?;
+		Ok(tokio_rustls::TlsAcceptor::from(Arc::new(config)))
+	}
+
+	/// Keeps the old certificate if the new one's broken.
+	fn reload(&self) {
+		match Self::load(&self.cert, &self.key) {
+			Ok(acceptor) => {
+				*self.acceptor.write().unwrap() = acceptor;
+				info!("reloaded {}", self.cert.display());
+			}
+			Err(e) => warn!("couldn't reload the certificate. still using the old one: {e:#}"),
+		}
+	}
+}
+
+impl RelayServer {
+	pub async fn bind(
+		addr: std::net::SocketAddr,
+		repo_url: http::Uri,
+		ping_settings: PingSettings,
+	) -> anyhow::Result<Self> {
+		Self::bind_all(&[addr], repo_url, ping_settings).await
+	}
+
+	/// Listens on every address with the same rooms. IPv6 addresses are made IPv6-only if there are IPv4 ones too
+	/// so `0.0.0.0` & `::` don't fight over the port on platforms where `::` is dual-stack.
+	pub async fn bind_all(
+		addrs: &[std::net::SocketAddr],
+		repo_url: http::Uri,
+		ping_settings: PingSettings,
+	) -> anyhow::Result<Self> {
+		anyhow::ensure!(!addrs.is_empty(), "no addresses to bind to");
+		let v6_only = addrs.iter().any(|addr| addr.is_ipv4()) && addrs.iter().any(|addr| addr.is_ipv6());
+		let mut listeners = vec![];
+		for &addr in addrs {
+			let listener = if v6_only && addr.is_ipv6() {
+				bind_v6_only(addr)
+			} else {
+				tokio::net::TcpListener::bind(addr).await
+			}
+			.with_context(|| format!("couldn't bind to {addr}"))?;
+			info!("listening on {}", listener.local_addr()?);
+			listeners.push(listener);
+		}
+		Ok(RelayServer {
+			listener: Listener::Tcp(listeners),
+			tls: None,
+			settings: RelaySettings::new(repo_url, ping_settings),
+		})
+	}
+
+	/// Listens on a unix socket instead. There are no HTTP endpoints here.
+	#[cfg(unix)]
+	pub fn bind_unix(
+		path: std::path::PathBuf,
+		repo_url: http::Uri,
+		ping_settings: PingSettings,
+	) -> anyhow::Result<Self> {
+		use std::os::unix::fs::FileTypeExt;
+		// Left behind by a relay that didn't shut down cleanly.
+		if std::fs::metadata(&path).is_ok_and(|m| m.file_type().is_socket()) {
+			std::fs::remove_file(&path)?;
+		}
+		let listener = tokio::net::UnixListener::bind(&path)?;
+		info!("listening on {}", path.display());
+		Ok(RelayServer {
+			listener: Listener::Unix(listener, path),
+			tls: None,
+			settings: RelaySettings::new(repo_url, ping_settings),
+		})
+	}
+
+	/// `--bind-unix` if it's set. Otherwise every `--bind-address` on `--bind-port`.
+	pub async fn bind_flags(
+		bind_addresses: &[std::net::IpAddr],
+		bind_port: u16,
+		bind_unix: Option<std::path::PathBuf>,
+		repo_url: http::Uri,
+		ping_settings: PingSettings,
+	) -> anyhow::Result<Self> {
+		match bind_unix {
+			#[cfg(unix)]
+			Some(path) => Self::bind_unix(path, repo_url, ping_settings),
+			#[cfg(not(unix))]
+			Some(_) => anyhow::bail!("--bind-unix isn't supported on this platform"),
+			None => {
+				let addrs: Vec<_> = bind_addresses
+					.iter()
+					.map(|&addr| std::net::SocketAddr::new(addr, bind_port))
+					.collect();
+				Self::bind_all(&addrs, repo_url, ping_settings).await
+			}
+		}
+	}
+
+	/// Serve `wss://` with `cert` (a PEM chain) & `key` instead of plain `ws://`.
+	/// Only websockets are served over TLS. The HTTP endpoints need a plaintext connection.
+	pub fn tls(mut self, cert: std::path::PathBuf, key: std::path::PathBuf) -> anyhow::Result<Self> {
+		anyhow::ensure!(
+			matches!(self.listener, Listener::Tcp(_)),
+			"--tls-cert doesn't work with --bind-unix. Let the reverse proxy do TLS"
+		);
+		let acceptor = Tls::load(&cert, &key)?.into();
+		info!("serving wss:// with {}", cert.display());
+		self.tls = Some(Arc::new(Tls { cert, key, acceptor }));
+		Ok(self)
+	}
+
+	/// `--rate-limit` (0 turns it off).
+	pub fn rate_limit(mut self, rate_limit: u32) -> Self {
+		self.settings.rate_limit = rate_limit;
+		self
+	}
+
+	/// `--max-connections` (0 turns it off).
+	pub fn max_connections(mut self, max_connections: usize) -> Self {
+		self.settings.max_connections = max_connections;
+		self
+	}
+
+	/// `--max-message-size` & `--max-frame-size`.
+	pub fn message_size(mut self, max_message_size: usize, max_frame_size: usize) -> anyhow::Result<Self> {
+		anyhow::ensure!(
+			max_frame_size <= max_message_size,
+			"--max-frame-size ({max_frame_size}) can't be larger than --max-message-size ({max_message_size})"
+		);
+		self.settings.websocket = websocket_config(max_message_size, max_frame_size);
+		Ok(self)
+	}
+
+	/// `--short-room-names`: give rooms without a password a name like "brave-otter-42" that `Join("@name")` finds.
+	/// Anyone can guess one & end up in a stranger's room.
+	pub fn short_room_names(mut self, enabled: bool) -> Self {
+		self.settings.short_room_names = enabled;
+		self
+	}
+
+	/// `--discovery-file`: served at `/servers.txt`.
+	pub fn discovery_file(mut self, path: std::path::PathBuf) -> Self {
+		self.settings.discovery_file = Some(path);
+		self
+	}
+
+	/// `--admin-socket`. Has to be a localhost address.
+	pub fn admin_socket(mut self, addr: std::net::SocketAddr) -> anyhow::Result<Self> {
+		anyhow::ensure!(
+			addr.ip().is_loopback(),
+			"--admin-socket has to be a localhost address (not {addr})"
+		);
+		self.settings.admin_socket = Some(addr);
+		Ok(self)
+	}
+
+	/// `--room-names-file` & `--room-names-expiry-days`.
+	pub fn room_names_file(mut self, path: std::path::PathBuf, expiry: Duration) -> anyhow::Result<Self> {
+		let saved = SavedNames::load(path, expiry)?;
+		info!("loaded {} room names from {}", saved.names.len(), saved.path.display());
+		self.settings.saved_names = Some(Arc::new(Mutex::new(saved)));
+		Ok(self)
+	}
+
+	/// `--audit-log` & `--audit-log-max-mb`.
+	pub fn audit_log(mut self, path: std::path::PathBuf, max_mb: u64) -> anyhow::Result<Self> {
+		anyhow::ensure!(max_mb > 0, "--audit-log-max-mb has to be at least 1");
+		let audit_log = AuditLog::open(path, max_mb * 1024 * 1024)?;
+		info!("writing an audit log to {}", audit_log.path.display());
+		self.settings.audit_log = Some(Mutex::new(audit_log));
+		Ok(self)
+	}
+
+	/// Useful after binding to port 0. The first address if there are multiple.
+	pub fn local_addr(&self) -> Option<std::net::SocketAddr> {
+		match &self.listener {
+			Listener::Tcp(listeners) => listeners.first()?.local_addr().ok(),
+			#[cfg(unix)]
+			Listener::Unix(..) => None,
+		}
+	}
+
+	/// Accepts clients until `shutdown` finishes.
+	pub async fn serve(self, shutdown: impl std::future::Future<Output = ()>) -> anyhow::Result<()> {
+		serve(self.listener, self.tls, Arc::new(self.settings), shutdown).await
+	}
+}
+
+/// `tokio::net::TcpListener::bind()` but with `IPV6_V6ONLY` set.
+fn bind_v6_only(addr: std::net::SocketAddr) -> std::io::Result<tokio::net::TcpListener> {
+	let socket = socket2::Socket::new(
+		socket2::Domain::IPV6,
+		socket2::Type::STREAM,
+		Some(socket2::Protocol::TCP),
+	)?;
+	socket.set_only_v6(true)?;
+	// Same as tokio does so restarting the relay doesn't wait out TIME_WAIT.
+	#[cfg(unix)]
+	socket.set_reuse_address(true)?;
+	socket.set_nonblocking(true)?;
+	socket.bind(&addr.into())?;
+	socket.listen(1024)?;
+	tokio::net::TcpListener::from_std(socket.into())
+}
+
+/// Logs & returns false if `--max-connections` is full.
+fn has_room_for(id: u64, addr: &dyn std::fmt::Display, connected_counter: &Arc<()>, max_connections: usize) -> bool {
+	let num_connected = Arc::strong_count(connected_counter) - 1; // -1 for the original
+	if max_connections == 0 || num_connected < max_connections {
+		return true;
+	}
+	warn!(client_id = id, addr:% = addr, event = "rejected", connected = num_connected; "rejected client {id} {addr}. already at --max-connections ({max_connections})");
+	false
+}
+
+async fn serve(
+	listener: Listener,
+	tls: Option<Arc<Tls>>,
+	settings: Arc<RelaySettings>,
+	shutdown: impl std::future::Future<Output = ()>,
+) -> anyhow::Result<()> {
+	let rooms: Rooms = Default::default();
+	let sessions: LongPollSessions = Default::default();
+	let connected_counter = Arc::new(());
+
+	let ping_settings = settings.ping;
+	let reaper_rooms = rooms.clone();
+	let reaper = tokio::spawn(async move {
+		let mut interval = tokio::time::interval(ping_settings.timeout);
+		loop {
+			let _ = interval.tick().await;
+			// Some slack so we don't race `handle_client()` timing out on its own.
+			let _ = reap_stale_members(
+				&mut reaper_rooms.lock().unwrap(),
+				ping_settings.timeout + ping_settings.interval * 2,
+			);
+		}
+	});
+
+	let admin = match settings.admin_socket {
+		Some(addr) => {
+			let listener = tokio::net::TcpListener::bind(addr).await?;
+			info!("admin socket listening on {addr}");
+			Some(tokio::spawn(serve_admin(listener, rooms.clone())))
+		}
+		None => None,
+	};
+
+	let saver = settings.saved_names.clone().map(|saved| {
+		let rooms = rooms.clone();
+		tokio::spawn(async move {
+			let mut interval = tokio::time::interval(SAVED_NAMES_INTERVAL);
+			let _ = interval.tick().await;
+			loop {
+				let _ = interval.tick().await;
+				save_room_names(&rooms, &saved).await;
+			}
+		})
+	});
+
+	#[cfg(unix)]
+	let reloader = match &tls {
+		Some(tls) => {
+			let tls = tls.clone();
+			let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+			Some(tokio::spawn(async move {
+				while hangup.recv().await.is_some() {
+					tls.reload();
+				}
+			}))
+		}
+		None => None,
+	};
+
+	tokio::pin!(shutdown);
+
+	loop {
+		let rooms = rooms.clone();
+		match &listener {
+			Listener::Tcp(listeners) => {
+				let accepts = listeners.iter().map(|listener| Box::pin(listener.accept()));
+				let accepted = tokio::select! {
+					(accepted, ..) = futures::future::select_all(accepts) => accepted,
+					_ = &mut shutdown => break,
+				};
+				if let Ok((stream, addr)) = accepted {
+					let latest_id = LATEST_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed) + 1;
+					if !has_room_for(latest_id, &addr, &connected_counter, settings.max_connections) {
+						continue;
+					}
+					let num_connected = Arc::strong_count(&connected_counter);
+					info!(client_id = latest_id, addr:% = addr, event = "connect", connected = num_connected; "accepted client {latest_id} {addr} ({num_connected} clients connected)");
+					if let Some(tls) = &tls {
+						let acceptor = tls.acceptor.read().unwrap().clone();
+						tokio::spawn(handle_tls(
+							stream,
+							acceptor,
+							latest_id,
+							addr,
+							rooms,
+							connected_counter.clone(),
+							settings.clone(),
+						));
+						continue;
+					}
+					tokio::spawn(handle_tcp(
+						stream,
+						latest_id,
+						addr,
+						rooms,
+						sessions.clone(),
+						connected_counter.clone(),
+						settings.clone(),
+					));
+				}
+			}
+			#[cfg(unix)]
+			Listener::Unix(listener, path) => {
+				let accepted = tokio::select! {
+					accepted = listener.accept() => accepted,
+					_ = &mut shutdown => break,
+				};
+				if let Ok((stream, _)) = accepted {
+					let latest_id = LATEST_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed) + 1;
+					// The reverse proxy should be passing the real address along in a header but we don't bother with that.
+					let addr = path.display().to_string();
+					if !has_room_for(latest_id, &addr, &connected_counter, settings.max_connections) {
+						continue;
+					}
+					let num_connected = Arc::strong_count(&connected_counter);
+					info!(client_id = latest_id, addr:% = addr, event = "connect", connected = num_connected; "accepted client {latest_id} {addr} ({num_connected} clients connected)");
+					// No HTTP endpoints here. The reverse proxy can do its own health checks.
+					tokio::spawn(handle_websocket(
+						stream,
+						latest_id,
+						addr,
+						rooms,
+						connected_counter.clone(),
+						settings.clone(),
+					));
+				}
+			}
+		}
+	}
+
+	info!("shutting down");
+	reaper.abort();
+	#[cfg(unix)]
+	if let Some(reloader) = reloader {
+		reloader.abort();
+	}
+	if let Some(admin) = admin {
+		admin.abort();
+	}
+	if let (Some(saver), Some(saved)) = (saver, &settings.saved_names) {
+		saver.abort();
+		save_room_names(&rooms, saved).await;
+	}
+	#[cfg(unix)]
+	if let Listener::Unix(_, path) = &listener {
+		let _ = std::fs::remove_file(path);
+	}
+	Ok(())
+}
+
+/// A systemd unit for `simulcast-mpv relay` with the given settings. `user` makes one for `systemctl --user`.
+pub fn systemd_unit(
+	exe: &std::path::Path,
+	bind_addresses: &[std::net::IpAddr],
+	bind_port: u16,
+	bind_unix: Option<&std::path::Path>,
+	repo_url: &http::Uri,
+	user: bool,
+) -> String {
+	let mut unit = format!(
+		"[Unit]\n\
+		 Description=simulcast-mpv relay\n\
+		 After=network-online.target\n\
+		 Wants=network-online.target\n\
+		 \n\
+		 [Service]\n\
+		 ExecStart=\"{}\" relay\n\
+		 Environment=SIMULCAST_BIND_ADDRESS={}\n\
+		 Environment=SIMULCAST_BIND_PORT={bind_port}\n\
+		 Environment=\"SIMULCAST_REPO_URL={repo_url}\"\n",
+		exe.display(),
+		bind_addresses
+			.iter()
+			.map(|addr| addr.to_string())
+			.collect::<Vec<_>>()
+			.join(",")
+	);
+	if let Some(path) = bind_unix {
+		unit += &format!("Environment=\"SIMULCAST_BIND_UNIX={}\"\n", path.display());
+	}
+	unit += "Restart=on-failure\n\
+			 RestartSec=5\n\
+			 NoNewPrivileges=yes\n\
+			 PrivateTmp=yes\n";
+	if !user {
+		// The relay doesn't write anything (except maybe --bind-unix) so it can run as a throwaway user in a read-only system.
+		unit += "DynamicUser=yes\n\
+				 ProtectSystem=strict\n\
+				 ProtectHome=read-only\n";
+		if let Some(dir) = bind_unix.and_then(|path| path.parent()) {
+			unit += &format!("ReadWritePaths={}\n", dir.display());
+		}
+	}
+	unit += &format!(
+		"\n[Install]\nWantedBy={}\n",
+		if user { "default.target" } else { "multi-user.target" }
+	);
+	unit
+}
+
+/// `simulcast-mpv relay`. Starts the logger & a runtime, then serves whatever `relay` builds until Ctrl+C or SIGTERM.
+pub fn server(
+	verbosity: log::LevelFilter,
+	log_format: LogFormat,
+	relay: impl std::future::Future<Output = anyhow::Result<RelayServer>>,
+) -> anyhow::Result<()> {
+	let verbosity = if true { log::LevelFilter::Debug } else { verbosity };
+	flexi_logger::Logger::with(
+		flexi_logger::LogSpecification::builder()
+			.default(verbosity)
+			.module("rustls", log::LevelFilter::Warn)
+			.module("tokio_tungstenite", log::LevelFilter::Warn)
+			.module("tungstenite", log::LevelFilter::Warn)
+			.build(),
+	)
+	.format(match log_format {
+		LogFormat::Text => flexi_logger::colored_default_format,
+		LogFormat::Json => json_format,
+	})
+	.start()?;
+
+	let rt = tokio::runtime::Runtime::new()?;
+	rt.block_on(async move { relay.await?.serve(shutdown_signal()).await })
+}
+
+/// Starts a relay on a random localhost port for tests. It runs until the test's runtime stops.
+#[cfg(test)]
+pub(crate) async fn spawn_test_relay() -> std::net::SocketAddr {
+	let relay = RelayServer::bind(
+		"127.0.0.1:0".parse().unwrap(),
+		http::Uri::from_static("https://example.com/simulcast-mpv"),
+		PingSettings {
+			interval: Duration::from_secs(1),
+			timeout: Duration::from_secs(10),
+		},
+	)
+	.await
+	.unwrap();
+	let addr = relay.local_addr().unwrap();
+	tokio::spawn(relay.serve(std::future::pending()));
+	addr
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn settings() -> RelaySettings {
+		RelaySettings::new(
+			http::Uri::from_static("https://example.com/simulcast-mpv"),
+			PingSettings {
+				interval: Duration::from_secs(1),
+				timeout: Duration::from_secs(10),
+			},
+		)
+	}
+
+	fn named_settings() -> RelaySettings {
+		RelaySettings {
+			short_room_names: true,
+			..settings()
+		}
+	}
+
+	fn member(id: u64) -> (Member, tokio::sync::mpsc::UnboundedReceiver<Message>) {
+		let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+		(
+			Member {
+				id,
+				ping: 0.0,
+				name: format!("member {id}"),
+				sender,
+				joined_at: std::time::Instant::now(),
+				last_pong: std::time::Instant::now(),
+				session: None,
+				disconnected: false,
+				kick: Default::default(),
+				observer: false,
+				moved_to: Default::default(),
+			},
+			receiver,
+		)
+	}
+
+	fn recv(receiver: &mut tokio::sync::mpsc::UnboundedReceiver<Message>) -> WsMessage {
+		serde_json::from_str(receiver.try_recv().unwrap().to_text().unwrap()).unwrap()
+	}
+
+	#[test]
+	fn seek_reaches_every_member() {
+		let (a, mut a_recv) = member(1);
+		let (b, mut b_recv) = member(2);
+		let mut room = Room {
+			members: vec![a, b],
+			..Default::default()
+		};
+
+		room.seek(123.5);
+
+		// Including the sender (whoever that was).
+		assert_eq!(recv(&mut a_recv), WsMessage::AbsoluteSeek(123.5));
+		assert_eq!(recv(&mut b_recv), WsMessage::AbsoluteSeek(123.5));
+		assert!(a_recv.try_recv().is_err());
+		assert!(b_recv.try_recv().is_err());
+	}
+
+	#[tokio::test]
+	async fn relays_keep_their_own_settings() {
+		let ping = settings().ping;
+		let mut addrs = vec![];
+		for repo in ["https://example.com/first", "https://example.com/second"] {
+			let relay = RelayServer::bind("127.0.0.1:0".parse().unwrap(), repo.parse().unwrap(), ping)
+				.await
+				.unwrap();
+			addrs.push(relay.local_addr().unwrap());
+			tokio::spawn(relay.serve(std::future::pending()));
+		}
+
+		for (addr, repo) in addrs.into_iter().zip(["first", "second"]) {
+			let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+			stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+			let mut response = String::new();
+			let _ = stream.read_to_string(&mut response).await.unwrap();
+			assert!(
+				response.ends_with(&format!("repo https://example.com/{repo}\n")),
+				"{response}"
+			);
+		}
+	}
+
+	#[tokio::test]
+	async fn longpoll_bodies_are_limited() {
+		let relay = spawn_test_relay().await;
+		let request = |head: &[u8], body: &[u8]| {
+			let mut bytes = head.to_vec();
+			bytes.extend_from_slice(format!("Content-Length: {}\r\n\r\n", body.len()).as_bytes());
+			bytes.extend_from_slice(body);
+			async move {
+				let mut stream = tokio::net::TcpStream::connect(relay).await.unwrap();
+				stream.write_all(&bytes).await.unwrap();
+				let mut response = String::new();
+				let _ = tokio::time::timeout(Duration::from_secs(5), stream.read_to_string(&mut response))
+					.await
+					.expect("relay didn't answer")
+					.unwrap();
+				response
+			}
+		};
+
+		// Invalid UTF-8 in the head mustn't throw off where the body starts.
+		let response = request(b"POST /\xff\xff/longpoll/send?session=x HTTP/1.1\r\n", b"{}").await;
+		assert!(response.starts_with("HTTP/1.1 404"), "{response}");
+
+		let big = vec![b'a'; DEFAULT_MAX_MESSAGE_SIZE + 1];
+		let response = request(b"POST /longpoll/send?session=x HTTP/1.1\r\n", &big).await;
+		assert!(response.starts_with("HTTP/1.1 413"), "{response}");
+	}
+
+	#[tokio::test]
+	async fn room_sessions_arent_connections() {
+		let connected_counter = Arc::new(());
+		let (ch_s, _ch_r) = tokio::sync::mpsc::unbounded_channel();
+		let session = spawn_room_session(
+			1,
+			"room",
+			&ch_s,
+			&Rooms::default(),
+			&Arc::downgrade(&connected_counter),
+			&Arc::new(settings()),
+		);
+		tokio::task::yield_now().await;
+		assert_eq!(Arc::strong_count(&connected_counter), 1);
+		drop(session);
+	}
+
+	#[tokio::test]
+	async fn seek_waits_for_the_slowest_member() {
+		let (a, mut a_recv) = member(1);
+		let (mut b, mut b_recv) = member(2);
+		b.ping = 0.05;
+		let mut room = Room {
+			members: vec![a, b],
+			..Default::default()
+		};
+
+		room.seek(10.0);
+
+		// b has the highest ping so it gets it right away & a gets it once b's should have arrived.
+		assert_eq!(recv(&mut b_recv), WsMessage::AbsoluteSeek(10.0));
+		assert!(a_recv.try_recv().is_err());
+		tokio::time::sleep(Duration::from_millis(100)).await;
+		assert_eq!(recv(&mut a_recv), WsMessage::AbsoluteSeek(10.0));
+	}
+
+	#[test]
+	fn future_pong_is_clamped() {
+		let now = DateTime::parse_from_rfc3339("2025-01-01T12:00:00.000Z")
+			.unwrap()
+			.to_utc();
+		let future = (now + chrono::TimeDelta::minutes(30)).to_rfc3339_opts(SecondsFormat::Millis, true);
+		assert_eq!(ping_from_pong(1, &future, now), Some(0.0));
+
+		let past = (now - chrono::TimeDelta::minutes(30)).to_rfc3339_opts(SecondsFormat::Millis, true);
+		assert_eq!(ping_from_pong(1, &past, now), Some(MAX_PING));
+
+		let normal = (now - chrono::TimeDelta::milliseconds(100)).to_rfc3339_opts(SecondsFormat::Millis, true);
+		assert_eq!(ping_from_pong(1, &normal, now), Some(0.05));
+
+		assert_eq!(ping_from_pong(1, "garbage", now), None);
+	}
+
+	#[test]
+	fn leaving_is_announced() {
+		let (a, mut a_recv) = member(1);
+		let (b, _) = member(2);
+		let mut rooms = HashMap::new();
+		let _ = rooms.insert(
+			"room".to_string(),
+			Arc::new(Mutex::new(Room {
+				members: vec![a, b],
+				..Default::default()
+			})),
+		);
+
+		let _ = remove_from_room(2, &"room".to_string(), &mut rooms);
+
+		assert_eq!(recv(&mut a_recv), WsMessage::Party(1));
+		assert_eq!(recv(&mut a_recv), WsMessage::Roster(vec!["member 1".to_string()]));
+		assert_eq!(recv(&mut a_recv), WsMessage::MemberLeft("member 2".to_string()));
+	}
+
+	#[test]
+	fn migrating_moves_the_whole_room() {
+		let (a, _a_recv) = member(1);
+		let (b, _b_recv) = member(2);
+		let (mut held, _) = member(3);
+		held.disconnected = true;
+		let (c, mut c_recv) = member(4);
+		let (a_moved, b_moved) = (a.moved_to.clone(), b.moved_to.clone());
+		let mut rooms = HashMap::new();
+		let _ = rooms.insert(
+			"old".to_string(),
+			Arc::new(Mutex::new(Room {
+				members: vec![a, b, held],
+				leader: Some(2),
+				..Default::default()
+			})),
+		);
+		join_room(c, "new", &mut rooms, &settings());
+		while c_recv.try_recv().is_ok() {}
+
+		migrate_room("old", "new", &mut rooms, &settings());
+
+		assert!(!rooms.contains_key("old"));
+		let room = rooms["new"].lock().unwrap();
+		let ids: Vec<u64> = room.members.iter().map(|m| m.id).collect();
+		assert_eq!(ids, [4, 2, 1]);
+		assert_eq!(room.leader, Some(4));
+		assert_eq!(*a_moved.lock().unwrap(), Some("new".to_string()));
+		assert_eq!(*b_moved.lock().unwrap(), Some("new".to_string()));
+		assert_eq!(recv(&mut c_recv), WsMessage::Party(2));
+
+		// Nothing to move.
+		drop(room);
+		migrate_room("gone", "new", &mut rooms, &settings());
+		assert_eq!(rooms["new"].lock().unwrap().members.len(), 3);
+	}
+
+	#[test]
+	fn leader_is_replaced_by_the_longest_joined() {
+		let (a, mut a_recv) = member(1);
+		let (b, mut b_recv) = member(2);
+		let (c, _) = member(3);
+		let b = Member {
+			joined_at: a.joined_at + Duration::from_secs(1),
+			..b
+		};
+		let c = Member {
+			joined_at: a.joined_at + Duration::from_secs(2),
+			..c
+		};
+		let mut rooms = HashMap::new();
+		let _ = rooms.insert(
+			"room".to_string(),
+			Arc::new(Mutex::new(Room {
+				// Not in join order on purpose.
+				members: vec![c, a, b],
+				leader: Some(1),
+				..Default::default()
+			})),
+		);
+
+		let _ = remove_from_room(1, &"room".to_string(), &mut rooms);
+
+		assert_eq!(rooms["room"].lock().unwrap().leader, Some(2));
+		for _ in 0..3 {
+			let _ = recv(&mut b_recv); // Party, Roster, MemberLeft
+		}
+		assert_eq!(recv(&mut b_recv), WsMessage::LeaderChanged("member 2".to_string()));
+		assert!(a_recv.try_recv().is_err());
+
+		// Nobody to promote.
+		let _ = remove_from_room(2, &"room".to_string(), &mut rooms);
+		let _ = remove_from_room(3, &"room".to_string(), &mut rooms);
+		assert!(rooms.is_empty());
+	}
+
+	#[test]
+	fn short_names_find_the_room() {
+		let (a, mut a_recv) = member(1);
+		let (b, mut b_recv) = member(2);
+		let mut rooms = HashMap::new();
+
+		join_room(a, "hash", &mut rooms, &named_settings());
+		let WsMessage::RoomName(name) = recv(&mut a_recv) else {
+			panic!("expected RoomName");
+		};
+		assert_eq!(resolve_short_name(name.clone(), &rooms, None), "hash");
+		assert_eq!(resolve_short_name("hash".to_string(), &rooms, None), "hash");
+
+		// Password rooms stay unlisted.
+		join_room(b, "hash:tag", &mut rooms, &named_settings());
+		assert_eq!(recv(&mut b_recv), WsMessage::RoomName(String::new()));
+
+		// The name goes away with the room.
+		let _ = remove_from_room(1, &"hash".to_string(), &mut rooms);
+		assert_eq!(resolve_short_name(name.clone(), &rooms, None), name);
+
+		// Off by default.
+		let (c, mut c_recv) = member(3);
+		join_room(c, "other", &mut rooms, &settings());
+		assert_eq!(recv(&mut c_recv), WsMessage::RoomName(String::new()));
+	}
+
+	#[test]
+	fn short_names_run_out() {
+		let mut rooms = HashMap::new();
+		for adjective in ROOM_NAME_ADJECTIVES {
+			for animal in ROOM_NAME_ANIMALS {
+				for n in 10..100 {
+					let room = Room {
+						short_name: Some(format!("{adjective}-{animal}-{n}")),
+						..Default::default()
+					};
+					let _ = rooms.insert(format!("{adjective}{animal}{n}"), Arc::new(Mutex::new(room)));
+				}
+			}
+		}
+		// The room still works. It just doesn't have a name.
+		let (a, mut a_recv) = member(1);
+		join_room(a, "hash", &mut rooms, &named_settings());
+		assert_eq!(recv(&mut a_recv), WsMessage::RoomName(String::new()));
+		assert_eq!(rooms["hash"].lock().unwrap().members.len(), 1);
+	}
+
+	#[test]
+	fn saved_names_round_trip_and_expire() {
+		let path = std::env::temp_dir().join(format!("simulcast-room-names-{}.json", std::process::id()));
+		let day = Duration::from_secs(24 * 60 * 60);
+		let now = Utc::now().timestamp();
+		let mut saved = SavedNames {
+			path: path.clone(),
+			expiry: day,
+			names: HashMap::new(),
+		};
+		saved.remember("old", "sleepy-moose-11", now - 2 * day.as_secs() as i64);
+		saved.remember("new", "brave-otter-42", now);
+		std::fs::write(&path, serde_json::to_string(&saved.names).unwrap()).unwrap();
+
+		let mut loaded = SavedNames::load(path.clone(), day).unwrap();
+		let _ = std::fs::remove_file(&path);
+		assert_eq!(loaded.name_of("new"), Some("brave-otter-42"));
+		assert_eq!(loaded.key_of("brave-otter-42"), Some("new"));
+		assert_eq!(loaded.name_of("old"), None);
+
+		// A name only belongs to one room.
+		loaded.remember("newer", "brave-otter-42", now);
+		assert_eq!(loaded.key_of("brave-otter-42"), Some("newer"));
+		assert_eq!(loaded.name_of("new"), None);
+
+		assert!(SavedNames::load(path, day).unwrap().names.is_empty());
+	}
+
+	#[test]
+	fn audit_log_has_no_content_and_rotates() {
+		let now = "2024-05-01T01:23:45.678Z".parse().unwrap();
+		let line = audit_line(WsMessage::Title("Secret Movie".to_string()).kind(), "abcd:tag", 7, now);
+		assert_eq!(
+			line,
+			r#"{"kind":"Title","member":7,"room":"abcd","time":"2024-05-01T01:23:45.678Z"}"#
+		);
+
+		let path = std::env::temp_dir().join(format!("simulcast-audit-{}.log", std::process::id()));
+		let mut old = path.clone().into_os_string();
+		old.push(".1");
+		let _ = std::fs::remove_file(&path);
+		let _ = std::fs::remove_file(&old);
+		let mut audit_log = AuditLog::open(path.clone(), 2 * line.len() as u64 + 2).unwrap();
+		for _ in 0..3 {
+			audit_log.write(&line).unwrap();
+		}
+		let current = std::fs::read_to_string(&path).unwrap();
+		let rotated = std::fs::read_to_string(&old).unwrap();
+		let _ = std::fs::remove_file(&path);
+		let _ = std::fs::remove_file(&old);
+		assert_eq!(rotated.lines().count(), 2);
+		assert_eq!(current.lines().count(), 1);
+	}
+
+	#[test]
+	fn stale_members_are_reaped() {
+		let (a, _a_recv) = member(1);
+		let (b, mut b_recv) = member(2);
+		let a = Member {
+			last_pong: a.last_pong - Duration::from_secs(30),
+			..a
+		};
+		let a_kick = a.kick.clone();
+		let mut rooms = HashMap::new();
+		join_room(a, "room", &mut rooms, &settings());
+		join_room(b, "room", &mut rooms, &settings());
+		while b_recv.try_recv().is_ok() {}
+
+		assert_eq!(reap_stale_members(&mut rooms, Duration::from_secs(12)), 1);
+		assert_eq!(rooms["room"].lock().unwrap().members.len(), 1);
+		assert_eq!(rooms["room"].lock().unwrap().members[0].id, 2);
+		assert_eq!(recv(&mut b_recv), WsMessage::Party(1));
+		// Their connection is told to hang up too.
+		assert!(futures::FutureExt::now_or_never(a_kick.notified()).is_some());
+
+		assert_eq!(reap_stale_members(&mut rooms, Duration::from_secs(12)), 0);
+	}
+
+	#[tokio::test]
+	async fn reaped_members_can_join_again() {
+		let rooms = Rooms::default();
+		let connected_counter = Arc::new(());
+		let (incoming_s, incoming_r) = futures::channel::mpsc::unbounded();
+		let (ch_s, _ch_r) = tokio::sync::mpsc::unbounded_channel();
+		let client = tokio::spawn({
+			let rooms = rooms.clone();
+			let connected_counter = Arc::downgrade(&connected_counter);
+			async move {
+				let mut current_room = String::new();
+				handle_client(
+					incoming_r.map(Ok),
+					ch_s,
+					1,
+					&mut current_room,
+					rooms,
+					&connected_counter,
+					&Arc::new(settings()),
+				)
+				.await
+			}
+		});
+		let in_room = |room: &str| {
+			rooms
+				.lock()
+				.unwrap()
+				.get(room)
+				.is_some_and(|room| room.lock().unwrap().members.iter().any(|m| m.id == 1))
+		};
+
+		incoming_s
+			.unbounded_send(WsMessage::Join("room".to_string()).send_helper())
+			.unwrap();
+		while !in_room("room") {
+			tokio::time::sleep(Duration::from_millis(5)).await;
+		}
+		// What the reaper does, minus the kick. Like the `Join` winning the race against it.
+		assert!(remove_from_room(1, &"room".to_string(), &mut rooms.lock().unwrap()).is_some());
+
+		incoming_s
+			.unbounded_send(WsMessage::Join("other".to_string()).send_helper())
+			.unwrap();
+		while !in_room("other") {
+			tokio::time::sleep(Duration::from_millis(5)).await;
+		}
+		assert!(!client.is_finished());
+	}
+
+	#[test]
+	fn admin_kicks_and_clears() {
+		let (a, _a_recv) = member(1);
+		let (b, _b_recv) = member(2);
+		let (c, _c_recv) = member(3);
+		let c = Member {
+			disconnected: true,
+			..c
+		};
+		let (a_kick, b_kick) = (a.kick.clone(), b.kick.clone());
+		let mut rooms = HashMap::new();
+		join_room(a, "room", &mut rooms, &named_settings());
+		join_room(b, "room", &mut rooms, &named_settings());
+		join_room(c, "room", &mut rooms, &named_settings());
+
+		let listed = admin_command(AdminCommand::Rooms, &mut rooms);
+		assert_eq!(listed["rooms"][0]["members"].as_array().unwrap().len(), 3);
+
+		// Connected members are hung up on by their `handle_client()`. Held slots go right away.
+		assert_eq!(admin_command(AdminCommand::Kick { id: 3 }, &mut rooms)["kicked"], 1);
+		assert_eq!(rooms["room"].lock().unwrap().members.len(), 2);
+		assert!(admin_command(AdminCommand::Kick { id: 3 }, &mut rooms)["error"].is_string());
+
+		let short_name = rooms["room"].lock().unwrap().short_name.clone().unwrap();
+		assert_eq!(
+			admin_command(AdminCommand::Clear { room: short_name }, &mut rooms)["kicked"],
+			2
+		);
+		assert!(rooms["room"]
+			.lock()
+			.unwrap()
+			.members
+			.iter()
+			.all(|m| m.session.is_none()));
+		// `notify_one()` leaves a permit for the next `notified()`.
+		for kick in [a_kick, b_kick] {
+			assert!(futures::FutureExt::now_or_never(kick.notified()).is_some());
+		}
+	}
+
+	#[test]
+	fn reconnecting_takes_the_slot_back() {
+		let (a, _) = member(1);
+		let (b, mut b_recv) = member(2);
+		let (a2, mut a2_recv) = member(3);
+		let a = Member {
+			session: Some("token".to_string()),
+			..a
+		};
+		let a2 = Member {
+			session: Some("token".to_string()),
+			..a2
+		};
+		let mut rooms = HashMap::new();
+		join_room(a, "room", &mut rooms, &settings());
+		join_room(b, "room", &mut rooms, &settings());
+		while b_recv.try_recv().is_ok() {}
+
+		rooms["room"].lock().unwrap().members[0].disconnected = true;
+		join_room(a2, "room", &mut rooms, &settings());
+
+		let room = rooms["room"].lock().unwrap();
+		assert_eq!(room.members.len(), 2);
+		assert_eq!(room.leader, Some(3));
+		assert!(!room.members.iter().any(|m| m.disconnected));
+		// Only the one reconnecting hears about it.
+		assert!(b_recv.try_recv().is_err());
+		let _ = recv(&mut a2_recv); // RoomName
+		let _ = recv(&mut a2_recv); // LeaderChanged
+		assert_eq!(recv(&mut a2_recv), WsMessage::Party(2));
+	}
+
+	#[test]
+	fn observers_dont_count() {
+		let (a, mut a_recv) = member(1);
+		let (o, mut o_recv) = member(2);
+		let (b, mut b_recv) = member(3);
+		let o = Member { observer: true, ..o };
+		let mut rooms = HashMap::new();
+		join_room(a, "room", &mut rooms, &settings());
+		while a_recv.try_recv().is_ok() {}
+
+		// Nobody gets paused by an observer showing up.
+		join_room(o, "room", &mut rooms, &settings());
+		assert_eq!(
+			recv(&mut a_recv),
+			WsMessage::Roster(vec!["member 1".to_string(), "member 2 (observing)".to_string()])
+		);
+		assert!(a_recv.try_recv().is_err());
+		let _ = recv(&mut o_recv); // RoomName
+		assert_eq!(recv(&mut o_recv), WsMessage::LeaderChanged("member 1".to_string()));
+		// The observer counts themselves.
+		assert_eq!(recv(&mut o_recv), WsMessage::Party(2));
+		while o_recv.try_recv().is_ok() {}
+
+		join_room(b, "room", &mut rooms, &settings());
+		assert_eq!(recv(&mut a_recv), WsMessage::Party(2));
+		assert_eq!(recv(&mut o_recv), WsMessage::Party(3));
+		while a_recv.try_recv().is_ok() {}
+		while b_recv.try_recv().is_ok() {}
+
+		// Observers never end up leading while someone else is around.
+		let _ = remove_from_room(1, &"room".to_string(), &mut rooms);
+		assert_eq!(rooms["room"].lock().unwrap().leader, Some(3));
+		while b_recv.try_recv().is_ok() {}
+
+		let _ = remove_from_room(2, &"room".to_string(), &mut rooms);
+		assert_eq!(recv(&mut b_recv), WsMessage::Roster(vec!["member 3".to_string()]));
+		assert_eq!(recv(&mut b_recv), WsMessage::MemberLeft("member 2".to_string()));
+	}
+
+	#[test]
+	fn renaming_tells_the_room() {
+		let (a, mut a_recv) = member(1);
+		let (b, mut b_recv) = member(2);
+		let mut rooms = HashMap::new();
+		join_room(a, "room", &mut rooms, &settings());
+		join_room(b, "room", &mut rooms, &settings());
+		while a_recv.try_recv().is_ok() {}
+		while b_recv.try_recv().is_ok() {}
+
+		let name = member_name(" bob\u{7} ", "2.1.0");
+		assert_eq!(name, "bob (2.1.0)");
+		rename_member(2, &name, "room", &rooms);
+		for receiver in [&mut a_recv, &mut b_recv] {
+			assert_eq!(
+				recv(receiver),
+				WsMessage::NickChanged {
+					old: "member 2".to_string(),
+					new: "bob (2.1.0)".to_string()
+				}
+			);
+			assert_eq!(
+				recv(receiver),
+				WsMessage::Roster(vec!["member 1".to_string(), "bob (2.1.0)".to_string()])
+			);
+		}
+		assert_eq!(member_name("", "2.1.0"), "anonymous (2.1.0)");
+	}
+
+	#[test]
+	fn party_includes_roster() {
+		let (a, mut a_recv) = member(1);
+		let (b, mut b_recv) = member(2);
+		let members = vec![a, b];
+
+		broadcast_party(&members);
+
+		for receiver in [&mut a_recv, &mut b_recv] {
+			assert_eq!(recv(receiver), WsMessage::Party(2));
+			assert_eq!(
+				recv(receiver),
+				WsMessage::Roster(vec!["member 1".to_string(), "member 2".to_string()])
+			);
+		}
+	}
+
+	#[cfg(feature = "client")]
+	#[tokio::test]
+	async fn one_connection_in_two_rooms() {
+		async fn next_matching(
+			ws: &mut tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>,
+			want: impl Fn(&WsMessage) -> bool,
+		) -> WsMessage {
+			tokio::time::timeout(Duration::from_secs(5), async {
+				loop {
+					let msg = ws.next().await.unwrap().unwrap();
+					let msg: WsMessage = serde_json::from_str(msg.to_text().unwrap()).unwrap();
+					if want(&msg) {
+						return msg;
+					}
+				}
+			})
+			.await
+			.unwrap()
+		}
+		fn in_room(room_hash: &str, msg: WsMessage) -> Message {
+			WsMessage::InRoom {
+				room_hash: room_hash.to_string(),
+				msg: Box::new(msg),
+			}
+			.to_websocket_msg()
+		}
+
+		let relay = spawn_test_relay().await;
+		let (mut viewer, _) = tokio_tungstenite::connect_async(format!("ws://{relay}")).await.unwrap();
+		let (mut bot, _) = tokio_tungstenite::connect_async(format!("ws://{relay}")).await.unwrap();
+
+		viewer
+			.send(WsMessage::Join("a".to_string()).to_websocket_msg())
+			.await
+			.unwrap();
+		let _ = next_matching(&mut viewer, |m| *m == WsMessage::Party(1)).await;
+		bot.send(in_room("a", WsMessage::Join("a".to_string()))).await.unwrap();
+		let _ = next_matching(&mut viewer, |m| *m == WsMessage::Party(2)).await;
+
+		// What happens in room a reaches the bot tagged with room a.
+		viewer
+			.send(WsMessage::AbsoluteSeek(42.0).to_websocket_msg())
+			.await
+			.unwrap();
+		let _ = next_matching(&mut bot, |m| {
+			*m == WsMessage::InRoom {
+				room_hash: "a".to_string(),
+				msg: Box::new(WsMessage::AbsoluteSeek(42.0)),
+			}
+		})
+		.await;
+
+		let _ = next_matching(&mut viewer, |m| *m == WsMessage::AbsoluteSeek(42.0)).await;
+
+		// Room b is joined on the first message for it & stays separate from room a.
+		bot.send(in_room("b", WsMessage::AbsoluteSeek(7.0))).await.unwrap();
+		let _ = next_matching(&mut bot, |m| {
+			*m == WsMessage::InRoom {
+				room_hash: "b".to_string(),
+				msg: Box::new(WsMessage::AbsoluteSeek(7.0)),
+			}
+		})
+		.await;
+		viewer
+			.send(WsMessage::AbsoluteSeek(1.0).to_websocket_msg())
+			.await
+			.unwrap();
+		assert_eq!(
+			next_matching(&mut viewer, |m| matches!(m, WsMessage::AbsoluteSeek(_))).await,
+			WsMessage::AbsoluteSeek(1.0)
+		);
+	}
 }
@@ -17,6 +17,7 @@ SIMULCAST_CONNECTED = nil
 local platform = mp.get_property("platform")
 
 mp.set_property("user-data/simulcast/fuckmpv", ".")
+mp.set_property("user-data/simulcast/force_resync", ".")
 
 local function setup_heartbeat()
 	local latest_beat = nil
@@ -25,6 +26,11 @@ local function setup_heartbeat()
 		latest_beat = value
 	end)
 	return mp.add_periodic_timer(0.5, function()
+		if latest_beat == -1 then
+			-- the client was started with --no-heartbeat
+			SIMULCAST_CONNECTED = true
+			return
+		end
 		if SIMULCAST_CONNECTED ~= (latest_beat ~= checked_beat) then
 			if checked_beat ~= nil then
 				-- TODO:
@@ -36,6 +42,19 @@ local function setup_heartbeat()
 	end)
 end
 
+-- Small top-right badge while the client isn't connected to the relay.
+local function setup_status_badge()
+	local badge = mp.create_osd_overlay("ass-events")
+	mp.observe_property("user-data/simulcast/connection_status", "string", function(name, value)
+		if value == nil or value == "connected" then
+			badge:remove()
+		else
+			badge.data = "{\\an9\\fs20\\bord1}simulcast: " .. value
+			badge:update()
+		end
+	end)
+end
+
 local function setup_keybinds()
 	local function pause_toggle()
 		if mp.get_property_bool("pause") then
@@ -52,6 +71,18 @@ local function setup_keybinds()
 	mp.add_forced_key_binding("space", pause_toggle)
 	mp.add_forced_key_binding("p", pause_toggle)
 
+	mp.add_key_binding("alt+r", "simulcast-force-resync", function()
+		mp.set_property("user-data/simulcast/force_resync", "resync")
+	end)
+
+	mp.add_key_binding("alt+l", "simulcast-catch-up", function()
+		mp.set_property("user-data/simulcast/fuckmpv", "catch_up")
+	end)
+
+	mp.add_key_binding("alt+m", "simulcast-migrate-room", function()
+		mp.set_property("user-data/simulcast/fuckmpv", "migrate_room")
+	end)
+
 	mp.add_key_binding("a", "simulcast-info", function()
 		-- TODO: Spam `a` a few times to open a prompt to accept a custom roomid.
 		mp.set_property("user-data/simulcast/fuckmpv", "print_info")
@@ -115,6 +146,7 @@ end
 local DEV = false
 
 local timer = setup_heartbeat()
+setup_status_badge()
 setup_keybinds()
 local mpvsock = setup_ipc_socket(DEV)
 if DEV then
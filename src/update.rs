@@ -0,0 +1,114 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+// Copyright 2025 rtldg <rtldg@protonmail.com>
+
+use crate::message::parse_version;
+use anyhow::{anyhow, Context};
+use serde::Deserialize;
+
+const LATEST_RELEASE_URL: &str = "https://api.github.com/repos/rtldg/simulcast-mpv/releases/latest";
+
+#[derive(Deserialize)]
+struct Release {
+	tag_name: String,
+	assets: Vec<Asset>,
+}
+
+#[derive(Deserialize)]
+struct Asset {
+	name: String,
+	browser_download_url: String,
+}
+
+async fn get(client: &reqwest::Client, url: &str) -> anyhow::Result<reqwest::Response> {
+	Ok(client
+		.get(url)
+		.header(
+			"user-agent",
+			format!(
+				"{}/{} ({})",
+				env!("CARGO_PKG_NAME"),
+				env!("CARGO_PKG_VERSION"),
+				env!("CARGO_PKG_REPOSITORY")
+			),
+		)
+		.send()
+		.await?
+		.error_for_status()?)
+}
+
+pub fn update() -> anyhow::Result<()> {
+	let rt = tokio::runtime::Runtime::new()?;
+	rt.block_on(update_inner())
+}
+
+async fn update_inner() -> anyhow::Result<()> {
+	let client = reqwest::Client::new();
+
+	println!("- Checking {LATEST_RELEASE_URL}");
+	let release: Release = serde_json::from_str(&get(&client, LATEST_RELEASE_URL).await?.text().await?)?;
+
+	let current = parse_version(env!("CARGO_PKG_VERSION")).unwrap();
+	let latest = parse_version(&release.tag_name)
+		.ok_or_else(|| anyhow!("failed to parse latest release version '{}'", release.tag_name))?;
+	if latest <= current {
+		println!("- Already up to date (version {})", env!("CARGO_PKG_VERSION"));
+		return Ok(());
+	}
+	println!("- Updating {} -> {}", env!("CARGO_PKG_VERSION"), release.tag_name);
+
+	let asset_name = if cfg!(windows) {
+		"simulcast-mpv.exe"
+	} else {
+		"simulcast-mpv"
+	};
+	let find_asset = |name: &str| {
+		release
+			.assets
+			.iter()
+			.find(|a| a.name == name)
+			.ok_or_else(|| anyhow!("release {} is missing '{name}'", release.tag_name))
+	};
+	let asset = find_asset(asset_name)?;
+	// The release workflow uploads `b3sum` output next to each binary.
+	let checksum = find_asset(&format!("{asset_name}.b3"))?;
+
+	println!("- Downloading {}", checksum.browser_download_url);
+	let checksum = get(&client, &checksum.browser_download_url).await?.text().await?;
+	let expected = checksum
+		.split_whitespace()
+		.next()
+		.ok_or_else(|| anyhow!("empty checksum file"))?;
+	let expected = blake3::Hash::from_hex(expected).context("malformed checksum file")?;
+
+	println!("- Downloading {}", asset.browser_download_url);
+	let binary = get(&client, &asset.browser_download_url).await?.bytes().await?;
+	let actual = blake3::hash(&binary);
+	if actual != expected {
+		return Err(anyhow!(
+			"checksum mismatch! expected {expected} but downloaded file is {actual}"
+		));
+	}
+
+	let current_exe = std::env::current_exe()?;
+	let new_exe = current_exe.with_extension("new");
+	let old_exe = current_exe.with_extension("old");
+
+	std::fs::write(&new_exe, &binary).with_context(|| format!("Failed to write {}", new_exe.display()))?;
+	std::fs::set_permissions(&new_exe, std::fs::metadata(&current_exe)?.permissions())?;
+
+	// Windows won't let us overwrite a running executable but it will let us rename it out of the way.
+	let _ = std::fs::remove_file(&old_exe);
+	std::fs::rename(&current_exe, &old_exe)
+		.with_context(|| format!("Failed to move {} out of the way", current_exe.display()))?;
+	if let Err(e) = std::fs::rename(&new_exe, &current_exe) {
+		let _ = std::fs::rename(&old_exe, &current_exe);
+		return Err(e).with_context(|| format!("Failed to replace {}", current_exe.display()));
+	}
+	// Still running on Windows so this will fail there. It'll be cleaned up next update.
+	let _ = std::fs::remove_file(&old_exe);
+
+	println!("- Replaced {}", current_exe.display());
+	println!("\nDONE! Run simulcast-mpv without arguments to also update simulcast-mpv.lua");
+
+	Ok(())
+}